@@ -0,0 +1,72 @@
+use crate::gameboy::CPU_FREQUENCY;
+
+/// Sample rate used by `Apu::default`
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+/// Audio Processing Unit
+///
+/// The sound channels aren't emulated yet, so the samples are silent.
+/// They are still produced at the requested rate so frontends can keep an audio stream fed.
+#[derive(Debug)]
+pub struct Apu {
+    sample_rate: u32,
+    /// Interleaved left and right samples produced since the last call to `take_samples`
+    samples: Vec<f32>,
+    /// T-cycles multiplied by the sample rate that haven't made up a whole sample yet
+    remainder: u64,
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32) -> Apu {
+        Apu {
+            sample_rate,
+            samples: Vec::new(),
+            remainder: 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Advance the APU by a number of T-cycles
+    pub fn step(&mut self, cycles: u64) {
+        self.remainder += cycles * self.sample_rate as u64;
+
+        while self.remainder >= CPU_FREQUENCY {
+            self.remainder -= CPU_FREQUENCY;
+            self.samples.push(0.0);
+            self.samples.push(0.0);
+        }
+    }
+
+    /// Returns the interleaved left and right samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_second_of_samples() {
+        let mut apu = Apu::new(48_000);
+
+        // Step in uneven chunks to make sure no partial samples are lost
+        for _ in 0..(CPU_FREQUENCY / 7) {
+            apu.step(7);
+        }
+        apu.step(CPU_FREQUENCY % 7);
+
+        assert_eq!(apu.take_samples().len(), 48_000 * 2);
+        assert!(apu.take_samples().is_empty());
+    }
+}
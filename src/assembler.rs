@@ -0,0 +1,1177 @@
+use crate::instructions::{
+    instruction_length, ConditionalFlag, DoubleRegister, Instruction, Register,
+};
+use std::{collections::HashMap, error, fmt, num::IntErrorKind};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AssembleError {
+    /// The instruction can't be encoded, eg. `Instruction::Invalid` or `PUSH SP`
+    Unencodable,
+    /// The wrong number of operand bytes were given for an instruction
+    OperandCount {
+        expected: usize,
+        found: usize,
+    },
+    UnknownMnemonic {
+        line: usize,
+        mnemonic: String,
+    },
+    InvalidOperands {
+        line: usize,
+        statement: String,
+    },
+    InvalidExpression {
+        line: usize,
+        expression: String,
+    },
+    UndefinedLabel {
+        line: usize,
+        label: String,
+    },
+    DuplicateLabel {
+        line: usize,
+        label: String,
+    },
+    OutOfRange {
+        line: usize,
+        statement: String,
+    },
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssembleError::Unencodable => write!(f, "instruction can't be encoded"),
+            AssembleError::OperandCount { expected, found } => {
+                write!(f, "expected {} operand bytes, found {}", expected, found)
+            }
+            AssembleError::UnknownMnemonic { line, mnemonic } => {
+                write!(f, "line {}: unknown mnemonic `{}`", line, mnemonic)
+            }
+            AssembleError::InvalidOperands { line, statement } => {
+                write!(f, "line {}: invalid operands in `{}`", line, statement)
+            }
+            AssembleError::InvalidExpression { line, expression } => {
+                write!(f, "line {}: invalid expression `{}`", line, expression)
+            }
+            AssembleError::UndefinedLabel { line, label } => {
+                write!(f, "line {}: undefined label `{}`", line, label)
+            }
+            AssembleError::DuplicateLabel { line, label } => {
+                write!(f, "line {}: label `{}` is already defined", line, label)
+            }
+            AssembleError::OutOfRange { line, statement } => {
+                write!(f, "line {}: value out of range in `{}`", line, statement)
+            }
+        }
+    }
+}
+
+impl error::Error for AssembleError {}
+
+/// Index of a register in the 3 bit register fields of an opcode, `(HL)` is 6
+fn register_index(register: Register) -> u8 {
+    match register {
+        Register::B => 0,
+        Register::C => 1,
+        Register::D => 2,
+        Register::E => 3,
+        Register::H => 4,
+        Register::L => 5,
+        Register::A => 7,
+    }
+}
+
+/// Index of a register pair in the 2 bit register fields of an opcode
+///
+/// `SP` and `AF` share an index, `PUSH`/`POP` use `AF` and every other instruction uses `SP`
+fn double_register_index(register: DoubleRegister, uses_af: bool) -> Option<u8> {
+    match (register, uses_af) {
+        (DoubleRegister::BC, _) => Some(0),
+        (DoubleRegister::DE, _) => Some(1),
+        (DoubleRegister::HL, _) => Some(2),
+        (DoubleRegister::SP, false) | (DoubleRegister::AF, true) => Some(3),
+        _ => None,
+    }
+}
+
+fn flag_index(flag: ConditionalFlag) -> u8 {
+    match flag {
+        ConditionalFlag::NZ => 0,
+        ConditionalFlag::Z => 1,
+        ConditionalFlag::NC => 2,
+        ConditionalFlag::C => 3,
+    }
+}
+
+/// Returns the opcode bytes of an instruction without its operands
+///
+/// This is the reverse of `Cpu::parse`, prefixed instructions return both the `0xCB` prefix and the opcode
+pub fn encode_opcode(instruction: &Instruction) -> Result<Vec<u8>, AssembleError> {
+    let r = |register: &Register| register_index(*register);
+    let rr = |register: &DoubleRegister| {
+        double_register_index(*register, false).ok_or(AssembleError::Unencodable)
+    };
+    let bit_op = |base: u8, bit: &u8, register: u8| {
+        if *bit < 8 {
+            Ok(vec![0xCB, base + bit * 8 + register])
+        } else {
+            Err(AssembleError::Unencodable)
+        }
+    };
+
+    let opcode = match instruction {
+        Instruction::Invalid => return Err(AssembleError::Unencodable),
+        // 8-bit load instructions
+        Instruction::LoadReg { dst, src } => 0x40 + r(dst) * 8 + r(src),
+        Instruction::LoadReg8 { register } => 0x06 + r(register) * 8,
+        Instruction::LoadRegHL { register } => 0x46 + r(register) * 8,
+        Instruction::LoadHLReg { register } => 0x70 + r(register),
+        Instruction::LoadHL8 => 0x36,
+        Instruction::LoadABC => 0x0A,
+        Instruction::LoadADE => 0x1A,
+        Instruction::LoadAAddress => 0xFA,
+        Instruction::LoadBCA => 0x02,
+        Instruction::LoadDEA => 0x12,
+        Instruction::LoadAddressA => 0xEA,
+        Instruction::LoadAOffset => 0xF0,
+        Instruction::LoadOffsetA => 0xE0,
+        Instruction::LoadAOffsetC => 0xF2,
+        Instruction::LoadOffsetCA => 0xE2,
+        Instruction::LoadIncrementHLA => 0x22,
+        Instruction::LoadIncrementAHL => 0x2A,
+        Instruction::LoadDecrementHLA => 0x32,
+        Instruction::LoadDecrementAHL => 0x3A,
+
+        // 16-bit load instructions
+        Instruction::LoadReg16 { register } => 0x01 + rr(register)? * 0x10,
+        Instruction::LoadAddressSP => 0x08,
+        Instruction::LoadSPHL => 0xF9,
+        Instruction::PushReg { register } => {
+            let index = double_register_index(*register, true).ok_or(AssembleError::Unencodable)?;
+            0xC5 + index * 0x10
+        }
+        Instruction::PopReg { register } => {
+            let index = double_register_index(*register, true).ok_or(AssembleError::Unencodable)?;
+            0xC1 + index * 0x10
+        }
+
+        // 8-bit Arithmetic/Logic instructions
+        Instruction::AddAReg { register } => 0x80 + r(register),
+        Instruction::AddA => 0xC6,
+        Instruction::AddAHL => 0x86,
+        Instruction::AddCarryAReg { register } => 0x88 + r(register),
+        Instruction::AddCarryA => 0xCE,
+        Instruction::AddCarryAHL => 0x8E,
+        Instruction::SubtractAReg { register } => 0x90 + r(register),
+        Instruction::SubtractA => 0xD6,
+        Instruction::SubtractAHL => 0x96,
+        Instruction::SubtractARegCarry { register } => 0x98 + r(register),
+        Instruction::SubtractACarry => 0xDE,
+        Instruction::SubtractAHLCarry => 0x9E,
+        Instruction::AndAReg { register } => 0xA0 + r(register),
+        Instruction::AndA => 0xE6,
+        Instruction::AndAHL => 0xA6,
+        Instruction::XorAReg { register } => 0xA8 + r(register),
+        Instruction::XorA => 0xEE,
+        Instruction::XorAHL => 0xAE,
+        Instruction::OrAReg { register } => 0xB0 + r(register),
+        Instruction::OrA => 0xF6,
+        Instruction::OrAHL => 0xB6,
+        Instruction::CompareAReg { register } => 0xB8 + r(register),
+        Instruction::CompareA => 0xFE,
+        Instruction::CompareAHL => 0xBE,
+        Instruction::IncrementReg { register } => 0x04 + r(register) * 8,
+        Instruction::IncrementHL => 0x34,
+        Instruction::DecrementReg { register } => 0x05 + r(register) * 8,
+        Instruction::DecrementHL => 0x35,
+        Instruction::DecimalAdjustA => 0x27,
+        Instruction::Complement => 0x2F,
+
+        // 16-bit Arithmetic/Logic instructions
+        Instruction::AddHLReg { register } => 0x09 + rr(register)? * 0x10,
+        Instruction::IncrementReg16 { register } => 0x03 + rr(register)? * 0x10,
+        Instruction::DecrementReg16 { register } => 0x0B + rr(register)? * 0x10,
+        Instruction::AddSPOffset => 0xE8,
+        Instruction::LoadHLSPOffset => 0xF8,
+
+        // Rotate and Shift instructions
+        Instruction::RotateALeft => 0x07,
+        Instruction::RotateALeftThroughCarry => 0x17,
+        Instruction::RotateARight => 0x0F,
+        Instruction::RotateARightThroughCarry => 0x1F,
+        Instruction::RotateLeft { register } => return Ok(vec![0xCB, r(register)]),
+        Instruction::RotateHLLeft => return Ok(vec![0xCB, 0x06]),
+        Instruction::RotateRight { register } => return Ok(vec![0xCB, 0x08 + r(register)]),
+        Instruction::RotateHLRight => return Ok(vec![0xCB, 0x0E]),
+        Instruction::RotateLeftThroughCarry { register } => {
+            return Ok(vec![0xCB, 0x10 + r(register)])
+        }
+        Instruction::RotateHLLeftThroughCarry => return Ok(vec![0xCB, 0x16]),
+        Instruction::RotateRightThroughCarry { register } => {
+            return Ok(vec![0xCB, 0x18 + r(register)])
+        }
+        Instruction::RotateHLRightThroughCarry => return Ok(vec![0xCB, 0x1E]),
+        Instruction::ShiftLeftArithmetic { register } => return Ok(vec![0xCB, 0x20 + r(register)]),
+        Instruction::ShiftHLLeftArithmetic => return Ok(vec![0xCB, 0x26]),
+        Instruction::ShiftRightArithmetic { register } => {
+            return Ok(vec![0xCB, 0x28 + r(register)])
+        }
+        Instruction::ShiftHLRightArithmetic => return Ok(vec![0xCB, 0x2E]),
+        Instruction::Swap { register } => return Ok(vec![0xCB, 0x30 + r(register)]),
+        Instruction::SwapHL => return Ok(vec![0xCB, 0x36]),
+        Instruction::ShiftRightLogical { register } => return Ok(vec![0xCB, 0x38 + r(register)]),
+        Instruction::ShiftHLRightLogical => return Ok(vec![0xCB, 0x3E]),
+
+        // Single-bit operation instructions
+        Instruction::TestBit { bit, register } => return bit_op(0x40, bit, r(register)),
+        Instruction::TestHLBit { bit } => return bit_op(0x40, bit, 6),
+        Instruction::ResetBit { bit, register } => return bit_op(0x80, bit, r(register)),
+        Instruction::ResetHLBit { bit } => return bit_op(0x80, bit, 6),
+        Instruction::SetBit { bit, register } => return bit_op(0xC0, bit, r(register)),
+        Instruction::SetHLBit { bit } => return bit_op(0xC0, bit, 6),
+
+        // CPU Control instructions
+        Instruction::FlipCarryFlag => 0x3F,
+        Instruction::SetCarryFlag => 0x37,
+        Instruction::Nop => 0x00,
+        Instruction::Halt => 0x76,
+        Instruction::Stop => 0x10,
+        Instruction::DisableInterrupts => 0xF3,
+        Instruction::EnableInterrupts => 0xFB,
+
+        // Jump instructions
+        Instruction::Jump => 0xC3,
+        Instruction::JumpHL => 0xE9,
+        Instruction::JumpConditional { flag } => 0xC2 + flag_index(*flag) * 8,
+        Instruction::JumpRelative => 0x18,
+        Instruction::JumpRelativeConditional { flag } => 0x20 + flag_index(*flag) * 8,
+        Instruction::Call => 0xCD,
+        Instruction::CallConditional { flag } => 0xC4 + flag_index(*flag) * 8,
+        Instruction::Return => 0xC9,
+        Instruction::ReturnConditional { flag } => 0xC0 + flag_index(*flag) * 8,
+        Instruction::ReturnAndEnableInterrupts => 0xD9,
+        Instruction::Reset0 { location } | Instruction::Reset8 { location }
+            if !(0xC..=0xF).contains(location) =>
+        {
+            return Err(AssembleError::Unencodable)
+        }
+        Instruction::Reset0 { location } => (location << 4) | 0x7,
+        Instruction::Reset8 { location } => (location << 4) | 0xF,
+    };
+
+    Ok(vec![opcode])
+}
+
+/// Returns the number of operand bytes that follow the opcode bytes of an instruction
+pub fn operand_count(instruction: &Instruction) -> Result<usize, AssembleError> {
+    let opcode = encode_opcode(instruction)?;
+    if opcode[0] == 0xCB {
+        Ok(0)
+    } else {
+        Ok(instruction_length(opcode[0]) as usize - 1)
+    }
+}
+
+/// Encode an instruction followed by its little endian operand bytes
+pub fn encode(instruction: &Instruction, operands: &[u8]) -> Result<Vec<u8>, AssembleError> {
+    let expected = operand_count(instruction)?;
+    if operands.len() != expected {
+        return Err(AssembleError::OperandCount {
+            expected,
+            found: operands.len(),
+        });
+    }
+
+    let mut bytes = encode_opcode(instruction)?;
+    bytes.extend_from_slice(operands);
+    Ok(bytes)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Register(Register),
+    DoubleRegister(DoubleRegister),
+    Condition(ConditionalFlag),
+    IndirectHL,
+    IndirectHLIncrement,
+    IndirectHLDecrement,
+    IndirectBC,
+    IndirectDE,
+    IndirectC,
+    Indirect(String),
+    SPOffset(String),
+    Immediate(String),
+}
+
+/// How the expression of a statement is stored after the opcode
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Argument {
+    None,
+    /// STOP is always followed by a zero byte
+    Zero,
+    Byte(String),
+    SignedByte(String),
+    /// An address in `0xFF00..=0xFFFF`, stored as the low byte
+    HighPage(String),
+    Word(String),
+    Relative(String),
+}
+
+struct Statement {
+    line: usize,
+    text: String,
+    address: u16,
+    instruction: Instruction,
+    argument: Argument,
+}
+
+fn parse_operand(text: &str) -> Operand {
+    let text = text.trim();
+    let lower = text.to_ascii_lowercase();
+    let compact: String = lower.chars().filter(|c| !c.is_whitespace()).collect();
+
+    let indirect = (compact.starts_with('[') && compact.ends_with(']'))
+        || (compact.starts_with('(') && compact.ends_with(')'));
+
+    if indirect {
+        let inner = &compact[1..compact.len() - 1];
+        return match inner {
+            "hl" => Operand::IndirectHL,
+            "hl+" | "hli" => Operand::IndirectHLIncrement,
+            "hl-" | "hld" => Operand::IndirectHLDecrement,
+            "bc" => Operand::IndirectBC,
+            "de" => Operand::IndirectDE,
+            "c" | "$ff00+c" | "0xff00+c" => Operand::IndirectC,
+            _ => Operand::Indirect(text.trim()[1..text.trim().len() - 1].to_owned()),
+        };
+    }
+
+    match compact.as_str() {
+        "a" => Operand::Register(Register::A),
+        "b" => Operand::Register(Register::B),
+        "c" => Operand::Register(Register::C),
+        "d" => Operand::Register(Register::D),
+        "e" => Operand::Register(Register::E),
+        "h" => Operand::Register(Register::H),
+        "l" => Operand::Register(Register::L),
+        "bc" => Operand::DoubleRegister(DoubleRegister::BC),
+        "de" => Operand::DoubleRegister(DoubleRegister::DE),
+        "hl" => Operand::DoubleRegister(DoubleRegister::HL),
+        "sp" => Operand::DoubleRegister(DoubleRegister::SP),
+        "af" => Operand::DoubleRegister(DoubleRegister::AF),
+        "nz" => Operand::Condition(ConditionalFlag::NZ),
+        "z" => Operand::Condition(ConditionalFlag::Z),
+        "nc" => Operand::Condition(ConditionalFlag::NC),
+        _ if compact.starts_with("sp+") || compact.starts_with("sp-") => {
+            Operand::SPOffset(text.trim()[2..].to_owned())
+        }
+        _ => Operand::Immediate(text.to_owned()),
+    }
+}
+
+/// `c` is parsed as a register, but is the carry condition when it's the first of two operands
+fn condition(operand: &Operand) -> Option<ConditionalFlag> {
+    match operand {
+        Operand::Condition(flag) => Some(*flag),
+        Operand::Register(Register::C) => Some(ConditionalFlag::C),
+        _ => None,
+    }
+}
+
+/// Parse `rst` targets into the upper nibble stored by `Instruction::Reset0`/`Reset8`
+fn reset_instruction(target: i64) -> Option<Instruction> {
+    if !(0..=0x38).contains(&target) || target % 8 != 0 {
+        return None;
+    }
+
+    let location = 0xC + (target / 0x10) as u8;
+    if target % 0x10 == 0 {
+        Some(Instruction::Reset0 { location })
+    } else {
+        Some(Instruction::Reset8 { location })
+    }
+}
+
+/// Returns the instruction for a mnemonic and its operands, or `None` if they don't form a valid instruction
+///
+/// `Err` is only returned for unknown mnemonics
+fn parse_statement(
+    mnemonic: &str,
+    operands: &[Operand],
+) -> Result<Option<(Instruction, Argument)>, ()> {
+    use Argument as Arg;
+    use Operand as Op;
+
+    let alu = |register: Instruction, hl: Instruction, immediate: Instruction| match operands {
+        [source] | [Op::Register(Register::A), source] => match source {
+            Op::Register(_) => Some((register, Arg::None)),
+            Op::IndirectHL => Some((hl, Arg::None)),
+            Op::Immediate(value) => Some((immediate, Arg::Byte(value.clone()))),
+            _ => None,
+        },
+        _ => None,
+    };
+    let source_register = || match operands {
+        [Op::Register(register)] | [Op::Register(Register::A), Op::Register(register)] => *register,
+        _ => Register::A,
+    };
+    let bit_number = |value: &str| -> Option<u8> {
+        evaluate(value, 0, &HashMap::new())
+            .ok()
+            .filter(|bit| (0..8).contains(bit))
+            .map(|bit| bit as u8)
+    };
+    let cb = |register: fn(Register) -> Instruction, hl: Instruction| match operands {
+        [Op::Register(reg)] => Some((register(*reg), Arg::None)),
+        [Op::IndirectHL] => Some((hl, Arg::None)),
+        _ => None,
+    };
+    let bit_op =
+        |register: fn(u8, Register) -> Instruction, hl: fn(u8) -> Instruction| match operands {
+            [Op::Immediate(bit), Op::Register(reg)] => {
+                bit_number(bit).map(|bit| (register(bit, *reg), Arg::None))
+            }
+            [Op::Immediate(bit), Op::IndirectHL] => bit_number(bit).map(|bit| (hl(bit), Arg::None)),
+            _ => None,
+        };
+
+    let statement = match mnemonic {
+        "ld" => match operands {
+            [Op::Register(dst), Op::Register(src)] => Some((
+                Instruction::LoadReg {
+                    dst: *dst,
+                    src: *src,
+                },
+                Arg::None,
+            )),
+            [Op::Register(register), Op::Immediate(value)] => Some((
+                Instruction::LoadReg8 {
+                    register: *register,
+                },
+                Arg::Byte(value.clone()),
+            )),
+            [Op::Register(register), Op::IndirectHL] => Some((
+                Instruction::LoadRegHL {
+                    register: *register,
+                },
+                Arg::None,
+            )),
+            [Op::IndirectHL, Op::Register(register)] => Some((
+                Instruction::LoadHLReg {
+                    register: *register,
+                },
+                Arg::None,
+            )),
+            [Op::IndirectHL, Op::Immediate(value)] => {
+                Some((Instruction::LoadHL8, Arg::Byte(value.clone())))
+            }
+            [Op::Register(Register::A), Op::IndirectBC] => Some((Instruction::LoadABC, Arg::None)),
+            [Op::Register(Register::A), Op::IndirectDE] => Some((Instruction::LoadADE, Arg::None)),
+            [Op::Register(Register::A), Op::Indirect(address)] => {
+                Some((Instruction::LoadAAddress, Arg::Word(address.clone())))
+            }
+            [Op::IndirectBC, Op::Register(Register::A)] => Some((Instruction::LoadBCA, Arg::None)),
+            [Op::IndirectDE, Op::Register(Register::A)] => Some((Instruction::LoadDEA, Arg::None)),
+            [Op::Indirect(address), Op::Register(Register::A)] => {
+                Some((Instruction::LoadAddressA, Arg::Word(address.clone())))
+            }
+            [Op::Register(Register::A), Op::IndirectC] => {
+                Some((Instruction::LoadAOffsetC, Arg::None))
+            }
+            [Op::IndirectC, Op::Register(Register::A)] => {
+                Some((Instruction::LoadOffsetCA, Arg::None))
+            }
+            [Op::IndirectHLIncrement, Op::Register(Register::A)] => {
+                Some((Instruction::LoadIncrementHLA, Arg::None))
+            }
+            [Op::Register(Register::A), Op::IndirectHLIncrement] => {
+                Some((Instruction::LoadIncrementAHL, Arg::None))
+            }
+            [Op::IndirectHLDecrement, Op::Register(Register::A)] => {
+                Some((Instruction::LoadDecrementHLA, Arg::None))
+            }
+            [Op::Register(Register::A), Op::IndirectHLDecrement] => {
+                Some((Instruction::LoadDecrementAHL, Arg::None))
+            }
+            [Op::DoubleRegister(DoubleRegister::SP), Op::DoubleRegister(DoubleRegister::HL)] => {
+                Some((Instruction::LoadSPHL, Arg::None))
+            }
+            [Op::DoubleRegister(register), Op::Immediate(value)]
+                if *register != DoubleRegister::AF =>
+            {
+                Some((
+                    Instruction::LoadReg16 {
+                        register: *register,
+                    },
+                    Arg::Word(value.clone()),
+                ))
+            }
+            [Op::Indirect(address), Op::DoubleRegister(DoubleRegister::SP)] => {
+                Some((Instruction::LoadAddressSP, Arg::Word(address.clone())))
+            }
+            [Op::DoubleRegister(DoubleRegister::HL), Op::SPOffset(offset)] => {
+                Some((Instruction::LoadHLSPOffset, Arg::SignedByte(offset.clone())))
+            }
+            _ => None,
+        },
+        "ldh" => match operands {
+            [Op::Register(Register::A), Op::Indirect(address)] => {
+                Some((Instruction::LoadAOffset, Arg::HighPage(address.clone())))
+            }
+            [Op::Indirect(address), Op::Register(Register::A)] => {
+                Some((Instruction::LoadOffsetA, Arg::HighPage(address.clone())))
+            }
+            [Op::Register(Register::A), Op::IndirectC] => {
+                Some((Instruction::LoadAOffsetC, Arg::None))
+            }
+            [Op::IndirectC, Op::Register(Register::A)] => {
+                Some((Instruction::LoadOffsetCA, Arg::None))
+            }
+            _ => None,
+        },
+        "ldi" | "ldd" => {
+            let (store, load) = if mnemonic == "ldi" {
+                (Instruction::LoadIncrementHLA, Instruction::LoadIncrementAHL)
+            } else {
+                (Instruction::LoadDecrementHLA, Instruction::LoadDecrementAHL)
+            };
+            match operands {
+                [Op::IndirectHL, Op::Register(Register::A)] => Some((store, Arg::None)),
+                [Op::Register(Register::A), Op::IndirectHL] => Some((load, Arg::None)),
+                _ => None,
+            }
+        }
+        "push" | "pop" => match operands {
+            [Op::DoubleRegister(register)] if *register != DoubleRegister::SP => {
+                let register = *register;
+                if mnemonic == "push" {
+                    Some((Instruction::PushReg { register }, Arg::None))
+                } else {
+                    Some((Instruction::PopReg { register }, Arg::None))
+                }
+            }
+            _ => None,
+        },
+        "add" => match operands {
+            [Op::DoubleRegister(DoubleRegister::HL), Op::DoubleRegister(register)]
+                if *register != DoubleRegister::AF =>
+            {
+                Some((
+                    Instruction::AddHLReg {
+                        register: *register,
+                    },
+                    Arg::None,
+                ))
+            }
+            [Op::DoubleRegister(DoubleRegister::SP), Op::Immediate(offset)] => {
+                Some((Instruction::AddSPOffset, Arg::SignedByte(offset.clone())))
+            }
+            _ => alu(
+                Instruction::AddAReg {
+                    register: source_register(),
+                },
+                Instruction::AddAHL,
+                Instruction::AddA,
+            ),
+        },
+        "adc" => alu(
+            Instruction::AddCarryAReg {
+                register: source_register(),
+            },
+            Instruction::AddCarryAHL,
+            Instruction::AddCarryA,
+        ),
+        "sub" => alu(
+            Instruction::SubtractAReg {
+                register: source_register(),
+            },
+            Instruction::SubtractAHL,
+            Instruction::SubtractA,
+        ),
+        "sbc" => alu(
+            Instruction::SubtractARegCarry {
+                register: source_register(),
+            },
+            Instruction::SubtractAHLCarry,
+            Instruction::SubtractACarry,
+        ),
+        "and" => alu(
+            Instruction::AndAReg {
+                register: source_register(),
+            },
+            Instruction::AndAHL,
+            Instruction::AndA,
+        ),
+        "xor" => alu(
+            Instruction::XorAReg {
+                register: source_register(),
+            },
+            Instruction::XorAHL,
+            Instruction::XorA,
+        ),
+        "or" => alu(
+            Instruction::OrAReg {
+                register: source_register(),
+            },
+            Instruction::OrAHL,
+            Instruction::OrA,
+        ),
+        "cp" => alu(
+            Instruction::CompareAReg {
+                register: source_register(),
+            },
+            Instruction::CompareAHL,
+            Instruction::CompareA,
+        ),
+        "inc" | "dec" => {
+            let increment = mnemonic == "inc";
+            match operands {
+                [Op::Register(register)] => {
+                    let register = *register;
+                    if increment {
+                        Some((Instruction::IncrementReg { register }, Arg::None))
+                    } else {
+                        Some((Instruction::DecrementReg { register }, Arg::None))
+                    }
+                }
+                [Op::IndirectHL] if increment => Some((Instruction::IncrementHL, Arg::None)),
+                [Op::IndirectHL] => Some((Instruction::DecrementHL, Arg::None)),
+                [Op::DoubleRegister(register)] if *register != DoubleRegister::AF => {
+                    let register = *register;
+                    if increment {
+                        Some((Instruction::IncrementReg16 { register }, Arg::None))
+                    } else {
+                        Some((Instruction::DecrementReg16 { register }, Arg::None))
+                    }
+                }
+                _ => None,
+            }
+        }
+        "rlc" => cb(
+            |register| Instruction::RotateLeft { register },
+            Instruction::RotateHLLeft,
+        ),
+        "rrc" => cb(
+            |register| Instruction::RotateRight { register },
+            Instruction::RotateHLRight,
+        ),
+        "rl" => cb(
+            |register| Instruction::RotateLeftThroughCarry { register },
+            Instruction::RotateHLLeftThroughCarry,
+        ),
+        "rr" => cb(
+            |register| Instruction::RotateRightThroughCarry { register },
+            Instruction::RotateHLRightThroughCarry,
+        ),
+        "sla" => cb(
+            |register| Instruction::ShiftLeftArithmetic { register },
+            Instruction::ShiftHLLeftArithmetic,
+        ),
+        "sra" => cb(
+            |register| Instruction::ShiftRightArithmetic { register },
+            Instruction::ShiftHLRightArithmetic,
+        ),
+        "swap" => cb(
+            |register| Instruction::Swap { register },
+            Instruction::SwapHL,
+        ),
+        "srl" => cb(
+            |register| Instruction::ShiftRightLogical { register },
+            Instruction::ShiftHLRightLogical,
+        ),
+        "bit" => bit_op(
+            |bit, register| Instruction::TestBit { bit, register },
+            |bit| Instruction::TestHLBit { bit },
+        ),
+        "set" => bit_op(
+            |bit, register| Instruction::SetBit { bit, register },
+            |bit| Instruction::SetHLBit { bit },
+        ),
+        "res" => bit_op(
+            |bit, register| Instruction::ResetBit { bit, register },
+            |bit| Instruction::ResetHLBit { bit },
+        ),
+        "jp" => match operands {
+            [Op::DoubleRegister(DoubleRegister::HL)] | [Op::IndirectHL] => {
+                Some((Instruction::JumpHL, Arg::None))
+            }
+            [Op::Immediate(target)] => Some((Instruction::Jump, Arg::Word(target.clone()))),
+            [flag, Op::Immediate(target)] => condition(flag).map(|flag| {
+                (
+                    Instruction::JumpConditional { flag },
+                    Arg::Word(target.clone()),
+                )
+            }),
+            _ => None,
+        },
+        "jr" => match operands {
+            [Op::Immediate(target)] => {
+                Some((Instruction::JumpRelative, Arg::Relative(target.clone())))
+            }
+            [flag, Op::Immediate(target)] => condition(flag).map(|flag| {
+                (
+                    Instruction::JumpRelativeConditional { flag },
+                    Arg::Relative(target.clone()),
+                )
+            }),
+            _ => None,
+        },
+        "call" => match operands {
+            [Op::Immediate(target)] => Some((Instruction::Call, Arg::Word(target.clone()))),
+            [flag, Op::Immediate(target)] => condition(flag).map(|flag| {
+                (
+                    Instruction::CallConditional { flag },
+                    Arg::Word(target.clone()),
+                )
+            }),
+            _ => None,
+        },
+        "ret" => match operands {
+            [] => Some((Instruction::Return, Arg::None)),
+            [flag] => {
+                condition(flag).map(|flag| (Instruction::ReturnConditional { flag }, Arg::None))
+            }
+            _ => None,
+        },
+        "rst" => match operands {
+            [Op::Immediate(target)] => evaluate(target, 0, &HashMap::new())
+                .ok()
+                .and_then(reset_instruction)
+                .map(|instruction| (instruction, Arg::None)),
+            _ => None,
+        },
+        _ => {
+            let instruction = match mnemonic {
+                "daa" => Instruction::DecimalAdjustA,
+                "cpl" => Instruction::Complement,
+                "rlca" => Instruction::RotateALeft,
+                "rla" => Instruction::RotateALeftThroughCarry,
+                "rrca" => Instruction::RotateARight,
+                "rra" => Instruction::RotateARightThroughCarry,
+                "ccf" => Instruction::FlipCarryFlag,
+                "scf" => Instruction::SetCarryFlag,
+                "nop" => Instruction::Nop,
+                "halt" => Instruction::Halt,
+                "stop" => Instruction::Stop,
+                "di" => Instruction::DisableInterrupts,
+                "ei" => Instruction::EnableInterrupts,
+                "reti" => Instruction::ReturnAndEnableInterrupts,
+                _ => return Err(()),
+            };
+
+            match operands {
+                [] if instruction == Instruction::Stop => Some((instruction, Arg::Zero)),
+                [] => Some((instruction, Arg::None)),
+                _ => None,
+            }
+        }
+    };
+
+    Ok(statement)
+}
+
+/// An expression that can't be evaluated
+#[derive(Debug, PartialEq, Eq)]
+enum ExpressionError {
+    /// A term that isn't a number or a known label, or the whole expression if it is malformed
+    Term(String),
+    /// The value doesn't fit in 64 bits
+    Overflow,
+}
+
+/// Parse a number term, keeping track of numbers that are too large to evaluate
+fn parse_number(term: &str, digits: &str, radix: u32) -> Result<i64, ExpressionError> {
+    i64::from_str_radix(digits, radix).map_err(|error| match error.kind() {
+        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => ExpressionError::Overflow,
+        _ => ExpressionError::Term(term.to_owned()),
+    })
+}
+
+/// Evaluate a sum of numbers, labels and `@` (the address of the current instruction)
+///
+/// Numbers can be written as `$FF`, `0xFF`, `%1010` or decimal
+fn evaluate(
+    expression: &str,
+    address: u16,
+    labels: &HashMap<String, u16>,
+) -> Result<i64, ExpressionError> {
+    let term_error = |term: &str| ExpressionError::Term(term.to_owned());
+
+    let mut total: i64 = 0;
+    let mut sign = 1;
+    let mut expect_term = true;
+    let mut chars = expression.trim().chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            _ if c.is_whitespace() => {}
+            '+' => expect_term = true,
+            '-' => {
+                sign = -sign;
+                expect_term = true;
+            }
+            _ if expect_term => {
+                let mut term = c.to_string();
+                while let Some(next) = chars.peek() {
+                    if next.is_alphanumeric() || *next == '_' || *next == '.' {
+                        term.push(*next);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+
+                let value = if term == "@" {
+                    address as i64
+                } else if let Some(hex) = term.strip_prefix('$').or(term.strip_prefix("0x")) {
+                    parse_number(&term, hex, 16)?
+                } else if let Some(binary) = term.strip_prefix('%') {
+                    parse_number(&term, binary, 2)?
+                } else if term.chars().all(|c| c.is_ascii_digit()) {
+                    parse_number(&term, &term, 10)?
+                } else {
+                    *labels.get(&term).ok_or_else(|| term_error(&term))? as i64
+                };
+
+                total = value
+                    .checked_mul(sign)
+                    .and_then(|value| total.checked_add(value))
+                    .ok_or(ExpressionError::Overflow)?;
+                sign = 1;
+                expect_term = false;
+            }
+            _ => return Err(term_error(expression)),
+        }
+    }
+
+    if expect_term {
+        return Err(term_error(expression));
+    }
+
+    Ok(total)
+}
+
+/// Split a statement into its mnemonic and comma separated operands, ignoring commas inside brackets
+fn split_statement(text: &str) -> (String, Vec<String>) {
+    let (mnemonic, rest) = match text.split_once(char::is_whitespace) {
+        Some((mnemonic, rest)) => (mnemonic, rest.trim()),
+        None => (text, ""),
+    };
+
+    let mut operands = Vec::new();
+    if !rest.is_empty() {
+        let mut depth = 0;
+        let mut current = String::new();
+        for c in rest.chars() {
+            match c {
+                '[' | '(' => depth += 1,
+                ']' | ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    operands.push(current.trim().to_owned());
+                    current.clear();
+                    continue;
+                }
+                _ => {}
+            }
+            current.push(c);
+        }
+        operands.push(current.trim().to_owned());
+    }
+
+    (mnemonic.to_ascii_lowercase(), operands)
+}
+
+/// Assemble a snippet of RGBDS style source into bytes, as if it were placed at `origin`
+///
+/// Statements are separated by new lines or `/`, `;` starts a comment and `name:` defines a label
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, AssembleError> {
+    let mut labels = HashMap::new();
+    let mut statements = Vec::new();
+    let mut address = origin;
+
+    // First pass: find the instruction for every statement so labels can be given addresses
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let code = line.split(';').next().unwrap_or("");
+
+        for text in code.split('/') {
+            let mut text = text.trim();
+
+            while let Some((label, rest)) = text.split_once(':') {
+                let label = label.trim();
+                if label.is_empty() || label.contains(char::is_whitespace) {
+                    break;
+                }
+                if labels.insert(label.to_owned(), address).is_some() {
+                    return Err(AssembleError::DuplicateLabel {
+                        line: line_number,
+                        label: label.to_owned(),
+                    });
+                }
+                text = rest.trim();
+            }
+
+            if text.is_empty() {
+                continue;
+            }
+
+            let (mnemonic, operand_texts) = split_statement(text);
+            let operands: Vec<Operand> = operand_texts.iter().map(|o| parse_operand(o)).collect();
+
+            let (instruction, argument) = parse_statement(&mnemonic, &operands)
+                .map_err(|_| AssembleError::UnknownMnemonic {
+                    line: line_number,
+                    mnemonic: mnemonic.clone(),
+                })?
+                .ok_or_else(|| AssembleError::InvalidOperands {
+                    line: line_number,
+                    statement: text.to_owned(),
+                })?;
+
+            let length = encode_opcode(&instruction)?.len() + operand_count(&instruction)?;
+
+            statements.push(Statement {
+                line: line_number,
+                text: text.to_owned(),
+                address,
+                instruction,
+                argument,
+            });
+            address = address.wrapping_add(length as u16);
+        }
+    }
+
+    // Second pass: evaluate expressions now that every label is known
+    let mut bytes = Vec::new();
+    for statement in statements {
+        let line = statement.line;
+        let out_of_range = || AssembleError::OutOfRange {
+            line,
+            statement: statement.text.clone(),
+        };
+        let evaluate = |expression: &str| {
+            evaluate(expression, statement.address, &labels).map_err(|error| match error {
+                ExpressionError::Term(term)
+                    if term.starts_with(|c: char| c.is_alphabetic() || c == '_') =>
+                {
+                    AssembleError::UndefinedLabel { line, label: term }
+                }
+                ExpressionError::Term(term) => AssembleError::InvalidExpression {
+                    line,
+                    expression: term,
+                },
+                ExpressionError::Overflow => out_of_range(),
+            })
+        };
+
+        let operands = match &statement.argument {
+            Argument::None => vec![],
+            Argument::Zero => vec![0x00],
+            Argument::Byte(expression) => {
+                let value = evaluate(expression)?;
+                if !(-128..=255).contains(&value) {
+                    return Err(out_of_range());
+                }
+                vec![value as u8]
+            }
+            Argument::SignedByte(expression) => {
+                let value = evaluate(expression)?;
+                if !(-128..=127).contains(&value) {
+                    return Err(out_of_range());
+                }
+                vec![value as u8]
+            }
+            Argument::HighPage(expression) => {
+                let value = evaluate(expression)?;
+                if !(0xFF00..=0xFFFF).contains(&value) && !(0..=0xFF).contains(&value) {
+                    return Err(out_of_range());
+                }
+                vec![value as u8]
+            }
+            Argument::Word(expression) => {
+                let value = evaluate(expression)?;
+                if !(-32768..=65535).contains(&value) {
+                    return Err(out_of_range());
+                }
+                vec![value as u8, (value >> 8) as u8]
+            }
+            Argument::Relative(expression) => {
+                let offset = evaluate(expression)?
+                    .checked_sub(statement.address as i64 + 2)
+                    .ok_or_else(out_of_range)?;
+                if !(-128..=127).contains(&offset) {
+                    return Err(out_of_range());
+                }
+                vec![offset as u8]
+            }
+        };
+
+        bytes.extend(encode(&statement.instruction, &operands)?);
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::{decode, format_instruction};
+
+    #[test]
+    fn test_assemble_snippet() {
+        assert_eq!(
+            assemble("ld a,$12 / call $1234", 0),
+            Ok(vec![0x3E, 0x12, 0xCD, 0x34, 0x12])
+        );
+    }
+
+    #[test]
+    fn test_assemble_labels_and_relative_jumps() {
+        let source = "
+            start:
+                dec b
+                jr nz, start ; loop until b is 0
+                jp end
+            end: ret
+        ";
+
+        assert_eq!(
+            assemble(source, 0x150),
+            Ok(vec![0x05, 0x20, 0xFD, 0xC3, 0x56, 0x01, 0xC9])
+        );
+    }
+
+    #[test]
+    fn test_assemble_alternate_syntax() {
+        assert_eq!(assemble("LD (HL),$12", 0), Ok(vec![0x36, 0x12]));
+        assert_eq!(assemble("ldi a,(hl)", 0), Ok(vec![0x2A]));
+        assert_eq!(assemble("ld [$ff00+c], a", 0), Ok(vec![0xE2]));
+        assert_eq!(assemble("ldh [$40], a", 0), Ok(vec![0xE0, 0x40]));
+        assert_eq!(assemble("sub b / cp 0x10", 0), Ok(vec![0x90, 0xFE, 0x10]));
+        assert_eq!(assemble("ld hl, sp+-2", 0), Ok(vec![0xF8, 0xFE]));
+        assert_eq!(assemble("ld b, %1010", 0), Ok(vec![0x06, 0x0A]));
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(
+            assemble("nop\nfoo a", 0),
+            Err(AssembleError::UnknownMnemonic {
+                line: 2,
+                mnemonic: "foo".to_owned()
+            })
+        );
+        assert_eq!(
+            assemble("push sp", 0),
+            Err(AssembleError::InvalidOperands {
+                line: 1,
+                statement: "push sp".to_owned()
+            })
+        );
+        assert_eq!(
+            assemble("jp nowhere", 0),
+            Err(AssembleError::UndefinedLabel {
+                line: 1,
+                label: "nowhere".to_owned()
+            })
+        );
+        assert_eq!(
+            assemble("jr $1000", 0),
+            Err(AssembleError::OutOfRange {
+                line: 1,
+                statement: "jr $1000".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn test_assemble_overflowing_expressions() {
+        for source in [
+            "ld a, 99999999999",
+            "ld a, 99999999999999999999",
+            "ld a, $FFFFFFFFFFFFFFFFFF",
+            "ld a, 9223372036854775807 + 1",
+            "jr -9223372036854775807 - 1",
+        ] {
+            assert_eq!(
+                assemble(source, 0),
+                Err(AssembleError::OutOfRange {
+                    line: 1,
+                    statement: source.to_owned()
+                }),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_checks_operand_count() {
+        assert_eq!(
+            encode(&Instruction::Call, &[0x34, 0x12]),
+            Ok(vec![0xCD, 0x34, 0x12])
+        );
+        assert_eq!(
+            encode(&Instruction::Call, &[0x34]),
+            Err(AssembleError::OperandCount {
+                expected: 2,
+                found: 1
+            })
+        );
+        assert_eq!(
+            encode(&Instruction::Invalid, &[]),
+            Err(AssembleError::Unencodable)
+        );
+    }
+
+    #[test]
+    fn test_encode_opcode_is_reverse_of_parse() {
+        let mut rom = vec![0; 0x8000];
+
+        for prefixed in [false, true] {
+            for opcode in 0..=0xFF {
+                if !prefixed && opcode == 0xCB {
+                    continue;
+                }
+
+                let bytes = if prefixed {
+                    vec![0xCB, opcode]
+                } else {
+                    vec![opcode]
+                };
+                rom[0x4000..(0x4000 + bytes.len())].copy_from_slice(&bytes);
+
+                if let Some(decoded) = decode(&rom, 1, 0x4000) {
+                    assert_eq!(
+                        encode_opcode(&decoded.instruction),
+                        Ok(bytes),
+                        "{:?}",
+                        decoded.instruction
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_assemble_disassembly_round_trip() {
+        let mut rom = vec![0; 0x8000];
+
+        for operands in [[0x00, 0x12], [0xFE, 0xFF]] {
+            for opcode in 0..=0xFF {
+                rom[0x4100..0x4103].copy_from_slice(&[opcode, operands[0], operands[1]]);
+
+                let Some(decoded) = decode(&rom, 1, 0x4100) else {
+                    continue;
+                };
+                if decoded.instruction == Instruction::Stop && operands[0] != 0 {
+                    continue;
+                }
+
+                let text = format_instruction(&decoded.instruction, decoded.operands(), None);
+                assert_eq!(assemble(&text, 0x4100), Ok(decoded.bytes), "{}", text);
+            }
+        }
+    }
+}
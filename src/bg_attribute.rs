@@ -0,0 +1,48 @@
+use crate::util::get_as_bits;
+
+/// Attributes of a background or window tile in CGB mode, stored in VRAM bank 1 at the same
+/// position in the tile map as the tile index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BgAttribute {
+    /// The tile is drawn over sprites, unless the background color is 0
+    pub priority: bool,
+    pub y_flip: bool,
+    pub x_flip: bool,
+    /// VRAM bank the tile data is read from
+    pub bank: u8,
+    /// BG palette (0-7)
+    pub palette: u8,
+}
+
+impl BgAttribute {
+    pub fn new(flags: u8) -> BgAttribute {
+        let flag_bits = get_as_bits(flags);
+        BgAttribute {
+            priority: flag_bits[0] == 1,
+            y_flip: flag_bits[1] == 1,
+            x_flip: flag_bits[2] == 1,
+            bank: flag_bits[4],
+            palette: flags & 0b0000_0111,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        assert_eq!(
+            BgAttribute::new(0b1010_1101),
+            BgAttribute {
+                priority: true,
+                y_flip: false,
+                x_flip: true,
+                bank: 1,
+                palette: 5,
+            }
+        );
+        assert_eq!(BgAttribute::new(0), BgAttribute::default());
+    }
+}
@@ -0,0 +1,320 @@
+use std::fmt;
+
+/// Offset of the first byte after the cartridge header
+pub const HEADER_END: usize = 0x150;
+
+/// Memory bank controller of the cartridge, from the cartridge type at 0x147
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mapper {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+    Unknown(u8),
+}
+
+impl fmt::Display for Mapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mapper::RomOnly => write!(f, "ROM"),
+            Mapper::Mbc1 => write!(f, "MBC1"),
+            Mapper::Mbc2 => write!(f, "MBC2"),
+            Mapper::Mmm01 => write!(f, "MMM01"),
+            Mapper::Mbc3 => write!(f, "MBC3"),
+            Mapper::Mbc5 => write!(f, "MBC5"),
+            Mapper::Mbc6 => write!(f, "MBC6"),
+            Mapper::Mbc7 => write!(f, "MBC7"),
+            Mapper::PocketCamera => write!(f, "Pocket Camera"),
+            Mapper::Tama5 => write!(f, "TAMA5"),
+            Mapper::HuC3 => write!(f, "HuC3"),
+            Mapper::HuC1 => write!(f, "HuC1"),
+            Mapper::Unknown(cartridge_type) => write!(f, "Unknown (0x{:0>2X})", cartridge_type),
+        }
+    }
+}
+
+/// Game Boy Color support, from the CGB flag at 0x143
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+    /// Made for the original Game Boy
+    None,
+    /// Runs on both the original Game Boy and the Game Boy Color
+    Enhanced,
+    /// Only runs on the Game Boy Color
+    Only,
+}
+
+/// Company that published the cartridge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Licensee {
+    /// Old licensee code at 0x14B
+    Old(u8),
+    /// Two character licensee code at 0x144, used when the old code is 0x33
+    New(String),
+}
+
+impl fmt::Display for Licensee {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Licensee::Old(code) => write!(f, "0x{:0>2X}", code),
+            Licensee::New(code) => write!(f, "\"{}\"", code),
+        }
+    }
+}
+
+/// Cartridge header, stored from 0x100 to 0x14F of the ROM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    /// Four character code in the last bytes of the title area of some later cartridges
+    pub manufacturer_code: Option<String>,
+    pub cgb_support: CgbSupport,
+    pub sgb_support: bool,
+    pub licensee: Licensee,
+    /// Raw cartridge type at 0x147, which sets the mapper and the extra hardware below
+    pub cartridge_type: u8,
+    pub mapper: Mapper,
+    pub has_ram: bool,
+    pub has_battery: bool,
+    pub has_timer: bool,
+    pub has_rumble: bool,
+    /// ROM size in bytes, `None` if the size code at 0x148 is unknown
+    pub rom_size: Option<usize>,
+    /// RAM size in bytes, `None` if the size code at 0x149 is unknown
+    pub ram_size: Option<usize>,
+    pub mask_rom_version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    computed_header_checksum: u8,
+    computed_global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    /// Parse the header of a ROM image
+    ///
+    /// Returns `None` if the ROM is too small to hold a header
+    pub fn parse(rom: &[u8]) -> Option<CartridgeHeader> {
+        if rom.len() < HEADER_END {
+            return None;
+        }
+
+        let cgb_support = match rom[0x143] {
+            0x80 => CgbSupport::Enhanced,
+            0xC0 => CgbSupport::Only,
+            _ => CgbSupport::None,
+        };
+
+        // Cartridges made after the CGB use the last byte of the title for the CGB flag
+        // and sometimes the four bytes before it for a manufacturer code
+        let manufacturer_code = &rom[0x13F..0x143];
+        let (title_end, manufacturer_code) = if cgb_support == CgbSupport::None {
+            (0x144, None)
+        } else if manufacturer_code
+            .iter()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        {
+            let code = String::from_utf8_lossy(manufacturer_code).into_owned();
+            (0x13F, Some(code))
+        } else {
+            (0x143, None)
+        };
+
+        let licensee = match rom[0x14B] {
+            0x33 => Licensee::New(String::from_utf8_lossy(&rom[0x144..0x146]).into_owned()),
+            code => Licensee::Old(code),
+        };
+
+        let cartridge_type = rom[0x147];
+        let (mapper, has_ram, has_battery, has_timer, has_rumble) =
+            cartridge_hardware(cartridge_type);
+
+        let rom_size = match rom[0x148] {
+            code @ 0x00..=0x08 => Some(0x8000 << code),
+            0x52 => Some(72 * 0x4000),
+            0x53 => Some(80 * 0x4000),
+            0x54 => Some(96 * 0x4000),
+            _ => None,
+        };
+
+        let ram_size = match rom[0x149] {
+            0x00 => Some(0),
+            0x01 => Some(0x800),
+            0x02 => Some(0x2000),
+            0x03 => Some(0x8000),
+            0x04 => Some(0x20000),
+            0x05 => Some(0x10000),
+            _ => None,
+        };
+
+        let computed_header_checksum = rom[0x134..0x14D].iter().fold(0u8, |checksum, byte| {
+            checksum.wrapping_sub(*byte).wrapping_sub(1)
+        });
+
+        let computed_global_checksum = rom
+            .iter()
+            .enumerate()
+            .filter(|(address, _)| *address != 0x14E && *address != 0x14F)
+            .fold(0u16, |checksum, (_, byte)| {
+                checksum.wrapping_add(*byte as u16)
+            });
+
+        Some(CartridgeHeader {
+            title: parse_title(&rom[0x134..title_end]),
+            manufacturer_code,
+            cgb_support,
+            sgb_support: rom[0x146] == 0x03,
+            licensee,
+            cartridge_type,
+            mapper,
+            has_ram,
+            has_battery,
+            has_timer,
+            has_rumble,
+            rom_size,
+            ram_size,
+            mask_rom_version: rom[0x14C],
+            header_checksum: rom[0x14D],
+            global_checksum: u16::from_be_bytes([rom[0x14E], rom[0x14F]]),
+            computed_header_checksum,
+            computed_global_checksum,
+        })
+    }
+
+    /// The boot ROM locks up if this checksum doesn't match
+    pub fn header_checksum_valid(&self) -> bool {
+        self.header_checksum == self.computed_header_checksum
+    }
+
+    /// The global checksum is never verified by the hardware, so some cartridges get it wrong
+    pub fn global_checksum_valid(&self) -> bool {
+        self.global_checksum == self.computed_global_checksum
+    }
+}
+
+/// Returns the mapper and whether the cartridge has RAM, a battery, a timer and a rumble motor
+fn cartridge_hardware(cartridge_type: u8) -> (Mapper, bool, bool, bool, bool) {
+    match cartridge_type {
+        0x00 => (Mapper::RomOnly, false, false, false, false),
+        0x01 => (Mapper::Mbc1, false, false, false, false),
+        0x02 => (Mapper::Mbc1, true, false, false, false),
+        0x03 => (Mapper::Mbc1, true, true, false, false),
+        0x05 => (Mapper::Mbc2, false, false, false, false),
+        0x06 => (Mapper::Mbc2, false, true, false, false),
+        0x08 => (Mapper::RomOnly, true, false, false, false),
+        0x09 => (Mapper::RomOnly, true, true, false, false),
+        0x0B => (Mapper::Mmm01, false, false, false, false),
+        0x0C => (Mapper::Mmm01, true, false, false, false),
+        0x0D => (Mapper::Mmm01, true, true, false, false),
+        0x0F => (Mapper::Mbc3, false, true, true, false),
+        0x10 => (Mapper::Mbc3, true, true, true, false),
+        0x11 => (Mapper::Mbc3, false, false, false, false),
+        0x12 => (Mapper::Mbc3, true, false, false, false),
+        0x13 => (Mapper::Mbc3, true, true, false, false),
+        0x19 => (Mapper::Mbc5, false, false, false, false),
+        0x1A => (Mapper::Mbc5, true, false, false, false),
+        0x1B => (Mapper::Mbc5, true, true, false, false),
+        0x1C => (Mapper::Mbc5, false, false, false, true),
+        0x1D => (Mapper::Mbc5, true, false, false, true),
+        0x1E => (Mapper::Mbc5, true, true, false, true),
+        0x20 => (Mapper::Mbc6, false, false, false, false),
+        0x22 => (Mapper::Mbc7, true, true, false, true),
+        0xFC => (Mapper::PocketCamera, false, false, false, false),
+        0xFD => (Mapper::Tama5, false, false, false, false),
+        0xFE => (Mapper::HuC3, false, false, false, false),
+        0xFF => (Mapper::HuC1, true, true, false, false),
+        _ => (Mapper::Unknown(cartridge_type), false, false, false, false),
+    }
+}
+
+/// Titles are padded with zeros, anything that isn't printable ASCII is dropped
+fn parse_title(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|c| **c != 0)
+        .filter(|c| c.is_ascii_graphic() || **c == b' ')
+        .map(|c| *c as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom_with_header(title: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..(0x134 + title.len())].copy_from_slice(title);
+        rom
+    }
+
+    #[test]
+    fn test_parse_too_small() {
+        assert_eq!(CartridgeHeader::parse(&[0; 0x14F]), None);
+    }
+
+    #[test]
+    fn test_parse_dmg_header() {
+        let mut rom = rom_with_header(b"TETRIS");
+        rom[0x146] = 0x03;
+        rom[0x147] = 0x03;
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x02;
+        rom[0x14B] = 0x01;
+        rom[0x14C] = 0x01;
+
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "TETRIS");
+        assert_eq!(header.manufacturer_code, None);
+        assert_eq!(header.cgb_support, CgbSupport::None);
+        assert!(header.sgb_support);
+        assert_eq!(header.licensee, Licensee::Old(0x01));
+        assert_eq!(header.mapper, Mapper::Mbc1);
+        assert!(header.has_ram && header.has_battery);
+        assert!(!header.has_timer && !header.has_rumble);
+        assert_eq!(header.rom_size, Some(0x10000));
+        assert_eq!(header.ram_size, Some(0x2000));
+        assert_eq!(header.mask_rom_version, 0x01);
+    }
+
+    #[test]
+    fn test_parse_cgb_header() {
+        let mut rom = rom_with_header(b"POKEMON YEAAYYE");
+        rom[0x143] = 0x80;
+        rom[0x144..0x146].copy_from_slice(b"01");
+        rom[0x14B] = 0x33;
+        rom[0x147] = 0x1B;
+        rom[0x148] = 0x52;
+
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "POKEMON YEA");
+        assert_eq!(header.manufacturer_code, Some("AYYE".to_owned()));
+        assert_eq!(header.cgb_support, CgbSupport::Enhanced);
+        assert_eq!(header.licensee, Licensee::New("01".to_owned()));
+        assert_eq!(header.mapper, Mapper::Mbc5);
+        assert_eq!(header.rom_size, Some(0x120000));
+    }
+
+    #[test]
+    fn test_checksums() {
+        let mut rom = rom_with_header(b"TEST");
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(!header.header_checksum_valid());
+
+        rom[0x14D] = header.computed_header_checksum;
+        let global_checksum = header
+            .computed_global_checksum
+            .wrapping_add(rom[0x14D] as u16);
+        rom[0x14E..0x150].copy_from_slice(&global_checksum.to_be_bytes());
+
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(header.header_checksum_valid());
+        assert!(header.global_checksum_valid());
+    }
+}
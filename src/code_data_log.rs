@@ -0,0 +1,137 @@
+use crate::instructions::instruction_length;
+use std::{fs, io, path::Path};
+
+/// The byte was executed as the first byte of an instruction
+pub const CDL_EXEC_FIRST: u8 = 0b0000_0001;
+/// The byte was read as part of an instruction after the opcode
+pub const CDL_EXEC_OPERAND: u8 = 0b0000_0010;
+/// The byte was read as data
+pub const CDL_DATA: u8 = 0b0000_0100;
+
+/// Code/Data Log of the cartridge ROM
+///
+/// Holds one flag byte per byte of ROM, indexed by the offset into the ROM file
+/// (`bank * 0x4000 + address - 0x4000` for the switchable banks)
+#[derive(Debug)]
+pub struct CodeDataLog {
+    flags: Vec<u8>,
+    program_counter: u16,
+    opcode_fetched: bool,
+    operand_end: u16,
+}
+
+impl CodeDataLog {
+    pub fn new(rom_size: usize) -> CodeDataLog {
+        CodeDataLog {
+            flags: vec![0; rom_size],
+            program_counter: 0,
+            opcode_fetched: true,
+            operand_end: 0,
+        }
+    }
+
+    /// Create a log from the contents of a saved `.cdl` file
+    ///
+    /// Logs of a different size than the ROM are padded or truncated to `rom_size`
+    pub fn from_bytes(bytes: &[u8], rom_size: usize) -> CodeDataLog {
+        let mut log = CodeDataLog::new(rom_size);
+        let length = bytes.len().min(rom_size);
+        log.flags[..length].copy_from_slice(&bytes[..length]);
+        log
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.flags
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, &self.flags)
+    }
+
+    /// Returns the flags recorded for the byte at `offset` in the ROM file
+    pub fn flags(&self, offset: usize) -> u8 {
+        self.flags.get(offset).copied().unwrap_or(0)
+    }
+
+    /// Mark the start of the instruction at `program_counter`
+    ///
+    /// Must be called before the CPU parses the instruction so the opcode fetch is recognized
+    pub fn begin_instruction(&mut self, program_counter: u16) {
+        self.program_counter = program_counter;
+        self.opcode_fetched = false;
+        self.operand_end = program_counter;
+    }
+
+    /// Record a CPU read of `value` from `address`, which maps to `offset` in the ROM file
+    pub fn record_read(&mut self, address: u16, offset: usize, value: u8) {
+        let flag = if !self.opcode_fetched && address == self.program_counter {
+            // Only the first read of the program counter is the opcode fetch
+            self.opcode_fetched = true;
+            self.operand_end = self.program_counter.wrapping_add(instruction_length(value));
+            CDL_EXEC_FIRST
+        } else if address > self.program_counter && address < self.operand_end {
+            CDL_EXEC_OPERAND
+        } else {
+            CDL_DATA
+        };
+
+        if let Some(flags) = self.flags.get_mut(offset) {
+            *flags |= flag;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_instruction_and_data() {
+        let mut log = CodeDataLog::new(0x8000);
+
+        // LD A,(a16) at $0150 reading from $1234
+        log.begin_instruction(0x150);
+        log.record_read(0x150, 0x150, 0xFA);
+        log.record_read(0x151, 0x151, 0x34);
+        log.record_read(0x152, 0x152, 0x12);
+        log.record_read(0x1234, 0x1234, 0x00);
+
+        assert_eq!(log.flags(0x150), CDL_EXEC_FIRST);
+        assert_eq!(log.flags(0x151), CDL_EXEC_OPERAND);
+        assert_eq!(log.flags(0x152), CDL_EXEC_OPERAND);
+        assert_eq!(log.flags(0x153), 0);
+        assert_eq!(log.flags(0x1234), CDL_DATA);
+    }
+
+    #[test]
+    fn test_reads_without_instruction_are_data() {
+        let mut log = CodeDataLog::new(0x8000);
+
+        log.record_read(0x4000, 0x4000, 0x00);
+
+        assert_eq!(log.flags(0x4000), CDL_DATA);
+    }
+
+    #[test]
+    fn test_flags_accumulate() {
+        let mut log = CodeDataLog::new(0x8000);
+
+        log.begin_instruction(0x200);
+        log.record_read(0x200, 0x200, 0x00);
+        log.record_read(0x200, 0x200, 0x00);
+
+        assert_eq!(log.flags(0x200), CDL_EXEC_FIRST | CDL_DATA);
+    }
+
+    #[test]
+    fn test_from_bytes_resizes() {
+        let log = CodeDataLog::from_bytes(&[CDL_DATA; 0x10], 0x8);
+        assert_eq!(log.as_bytes(), &[CDL_DATA; 0x8]);
+
+        let log = CodeDataLog::from_bytes(&[CDL_DATA; 0x4], 0x8);
+        assert_eq!(
+            log.as_bytes(),
+            &[CDL_DATA, CDL_DATA, CDL_DATA, CDL_DATA, 0, 0, 0, 0]
+        );
+    }
+}
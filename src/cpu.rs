@@ -1,6 +1,7 @@
 use crate::{
     alu_result::AluResult,
     instructions::{ConditionalFlag, DoubleRegister, Instruction, Register},
+    model::Model,
     util::*,
 };
 
@@ -9,6 +10,31 @@ mod cpu_tests;
 pub trait CpuBus {
     fn read(&mut self, address: u16) -> u8;
     fn write(&mut self, address: u16, val: u8);
+
+    /// Called before a 16-bit register holding `address` is incremented or decremented outside of
+    /// a read, which puts `address` on the address bus
+    fn increment_address(&mut self, _address: u16) {}
+
+    /// Read `address` while the register holding it is incremented or decremented
+    fn read_increment(&mut self, address: u16) -> u8 {
+        self.read(address)
+    }
+
+    /// Called when the CPU executes STOP, returns true if the CPU switched speed instead of
+    /// stopping
+    fn switch_speed(&mut self) -> bool {
+        false
+    }
+
+    /// Called when the CPU enters STOP mode, which resets DIV
+    fn stop(&mut self) {}
+
+    /// Returns true if one of the joypad input lines selected in P1 is low, which ends STOP mode
+    ///
+    /// Nothing is clocked while the CPU is stopped, so this must not advance time
+    fn joypad_line_low(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, Default)]
@@ -27,8 +53,11 @@ pub struct Cpu {
     pub stack_pointer: u16,
     pub program_counter: u16,
     halt: bool,
+    /// STOP mode, the system clock is stopped until a joypad input line goes low
+    stopped: bool,
     pub interrupts_enabled: bool,
-    pub debug: bool,
+    /// Handler address of the interrupt serviced by the last executed instruction
+    serviced_interrupt: Option<u16>,
 }
 
 impl Cpu {
@@ -48,11 +77,53 @@ impl Cpu {
             stack_pointer: 0xFFFE,
             program_counter: 0,
             halt: false,
+            stopped: false,
             interrupts_enabled: true,
-            debug: false,
+            serviced_interrupt: None,
         }
     }
 
+    /// Set the registers to the values the boot ROM of `model` leaves behind
+    ///
+    /// The DMG and MGB boot ROMs leave the half-carry and carry flags set unless the header checksum is 0.
+    /// The CGB boot ROM leaves different values when it switched to DMG compatibility mode.
+    pub fn set_post_boot_state(&mut self, model: Model, cgb_mode: bool, header_checksum: u8) {
+        let checksum_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+
+        let [a, f, b, c, d, e, h, l] = match model {
+            Model::Dmg0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+            Model::Dmg => [0x01, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Mgb => [0xFF, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::Cgb if cgb_mode => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            Model::Cgb => [0x11, 0x80, 0x00, 0x00, 0x00, 0x08, 0x00, 0x7C],
+        };
+
+        self.a = a;
+        self.byte_to_flags(f);
+        self.b = b;
+        self.c = c;
+        self.d = d;
+        self.e = e;
+        self.h = h;
+        self.l = l;
+        self.stack_pointer = 0xFFFE;
+        self.program_counter = 0x0100;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halt
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Returns the handler address if an interrupt was serviced after the last executed instruction
+    pub fn serviced_interrupt(&self) -> Option<u16> {
+        self.serviced_interrupt
+    }
+
     pub fn bc(&self) -> u16 {
         combine_bytes(self.b, self.c)
     }
@@ -65,6 +136,16 @@ impl Cpu {
         combine_bytes(self.h, self.l)
     }
 
+    fn double_register(&self, register: DoubleRegister) -> u16 {
+        match register {
+            DoubleRegister::BC => self.bc(),
+            DoubleRegister::DE => self.de(),
+            DoubleRegister::HL => self.hl(),
+            DoubleRegister::SP => self.stack_pointer,
+            _ => panic!("Invalid Instruction"),
+        }
+    }
+
     fn increment_hl(&mut self) {
         if self.hl() == u16::MAX {
             self.h = 0;
@@ -106,6 +187,11 @@ impl Cpu {
     }
 
     pub fn parse(&mut self, cpu_bus: &mut impl CpuBus) -> Instruction {
+        // A stopped CPU doesn't fetch anything, it just waits on the joypad
+        if self.stopped {
+            return Instruction::Stop;
+        }
+
         let instruction = cpu_bus.read(self.program_counter);
 
         match (get_upper_bits(instruction), get_lower_bits(instruction)) {
@@ -379,9 +465,6 @@ impl Cpu {
             (0xD, 0x2) => Instruction::JumpConditional {
                 flag: ConditionalFlag::NC,
             },
-            (0xD, 0x3) => Instruction::CallConditional {
-                flag: ConditionalFlag::NZ,
-            },
             (0xD, 0x4) => Instruction::CallConditional {
                 flag: ConditionalFlag::NC,
             },
@@ -413,6 +496,18 @@ impl Cpu {
             (0xF, 0xA) => Instruction::LoadAAddress,
             (0xF, 0xB) => Instruction::EnableInterrupts,
             (0xF, 0xE) => Instruction::CompareA,
+            // Unused opcodes
+            (0xD, 0x3)
+            | (0xD, 0xB)
+            | (0xD, 0xD)
+            | (0xE, 0x3)
+            | (0xE, 0x4)
+            | (0xE, 0xB)
+            | (0xE, 0xC)
+            | (0xE, 0xD)
+            | (0xF, 0x4)
+            | (0xF, 0xC)
+            | (0xF, 0xD) => Instruction::Invalid,
             (reg, 0x1) => {
                 if reg < 4 {
                     let registers = [
@@ -778,6 +873,8 @@ impl Cpu {
     }
 
     pub fn execute(&mut self, instruction: Instruction, cpu_bus: &mut impl CpuBus) {
+        self.serviced_interrupt = None;
+
         match instruction {
             Instruction::Invalid => todo!(),
             // 8-bit load instructions
@@ -910,7 +1007,7 @@ impl Cpu {
                 self.program_counter += 1;
             }
             Instruction::LoadIncrementAHL => {
-                self.a = cpu_bus.read(self.hl());
+                self.a = cpu_bus.read_increment(self.hl());
                 self.increment_hl();
                 self.program_counter += 1;
             }
@@ -920,7 +1017,7 @@ impl Cpu {
                 self.program_counter += 1;
             }
             Instruction::LoadDecrementAHL => {
-                self.a = cpu_bus.read(self.hl());
+                self.a = cpu_bus.read_increment(self.hl());
                 self.decrement_hl();
                 self.program_counter += 1;
             }
@@ -965,6 +1062,7 @@ impl Cpu {
                 self.program_counter += 1;
             }
             Instruction::PushReg { register } => {
+                cpu_bus.increment_address(self.stack_pointer);
                 self.stack_pointer -= 2;
 
                 match register {
@@ -990,8 +1088,8 @@ impl Cpu {
                 self.program_counter += 1;
             }
             Instruction::PopReg { register } => {
-                let lower = cpu_bus.read(self.stack_pointer);
-                let upper = cpu_bus.read(self.stack_pointer + 1);
+                let lower = cpu_bus.read_increment(self.stack_pointer);
+                let upper = cpu_bus.read_increment(self.stack_pointer + 1);
 
                 match register {
                     DoubleRegister::BC => {
@@ -1436,6 +1534,7 @@ impl Cpu {
                 self.program_counter += 1;
             }
             Instruction::IncrementReg16 { register } => {
+                cpu_bus.increment_address(self.double_register(register));
                 match register {
                     DoubleRegister::BC => {
                         if self.bc() == u16::MAX {
@@ -1470,6 +1569,7 @@ impl Cpu {
                 self.program_counter += 1;
             }
             Instruction::DecrementReg16 { register } => {
+                cpu_bus.increment_address(self.double_register(register));
                 match register {
                     DoubleRegister::BC => {
                         if self.bc() == 0 {
@@ -1974,7 +2074,25 @@ impl Cpu {
             Instruction::Halt => {
                 self.halt = true;
             }
-            Instruction::Stop => todo!(),
+            Instruction::Stop => {
+                if self.stopped {
+                    // The CPU stays stopped until one of the selected joypad input lines goes low
+                    if cpu_bus.joypad_line_low() {
+                        self.stopped = false;
+                        self.program_counter += 2;
+                    }
+                } else if cpu_bus.switch_speed() {
+                    // An armed CGB speed switch happens instead of stopping
+                    self.program_counter += 2;
+                } else {
+                    cpu_bus.stop();
+                    if cpu_bus.joypad_line_low() {
+                        self.program_counter += 2;
+                    } else {
+                        self.stopped = true;
+                    }
+                }
+            }
             Instruction::DisableInterrupts => {
                 self.interrupts_enabled = false;
                 self.program_counter += 1;
@@ -2066,8 +2184,8 @@ impl Cpu {
                 }
             }
             Instruction::Return => {
-                let low = cpu_bus.read(self.stack_pointer);
-                let high = cpu_bus.read(self.stack_pointer + 1);
+                let low = cpu_bus.read_increment(self.stack_pointer);
+                let high = cpu_bus.read_increment(self.stack_pointer + 1);
                 self.program_counter = combine_bytes(high, low);
                 self.stack_pointer += 2;
             }
@@ -2080,8 +2198,8 @@ impl Cpu {
                 };
 
                 if predicate {
-                    let low = cpu_bus.read(self.stack_pointer);
-                    let high = cpu_bus.read(self.stack_pointer + 1);
+                    let low = cpu_bus.read_increment(self.stack_pointer);
+                    let high = cpu_bus.read_increment(self.stack_pointer + 1);
                     self.program_counter = combine_bytes(high, low);
                     self.stack_pointer += 2;
                 } else {
@@ -2089,8 +2207,8 @@ impl Cpu {
                 }
             }
             Instruction::ReturnAndEnableInterrupts => {
-                let low = cpu_bus.read(self.stack_pointer);
-                let high = cpu_bus.read(self.stack_pointer + 1);
+                let low = cpu_bus.read_increment(self.stack_pointer);
+                let high = cpu_bus.read_increment(self.stack_pointer + 1);
                 self.interrupts_enabled = true;
                 self.program_counter = combine_bytes(high, low);
                 self.stack_pointer += 2;
@@ -2105,6 +2223,11 @@ impl Cpu {
             }
         }
 
+        // Interrupts can't be serviced without a clock
+        if self.stopped {
+            return;
+        }
+
         self.check_interrupts(instruction, cpu_bus);
     }
 
@@ -2126,22 +2249,27 @@ impl Cpu {
                     // VBlank
                     cpu_bus.write(0xFF0F, requested_interrupt_flags & 0xFE);
                     self.call_address(cpu_bus, 0x40);
+                    self.serviced_interrupt = Some(0x40);
                 } else if interrupt_flags[6] == 1 {
                     // LCD STAT
                     cpu_bus.write(0xFF0F, requested_interrupt_flags & 0xFD);
                     self.call_address(cpu_bus, 0x48);
+                    self.serviced_interrupt = Some(0x48);
                 } else if interrupt_flags[5] == 1 {
                     // Timer
                     cpu_bus.write(0xFF0F, requested_interrupt_flags & 0xFB);
                     self.call_address(cpu_bus, 0x50);
+                    self.serviced_interrupt = Some(0x50);
                 } else if interrupt_flags[4] == 1 {
                     // Serial
                     cpu_bus.write(0xFF0F, requested_interrupt_flags & 0xF7);
                     self.call_address(cpu_bus, 0x58);
+                    self.serviced_interrupt = Some(0x58);
                 } else if interrupt_flags[3] == 1 {
                     // Joypad
                     cpu_bus.write(0xFF0F, requested_interrupt_flags & 0xEF);
                     self.call_address(cpu_bus, 0x60);
+                    self.serviced_interrupt = Some(0x60);
                 }
             }
         } else if self.halt {
@@ -2362,6 +2490,7 @@ impl Cpu {
 
     /// Push the PC onto the stack, then set the PC to the given address
     fn call_address(&mut self, cpu_bus: &mut impl CpuBus, address: u16) {
+        cpu_bus.increment_address(self.stack_pointer);
         self.stack_pointer -= 2;
         cpu_bus.write(self.stack_pointer, get_lower_byte(self.program_counter));
         cpu_bus.write(self.stack_pointer + 1, get_upper_byte(self.program_counter));
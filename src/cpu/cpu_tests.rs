@@ -231,3 +231,22 @@ fn test_rst_8() {
     assert_eq!(memory.read(cpu.stack_pointer), 1);
     assert_eq!(cpu.program_counter, 0x18);
 }
+
+#[test]
+fn test_parse_unused_opcodes() {
+    let mut cpu = Cpu::new();
+    let mut memory = Memory::new();
+    memory.write(0xFF50, 1);
+
+    for opcode in [
+        0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+    ] {
+        memory.rom[0] = opcode;
+        assert_eq!(
+            cpu.parse(&mut memory),
+            Instruction::Invalid,
+            "opcode {:0>2X}",
+            opcode
+        );
+    }
+}
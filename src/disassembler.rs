@@ -0,0 +1,705 @@
+use crate::{
+    code_data_log::{CodeDataLog, CDL_EXEC_FIRST},
+    cpu::{Cpu, CpuBus},
+    instructions::{instruction_length, Instruction, Register},
+    util::combine_bytes,
+};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::{self, Write},
+};
+
+/// Number of identical bytes in a row before they are emitted with `ds` instead of `db`
+const FILL_THRESHOLD: usize = 16;
+
+/// Reads a ROM image as the CPU would see it with `bank` mapped at `0x4000`
+struct RomBus<'a> {
+    rom: &'a [u8],
+    bank: usize,
+}
+
+impl CpuBus for RomBus<'_> {
+    fn read(&mut self, address: u16) -> u8 {
+        rom_offset(self.bank, address)
+            .and_then(|offset| self.rom.get(offset))
+            .copied()
+            .unwrap_or(0xFF)
+    }
+
+    fn write(&mut self, _address: u16, _val: u8) {}
+}
+
+/// Returns the offset into the ROM image of `address` with `bank` mapped at `0x4000`
+///
+/// Bank 0 is never mapped at `0x4000`, so code in bank 0 can't run on into the switchable area
+fn rom_offset(bank: usize, address: u16) -> Option<usize> {
+    match address {
+        0x0000..=0x3FFF => Some(address as usize),
+        0x4000..=0x7FFF if bank != 0 => Some(bank * 0x4000 + address as usize - 0x4000),
+        _ => None,
+    }
+}
+
+/// Returns the bank and CPU address of an offset into the ROM image
+fn bank_address(offset: usize) -> (usize, u16) {
+    let bank = offset / 0x4000;
+    if bank == 0 {
+        (0, offset as u16)
+    } else {
+        (bank, (0x4000 + offset % 0x4000) as u16)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DecodedInstruction {
+    pub instruction: Instruction,
+    /// The opcode followed by any operands
+    pub bytes: Vec<u8>,
+}
+
+impl DecodedInstruction {
+    pub fn operands(&self) -> &[u8] {
+        // The second byte of prefixed instructions is part of the opcode
+        if self.bytes[0] == 0xCB {
+            &[]
+        } else {
+            &self.bytes[1..]
+        }
+    }
+}
+
+/// Decode the instruction at `address` with `bank` mapped into the switchable ROM area
+///
+/// Returns `None` for invalid opcodes and instructions that run off the end of a bank
+pub fn decode(rom: &[u8], bank: usize, address: u16) -> Option<DecodedInstruction> {
+    let mut bus = RomBus { rom, bank };
+    let offset = rom_offset(bank, address)?;
+    let opcode = *rom.get(offset)?;
+    let length = instruction_length(opcode);
+
+    let last_address = address.checked_add(length - 1)?;
+    if (address < 0x4000) != (last_address < 0x4000) || last_address > 0x7FFF {
+        return None;
+    }
+    if rom_offset(bank, last_address)? >= rom.len() {
+        return None;
+    }
+
+    let mut cpu = Cpu::new();
+    cpu.program_counter = address;
+    let instruction = cpu.parse(&mut bus);
+
+    if instruction == Instruction::Invalid {
+        return None;
+    }
+
+    let bytes = (0..length).map(|i| bus.read(address + i)).collect();
+
+    Some(DecodedInstruction { instruction, bytes })
+}
+
+/// Returns the address an instruction can transfer control to, if it is known statically
+pub fn branch_target(instruction: &Instruction, address: u16, operands: &[u8]) -> Option<u16> {
+    match instruction {
+        Instruction::Jump
+        | Instruction::JumpConditional { .. }
+        | Instruction::Call
+        | Instruction::CallConditional { .. } => Some(combine_bytes(operands[1], operands[0])),
+        Instruction::JumpRelative | Instruction::JumpRelativeConditional { .. } => {
+            let offset = operands[0] as i8;
+            Some(address.wrapping_add(2).wrapping_add(offset as u16))
+        }
+        Instruction::Reset0 { location } => Some(((location % 4) * 0x10) as u16),
+        Instruction::Reset8 { location } => Some(((location % 4) * 0x10 + 0x8) as u16),
+        _ => None,
+    }
+}
+
+/// Returns true if execution never continues with the following instruction
+fn ends_block(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Jump
+            | Instruction::JumpHL
+            | Instruction::JumpRelative
+            | Instruction::Return
+            | Instruction::ReturnAndEnableInterrupts
+    )
+}
+
+/// Returns true if RGBDS would not assemble the formatted instruction back into the same bytes
+fn needs_raw_bytes(decoded: &DecodedInstruction) -> bool {
+    match decoded.instruction {
+        // Can be optimized into LDH by the assembler
+        Instruction::LoadAAddress | Instruction::LoadAddressA => decoded.bytes[2] == 0xFF,
+        // The assembler always emits STOP as $10 $00
+        Instruction::Stop => decoded.bytes[1] != 0x00,
+        _ => false,
+    }
+}
+
+/// Format an instruction in RGBDS syntax
+///
+/// `target` replaces the address of jumps and calls, for use with labels
+pub fn format_instruction(
+    instruction: &Instruction,
+    operands: &[u8],
+    target: Option<&str>,
+) -> String {
+    let n8 = || format!("${:02x}", operands[0]);
+    let n16 = || format!("${:04x}", combine_bytes(operands[1], operands[0]));
+    let e8 = || {
+        let offset = operands[0] as i8;
+        if offset < 0 {
+            format!("-{}", offset.unsigned_abs())
+        } else {
+            format!("{}", offset)
+        }
+    };
+    let jump_target = || match target {
+        Some(label) => label.to_owned(),
+        None => n16(),
+    };
+    let relative_target = || match target {
+        Some(label) => label.to_owned(),
+        None => format!("@ + 2 + {}", e8()),
+    };
+
+    match instruction {
+        Instruction::Invalid => "db ?".to_owned(),
+        // 8-bit load instructions
+        Instruction::LoadReg { dst, src } => format!("ld {}, {}", dst, src),
+        Instruction::LoadReg8 { register } => format!("ld {}, {}", register, n8()),
+        Instruction::LoadRegHL { register } => format!("ld {}, [hl]", register),
+        Instruction::LoadHLReg { register } => format!("ld [hl], {}", register),
+        Instruction::LoadHL8 => format!("ld [hl], {}", n8()),
+        Instruction::LoadABC => "ld a, [bc]".to_owned(),
+        Instruction::LoadADE => "ld a, [de]".to_owned(),
+        Instruction::LoadAAddress => format!("ld a, [{}]", n16()),
+        Instruction::LoadBCA => "ld [bc], a".to_owned(),
+        Instruction::LoadDEA => "ld [de], a".to_owned(),
+        Instruction::LoadAddressA => format!("ld [{}], a", n16()),
+        Instruction::LoadAOffset => format!("ldh a, [$ff{:02x}]", operands[0]),
+        Instruction::LoadOffsetA => format!("ldh [$ff{:02x}], a", operands[0]),
+        Instruction::LoadAOffsetC => "ldh a, [c]".to_owned(),
+        Instruction::LoadOffsetCA => "ldh [c], a".to_owned(),
+        Instruction::LoadIncrementHLA => "ld [hl+], a".to_owned(),
+        Instruction::LoadIncrementAHL => "ld a, [hl+]".to_owned(),
+        Instruction::LoadDecrementHLA => "ld [hl-], a".to_owned(),
+        Instruction::LoadDecrementAHL => "ld a, [hl-]".to_owned(),
+
+        // 16-bit load instructions
+        Instruction::LoadReg16 { register } => format!("ld {}, {}", register, n16()),
+        Instruction::LoadAddressSP => format!("ld [{}], sp", n16()),
+        Instruction::LoadSPHL => "ld sp, hl".to_owned(),
+        Instruction::PushReg { register } => format!("push {}", register),
+        Instruction::PopReg { register } => format!("pop {}", register),
+
+        // 8-bit Arithmetic/Logic instructions
+        Instruction::AddAReg { register } => format!("add a, {}", register),
+        Instruction::AddA => format!("add a, {}", n8()),
+        Instruction::AddAHL => "add a, [hl]".to_owned(),
+        Instruction::AddCarryAReg { register } => format!("adc a, {}", register),
+        Instruction::AddCarryA => format!("adc a, {}", n8()),
+        Instruction::AddCarryAHL => "adc a, [hl]".to_owned(),
+        Instruction::SubtractAReg { register } => format!("sub a, {}", register),
+        Instruction::SubtractA => format!("sub a, {}", n8()),
+        Instruction::SubtractAHL => "sub a, [hl]".to_owned(),
+        Instruction::SubtractARegCarry { register } => format!("sbc a, {}", register),
+        Instruction::SubtractACarry => format!("sbc a, {}", n8()),
+        Instruction::SubtractAHLCarry => "sbc a, [hl]".to_owned(),
+        Instruction::AndAReg { register } => format!("and a, {}", register),
+        Instruction::AndA => format!("and a, {}", n8()),
+        Instruction::AndAHL => "and a, [hl]".to_owned(),
+        Instruction::XorAReg { register } => format!("xor a, {}", register),
+        Instruction::XorA => format!("xor a, {}", n8()),
+        Instruction::XorAHL => "xor a, [hl]".to_owned(),
+        Instruction::OrAReg { register } => format!("or a, {}", register),
+        Instruction::OrA => format!("or a, {}", n8()),
+        Instruction::OrAHL => "or a, [hl]".to_owned(),
+        Instruction::CompareAReg { register } => format!("cp a, {}", register),
+        Instruction::CompareA => format!("cp a, {}", n8()),
+        Instruction::CompareAHL => "cp a, [hl]".to_owned(),
+        Instruction::IncrementReg { register } => format!("inc {}", register),
+        Instruction::IncrementHL => "inc [hl]".to_owned(),
+        Instruction::DecrementReg { register } => format!("dec {}", register),
+        Instruction::DecrementHL => "dec [hl]".to_owned(),
+        Instruction::DecimalAdjustA => "daa".to_owned(),
+        Instruction::Complement => "cpl".to_owned(),
+
+        // 16-bit Arithmetic/Logic instructions
+        Instruction::AddHLReg { register } => format!("add hl, {}", register),
+        Instruction::IncrementReg16 { register } => format!("inc {}", register),
+        Instruction::DecrementReg16 { register } => format!("dec {}", register),
+        Instruction::AddSPOffset => format!("add sp, {}", e8()),
+        Instruction::LoadHLSPOffset => {
+            let offset = operands[0] as i8;
+            if offset < 0 {
+                format!("ld hl, sp - {}", offset.unsigned_abs())
+            } else {
+                format!("ld hl, sp + {}", offset)
+            }
+        }
+
+        // Rotate and Shift instructions
+        Instruction::RotateALeft => "rlca".to_owned(),
+        Instruction::RotateALeftThroughCarry => "rla".to_owned(),
+        Instruction::RotateARight => "rrca".to_owned(),
+        Instruction::RotateARightThroughCarry => "rra".to_owned(),
+        Instruction::RotateLeft { register } => format!("rlc {}", register),
+        Instruction::RotateHLLeft => "rlc [hl]".to_owned(),
+        Instruction::RotateLeftThroughCarry { register } => format!("rl {}", register),
+        Instruction::RotateHLLeftThroughCarry => "rl [hl]".to_owned(),
+        Instruction::RotateRight { register } => format!("rrc {}", register),
+        Instruction::RotateHLRight => "rrc [hl]".to_owned(),
+        Instruction::RotateRightThroughCarry { register } => format!("rr {}", register),
+        Instruction::RotateHLRightThroughCarry => "rr [hl]".to_owned(),
+        Instruction::ShiftLeftArithmetic { register } => format!("sla {}", register),
+        Instruction::ShiftHLLeftArithmetic => "sla [hl]".to_owned(),
+        Instruction::Swap { register } => format!("swap {}", register),
+        Instruction::SwapHL => "swap [hl]".to_owned(),
+        Instruction::ShiftRightArithmetic { register } => format!("sra {}", register),
+        Instruction::ShiftHLRightArithmetic => "sra [hl]".to_owned(),
+        Instruction::ShiftRightLogical { register } => format!("srl {}", register),
+        Instruction::ShiftHLRightLogical => "srl [hl]".to_owned(),
+
+        // Single-bit operation instructions
+        Instruction::TestBit { bit, register } => format!("bit {}, {}", bit, register),
+        Instruction::TestHLBit { bit } => format!("bit {}, [hl]", bit),
+        Instruction::SetBit { bit, register } => format!("set {}, {}", bit, register),
+        Instruction::SetHLBit { bit } => format!("set {}, [hl]", bit),
+        Instruction::ResetBit { bit, register } => format!("res {}, {}", bit, register),
+        Instruction::ResetHLBit { bit } => format!("res {}, [hl]", bit),
+
+        // CPU Control instructions
+        Instruction::FlipCarryFlag => "ccf".to_owned(),
+        Instruction::SetCarryFlag => "scf".to_owned(),
+        Instruction::Nop => "nop".to_owned(),
+        Instruction::Halt => "halt".to_owned(),
+        Instruction::Stop => "stop".to_owned(),
+        Instruction::DisableInterrupts => "di".to_owned(),
+        Instruction::EnableInterrupts => "ei".to_owned(),
+
+        // Jump instructions
+        Instruction::Jump => format!("jp {}", jump_target()),
+        Instruction::JumpHL => "jp hl".to_owned(),
+        Instruction::JumpConditional { flag } => format!("jp {}, {}", flag, jump_target()),
+        Instruction::JumpRelative => format!("jr {}", relative_target()),
+        Instruction::JumpRelativeConditional { flag } => {
+            format!("jr {}, {}", flag, relative_target())
+        }
+        Instruction::Call => format!("call {}", jump_target()),
+        Instruction::CallConditional { flag } => format!("call {}, {}", flag, jump_target()),
+        Instruction::Return => "ret".to_owned(),
+        Instruction::ReturnConditional { flag } => format!("ret {}", flag),
+        Instruction::ReturnAndEnableInterrupts => "reti".to_owned(),
+        Instruction::Reset0 { location } => format!("rst ${:02x}", (location % 4) * 0x10),
+        Instruction::Reset8 { location } => format!("rst ${:02x}", (location % 4) * 0x10 + 0x8),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LabelKind {
+    Call,
+    Jump,
+    Relative,
+}
+
+/// Walks a ROM image from its entry points and emits RGBDS source that assembles back into the same image
+pub struct Disassembler<'a> {
+    rom: &'a [u8],
+    /// Length of the instruction starting at each ROM offset, 0 if no instruction starts there
+    instructions: Vec<u8>,
+    /// Whether each ROM offset belongs to a decoded instruction
+    covered: Vec<bool>,
+    labels: BTreeMap<usize, String>,
+    /// ROM offset of the target of each jump or call, keyed by the offset of the instruction
+    targets: HashMap<usize, usize>,
+    /// Code left to walk, as (bank, address, ROM bank selected by the code so far)
+    queue: VecDeque<(usize, u16, Option<usize>)>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(rom: &'a [u8]) -> Disassembler<'a> {
+        Disassembler {
+            rom,
+            instructions: vec![0; rom.len()],
+            covered: vec![false; rom.len()],
+            labels: BTreeMap::new(),
+            targets: HashMap::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn bank_count(&self) -> usize {
+        self.rom.len().div_ceil(0x4000)
+    }
+
+    /// Add the boot entry point, the interrupt vectors and the RST targets
+    pub fn add_default_entry_points(&mut self) {
+        for location in (0x00..=0x38).step_by(8) {
+            self.add_entry_point(0, location, Some(&format!("RST_{:02x}", location)));
+        }
+
+        self.add_entry_point(0, 0x40, Some("VBlankInterrupt"));
+        self.add_entry_point(0, 0x48, Some("LCDCInterrupt"));
+        self.add_entry_point(0, 0x50, Some("TimerOverflowInterrupt"));
+        self.add_entry_point(0, 0x58, Some("SerialTransferCompleteInterrupt"));
+        self.add_entry_point(0, 0x60, Some("JoypadTransitionInterrupt"));
+        self.add_entry_point(0, 0x100, Some("Boot"));
+    }
+
+    pub fn add_entry_point(&mut self, bank: usize, address: u16, name: Option<&str>) {
+        let Some(offset) = rom_offset(bank, address).filter(|offset| *offset < self.rom.len())
+        else {
+            return;
+        };
+
+        if let Some(name) = name {
+            self.labels.entry(offset).or_insert_with(|| name.to_owned());
+        }
+
+        self.queue.push_back((bank, address, None));
+    }
+
+    /// Add every instruction the code/data logger saw executed as an entry point
+    pub fn add_code_data_log(&mut self, code_data_log: &CodeDataLog) {
+        for offset in 0..self.rom.len() {
+            if code_data_log.flags(offset) & CDL_EXEC_FIRST != 0 {
+                let (bank, address) = bank_address(offset);
+                self.queue.push_back((bank, address, None));
+            }
+        }
+    }
+
+    /// Decode every instruction reachable from the entry points
+    pub fn run(&mut self) {
+        while let Some((bank, address, selected_bank)) = self.queue.pop_front() {
+            self.walk(bank, address, selected_bank);
+        }
+    }
+
+    fn walk(&mut self, bank: usize, mut address: u16, mut selected_bank: Option<usize>) {
+        let mut a_value = None;
+
+        loop {
+            let Some(offset) = rom_offset(bank, address) else {
+                return;
+            };
+            let Some(decoded) = decode(self.rom, bank, address) else {
+                return;
+            };
+            let length = decoded.bytes.len();
+
+            if self.covered[offset..(offset + length)].iter().any(|c| *c) {
+                return;
+            }
+
+            self.instructions[offset] = length as u8;
+            self.covered[offset..(offset + length)].fill(true);
+
+            // Follow `ld a, n` / `ld [$2000], a` so calls from bank 0 into bank N can be resolved
+            match decoded.instruction {
+                Instruction::LoadReg8 {
+                    register: Register::A,
+                } => a_value = Some(decoded.bytes[1] as usize),
+                Instruction::LoadAddressA => {
+                    let target = combine_bytes(decoded.bytes[2], decoded.bytes[1]);
+                    if (0x2000..=0x3FFF).contains(&target) {
+                        selected_bank = a_value.map(|bank| bank.max(1));
+                    }
+                }
+                _ => {}
+            }
+
+            if let Some(target) = branch_target(&decoded.instruction, address, decoded.operands()) {
+                let kind = match decoded.instruction {
+                    Instruction::JumpRelative | Instruction::JumpRelativeConditional { .. } => {
+                        LabelKind::Relative
+                    }
+                    Instruction::Jump | Instruction::JumpConditional { .. } => LabelKind::Jump,
+                    _ => LabelKind::Call,
+                };
+
+                let target_bank = match target {
+                    0x0000..=0x3FFF => Some(0),
+                    0x4000..=0x7FFF if bank != 0 => Some(bank),
+                    0x4000..=0x7FFF => Some(selected_bank.unwrap_or(1)),
+                    _ => None,
+                };
+
+                if let Some(target_bank) = target_bank.filter(|b| *b < self.bank_count()) {
+                    if let Some(target_offset) =
+                        rom_offset(target_bank, target).filter(|o| *o < self.rom.len())
+                    {
+                        self.add_label(target_offset, kind);
+                        self.targets.insert(offset, target_offset);
+                        self.queue.push_back((target_bank, target, selected_bank));
+                    }
+                }
+            }
+
+            if ends_block(&decoded.instruction) {
+                return;
+            }
+
+            match address.checked_add(length as u16) {
+                Some(next) => address = next,
+                None => return,
+            }
+        }
+    }
+
+    fn add_label(&mut self, offset: usize, kind: LabelKind) {
+        let (bank, address) = bank_address(offset);
+        let prefix = match kind {
+            LabelKind::Call => "Call",
+            LabelKind::Jump => "Jump",
+            LabelKind::Relative => "jr",
+        };
+
+        self.labels
+            .entry(offset)
+            .or_insert_with(|| format!("{}_{:03x}_{:04x}", prefix, bank, address));
+    }
+
+    /// Write the disassembly as RGBDS source, one section per ROM bank
+    pub fn write_source(&self, writer: &mut impl Write) -> io::Result<()> {
+        for bank in 0..self.bank_count() {
+            if bank == 0 {
+                writeln!(writer, "SECTION \"ROM Bank $000\", ROM0[$0000]")?;
+            } else {
+                writeln!(
+                    writer,
+                    "\nSECTION \"ROM Bank ${:03x}\", ROMX[$4000], BANK[${:x}]",
+                    bank, bank
+                )?;
+            }
+
+            let start = bank * 0x4000;
+            let end = (start + 0x4000).min(self.rom.len());
+            let mut offset = start;
+
+            while offset < end {
+                self.write_label(writer, offset)?;
+
+                let length = self.instructions[offset] as usize;
+                if length > 0 {
+                    self.write_instruction(writer, bank, offset)?;
+                    offset += length;
+                } else {
+                    // Data runs until the next instruction or label
+                    let mut data_end = offset + 1;
+                    while data_end < end
+                        && self.instructions[data_end] == 0
+                        && !self.labels.contains_key(&data_end)
+                    {
+                        data_end += 1;
+                    }
+                    self.write_data(writer, &self.rom[offset..data_end])?;
+                    offset = data_end;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_label(&self, writer: &mut impl Write, offset: usize) -> io::Result<()> {
+        if let Some(label) = self.labels.get(&offset) {
+            writeln!(writer, "\n{}:", label)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_instruction(
+        &self,
+        writer: &mut impl Write,
+        bank: usize,
+        offset: usize,
+    ) -> io::Result<()> {
+        let (_, address) = bank_address(offset);
+        let decoded = decode(self.rom, bank, address).expect("walked instructions decode");
+        let length = decoded.bytes.len();
+        let target = self
+            .targets
+            .get(&offset)
+            .and_then(|target| self.labels.get(target))
+            .map(|label| label.as_str());
+        let text = format_instruction(&decoded.instruction, decoded.operands(), target);
+
+        let has_inner_label = (1..length).any(|i| self.labels.contains_key(&(offset + i)));
+
+        if has_inner_label {
+            // Something jumps into the middle of this instruction, so emit it byte by byte
+            writeln!(writer, "    ; {}", text)?;
+            for (i, byte) in decoded.bytes.iter().enumerate() {
+                if i > 0 {
+                    self.write_label(writer, offset + i)?;
+                }
+                writeln!(writer, "    db ${:02x}", byte)?;
+            }
+        } else if needs_raw_bytes(&decoded) {
+            writeln!(writer, "    db {} ; {}", format_bytes(&decoded.bytes), text)?;
+        } else {
+            writeln!(writer, "    {}", text)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_data(&self, writer: &mut impl Write, data: &[u8]) -> io::Result<()> {
+        let mut index = 0;
+        let mut pending_start = 0;
+
+        while index < data.len() {
+            let run = data[index..]
+                .iter()
+                .take_while(|byte| **byte == data[index])
+                .count();
+
+            if run >= FILL_THRESHOLD {
+                write_db_lines(writer, &data[pending_start..index])?;
+                writeln!(writer, "    ds {}, ${:02x}", run, data[index])?;
+                pending_start = index + run;
+            }
+
+            index += run;
+        }
+
+        write_db_lines(writer, &data[pending_start..])
+    }
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("${:02x}", byte))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn write_db_lines(writer: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    for chunk in data.chunks(16) {
+        writeln!(writer, "    db {}", format_bytes(chunk))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disassemble(rom: &[u8]) -> String {
+        let mut disassembler = Disassembler::new(rom);
+        disassembler.add_default_entry_points();
+        disassembler.run();
+
+        let mut source = Vec::new();
+        disassembler.write_source(&mut source).unwrap();
+        String::from_utf8(source).unwrap()
+    }
+
+    fn format(bytes: &[u8]) -> String {
+        let mut rom = vec![0; 0x8000];
+        rom[..bytes.len()].copy_from_slice(bytes);
+        let decoded = decode(&rom, 1, 0).unwrap();
+        format_instruction(&decoded.instruction, decoded.operands(), None)
+    }
+
+    #[test]
+    fn test_format_instruction() {
+        assert_eq!(format(&[0x3E, 0x12]), "ld a, $12");
+        assert_eq!(format(&[0xCD, 0x34, 0x12]), "call $1234");
+        assert_eq!(format(&[0xE0, 0x40]), "ldh [$ff40], a");
+        assert_eq!(format(&[0x2A]), "ld a, [hl+]");
+        assert_eq!(format(&[0xCB, 0x7C]), "bit 7, h");
+        assert_eq!(format(&[0xF8, 0xFE]), "ld hl, sp - 2");
+        assert_eq!(format(&[0xE8, 0x05]), "add sp, 5");
+        assert_eq!(format(&[0xDF]), "rst $18");
+        assert_eq!(format(&[0xC8]), "ret z");
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let rom = [0xD3, 0x00];
+        assert_eq!(decode(&rom, 1, 0), None);
+    }
+
+    #[test]
+    fn test_decode_past_end_of_rom() {
+        let rom = [0x00, 0xC3, 0x00];
+        assert_eq!(decode(&rom, 1, 1), None);
+    }
+
+    #[test]
+    fn test_branch_target_relative() {
+        assert_eq!(
+            branch_target(&Instruction::JumpRelative, 0x150, &[0xFE]),
+            Some(0x150)
+        );
+        assert_eq!(
+            branch_target(&Instruction::JumpRelative, 0x150, &[0x10]),
+            Some(0x162)
+        );
+    }
+
+    #[test]
+    fn test_walk_follows_jumps_and_labels_targets() {
+        let mut rom = vec![0xFF; 0x8000];
+        // Boot: nop; jp $0150
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        // $0150: call $4000; jr $0150
+        rom[0x150..0x155].copy_from_slice(&[0xCD, 0x00, 0x40, 0x18, 0xFB]);
+        // $4000: ret
+        rom[0x4000] = 0xC9;
+
+        let source = disassemble(&rom);
+
+        assert!(source.contains("\nBoot:\n    nop\n    jp Jump_000_0150\n"));
+        assert!(source.contains("\nJump_000_0150:\n    call Call_001_4000\n"));
+        assert!(source.contains("    jr Jump_000_0150\n"));
+        assert!(source.contains("SECTION \"ROM Bank $001\", ROMX[$4000], BANK[$1]"));
+        assert!(source.contains("\nCall_001_4000:\n    ret\n"));
+    }
+
+    #[test]
+    fn test_walk_tracks_selected_bank() {
+        let mut rom = vec![0x00; 0xC000];
+        // Boot: ld a, 2; ld [$2000], a; call $4000; halt
+        rom[0x100..0x109].copy_from_slice(&[0x3E, 0x02, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40, 0x76]);
+        // Bank 2 $4000: ret
+        rom[0x8000] = 0xC9;
+
+        let source = disassemble(&rom);
+
+        assert!(source.contains("    call Call_002_4000\n"));
+        assert!(source.contains("\nCall_002_4000:\n    ret\n"));
+    }
+
+    #[test]
+    fn test_data_is_emitted_as_bytes() {
+        let mut rom = vec![0xFF; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        rom[0x102..0x105].copy_from_slice(&[0x01, 0x02, 0x03]);
+        // Invalid opcodes stop the walk
+        rom[0x00] = 0xD3;
+
+        let source = disassemble(&rom);
+
+        assert!(source.contains("\nBoot:\n    jr Boot\n    db $01, $02, $03\n    ds "));
+        assert!(source.contains("\nRST_00:\n    db $d3, $ff, "));
+    }
+
+    #[test]
+    fn test_high_page_loads_are_emitted_as_bytes() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0xEA, 0x40, 0xFF, 0x76]);
+
+        let source = disassemble(&rom);
+
+        assert!(source.contains("    db $ea, $40, $ff ; ld [$ff40], a\n"));
+    }
+}
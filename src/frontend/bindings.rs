@@ -0,0 +1,513 @@
+//! Maps keyboard keys and game controller inputs to Game Boy buttons and emulator hotkeys
+//!
+//! Bindings are read from INI style config files, each line in a section replaces every binding of one action.
+//! Lines starting with `#` are comments, and a comma that starts an entry of a list is the comma key:
+//!
+//! ```text
+//! # Keys use SDL key names
+//! [keyboard]
+//! a = S
+//! select = Left Shift, Right Shift
+//!
+//! # Controllers use SDL game controller button names, or an axis name followed by + or -
+//! [controller]
+//! up = dpup, lefty-
+//!
+//! [hotkeys]
+//! fast_forward = Tab
+//! slow_motion = ,, `
+//! ```
+
+use gameboy::joypad::{Button, JoypadState};
+use sdl2::{
+    controller::{Axis, Button as ControllerButton, GameController},
+    keyboard::Keycode,
+};
+use std::{collections::HashSet, fmt, fs, io, path::Path};
+
+/// How far an axis has to be pushed before it counts as pressed
+const AXIS_THRESHOLD: i16 = 16384;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hotkey {
+    Quit,
+    Pause,
+    FrameAdvance,
+    StepInstruction,
+    SoftReset,
+    HardReset,
+    FastForward,
+    SlowMotion,
+    Screenshot,
+    Debug,
+}
+
+impl Hotkey {
+    const ALL: [Hotkey; 10] = [
+        Hotkey::Quit,
+        Hotkey::Pause,
+        Hotkey::FrameAdvance,
+        Hotkey::StepInstruction,
+        Hotkey::SoftReset,
+        Hotkey::HardReset,
+        Hotkey::FastForward,
+        Hotkey::SlowMotion,
+        Hotkey::Screenshot,
+        Hotkey::Debug,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Hotkey::Quit => "quit",
+            Hotkey::Pause => "pause",
+            Hotkey::FrameAdvance => "frame_advance",
+            Hotkey::StepInstruction => "step_instruction",
+            Hotkey::SoftReset => "soft_reset",
+            Hotkey::HardReset => "hard_reset",
+            Hotkey::FastForward => "fast_forward",
+            Hotkey::SlowMotion => "slow_motion",
+            Hotkey::Screenshot => "screenshot",
+            Hotkey::Debug => "debug",
+        }
+    }
+}
+
+fn button_name(button: Button) -> &'static str {
+    match button {
+        Button::Right => "right",
+        Button::Left => "left",
+        Button::Up => "up",
+        Button::Down => "down",
+        Button::A => "a",
+        Button::B => "b",
+        Button::Select => "select",
+        Button::Start => "start",
+    }
+}
+
+/// An input on a game controller, using the SDL game controller names
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControllerInput {
+    Button(String),
+    /// An axis pushed in the positive (`true`) or negative direction
+    Axis(String, bool),
+}
+
+impl ControllerInput {
+    fn parse(text: &str) -> ControllerInput {
+        if let Some(axis) = text.strip_suffix('+') {
+            ControllerInput::Axis(axis.to_owned(), true)
+        } else if let Some(axis) = text.strip_suffix('-') {
+            ControllerInput::Axis(axis.to_owned(), false)
+        } else {
+            ControllerInput::Button(text.to_owned())
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Keyboard,
+    Controller,
+    Hotkeys,
+}
+
+/// Input names bound to each action, before they are looked up by SDL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bindings {
+    pub keyboard: Vec<(String, Button)>,
+    pub controller: Vec<(ControllerInput, Button)>,
+    pub hotkeys: Vec<(String, Hotkey)>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let keyboard = [
+            ("Right", Button::Right),
+            ("Left", Button::Left),
+            ("Up", Button::Up),
+            ("Down", Button::Down),
+            ("S", Button::A),
+            ("A", Button::B),
+            ("Right Shift", Button::Select),
+            ("Left Shift", Button::Select),
+            ("Return", Button::Start),
+        ];
+
+        let controller = [
+            (ControllerInput::Button("dpright".to_owned()), Button::Right),
+            (
+                ControllerInput::Axis("leftx".to_owned(), true),
+                Button::Right,
+            ),
+            (ControllerInput::Button("dpleft".to_owned()), Button::Left),
+            (
+                ControllerInput::Axis("leftx".to_owned(), false),
+                Button::Left,
+            ),
+            (ControllerInput::Button("dpup".to_owned()), Button::Up),
+            (ControllerInput::Axis("lefty".to_owned(), false), Button::Up),
+            (ControllerInput::Button("dpdown".to_owned()), Button::Down),
+            (
+                ControllerInput::Axis("lefty".to_owned(), true),
+                Button::Down,
+            ),
+            (ControllerInput::Button("a".to_owned()), Button::A),
+            (ControllerInput::Button("b".to_owned()), Button::B),
+            (ControllerInput::Button("back".to_owned()), Button::Select),
+            (ControllerInput::Button("start".to_owned()), Button::Start),
+        ];
+
+        let hotkeys = [
+            ("Escape", Hotkey::Quit),
+            ("P", Hotkey::Pause),
+            ("N", Hotkey::FrameAdvance),
+            ("I", Hotkey::StepInstruction),
+            ("F1", Hotkey::SoftReset),
+            ("F2", Hotkey::HardReset),
+            ("Tab", Hotkey::FastForward),
+            ("`", Hotkey::SlowMotion),
+            ("F11", Hotkey::Screenshot),
+            ("F12", Hotkey::Debug),
+        ];
+
+        Bindings {
+            keyboard: keyboard
+                .into_iter()
+                .map(|(key, button)| (key.to_owned(), button))
+                .collect(),
+            controller: controller.into_iter().collect(),
+            hotkeys: hotkeys
+                .into_iter()
+                .map(|(key, hotkey)| (key.to_owned(), hotkey))
+                .collect(),
+        }
+    }
+}
+
+impl Bindings {
+    /// Apply a config file on top of the current bindings, doing nothing if the file doesn't exist
+    ///
+    /// Returns whether the file was found
+    pub fn load(&mut self, path: impl AsRef<Path>) -> io::Result<bool> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(error) => return Err(error),
+        };
+
+        self.apply(&text)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))?;
+
+        Ok(true)
+    }
+
+    /// Apply the contents of a config file on top of the current bindings
+    pub fn apply(&mut self, text: &str) -> Result<(), ConfigError> {
+        let mut section = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let error = |message: String| ConfigError {
+                line: line_number,
+                message,
+            };
+
+            // Comments only start a line, so `#` can still be bound as a key
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Some(match name.trim() {
+                    "keyboard" => Section::Keyboard,
+                    "controller" => Section::Controller,
+                    "hotkeys" => Section::Hotkeys,
+                    name => return Err(error(format!("unknown section `{}`", name))),
+                });
+                continue;
+            }
+
+            let Some((action, inputs)) = line.split_once('=') else {
+                return Err(error(format!(
+                    "expected `action = inputs`, found `{}`",
+                    line
+                )));
+            };
+            let action = action.trim().to_ascii_lowercase();
+            let inputs = split_inputs(inputs).into_iter();
+
+            match section {
+                Some(Section::Keyboard) | Some(Section::Controller) => {
+                    let button = Button::ALL
+                        .into_iter()
+                        .find(|button| button_name(*button) == action)
+                        .ok_or_else(|| error(format!("unknown button `{}`", action)))?;
+
+                    if section == Some(Section::Keyboard) {
+                        self.keyboard.retain(|(_, b)| *b != button);
+                        self.keyboard
+                            .extend(inputs.map(|input| (input.to_owned(), button)));
+                    } else {
+                        self.controller.retain(|(_, b)| *b != button);
+                        self.controller
+                            .extend(inputs.map(|input| (ControllerInput::parse(input), button)));
+                    }
+                }
+                Some(Section::Hotkeys) => {
+                    let hotkey = Hotkey::ALL
+                        .into_iter()
+                        .find(|hotkey| hotkey.name() == action)
+                        .ok_or_else(|| error(format!("unknown hotkey `{}`", action)))?;
+
+                    self.hotkeys.retain(|(_, h)| *h != hotkey);
+                    self.hotkeys
+                        .extend(inputs.map(|input| (input.to_owned(), hotkey)));
+                }
+                None => return Err(error("binding outside of a section".to_owned())),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Look up the SDL keys, buttons and axes, warning about any names SDL doesn't know
+    pub fn resolve(&self) -> ResolvedBindings {
+        let keycode = |name: &str| {
+            let keycode = Keycode::from_name(name);
+            if keycode.is_none() {
+                eprintln!("Unknown key `{}` in bindings", name);
+            }
+            keycode
+        };
+
+        let mut resolved = ResolvedBindings {
+            keyboard: self
+                .keyboard
+                .iter()
+                .filter_map(|(name, button)| Some((keycode(name)?, *button)))
+                .collect(),
+            controller_buttons: Vec::new(),
+            controller_axes: Vec::new(),
+            hotkeys: self
+                .hotkeys
+                .iter()
+                .filter_map(|(name, hotkey)| Some((keycode(name)?, *hotkey)))
+                .collect(),
+        };
+
+        for (input, button) in &self.controller {
+            match input {
+                ControllerInput::Button(name) => match ControllerButton::from_string(name) {
+                    Some(controller_button) => resolved
+                        .controller_buttons
+                        .push((controller_button, *button)),
+                    None => eprintln!("Unknown controller button `{}` in bindings", name),
+                },
+                ControllerInput::Axis(name, positive) => match Axis::from_string(name) {
+                    Some(axis) => resolved.controller_axes.push((axis, *positive, *button)),
+                    None => eprintln!("Unknown controller axis `{}` in bindings", name),
+                },
+            }
+        }
+
+        resolved
+    }
+}
+
+/// Split a comma separated list of inputs, trimming each one
+///
+/// A comma with nothing before it in its entry is the comma key instead of a separator, so
+/// `,, Z` is the comma key and Z
+fn split_inputs(list: &str) -> Vec<&str> {
+    let mut inputs = Vec::new();
+    let mut start = 0;
+
+    for (index, character) in list.char_indices() {
+        if character == ',' && !list[start..index].trim().is_empty() {
+            inputs.push(list[start..index].trim());
+            start = index + 1;
+        }
+    }
+
+    let last = list[start..].trim();
+    if !last.is_empty() {
+        inputs.push(last);
+    }
+
+    inputs
+}
+
+/// Bindings with every input looked up by SDL
+#[derive(Debug)]
+pub struct ResolvedBindings {
+    keyboard: Vec<(Keycode, Button)>,
+    controller_buttons: Vec<(ControllerButton, Button)>,
+    controller_axes: Vec<(Axis, bool, Button)>,
+    hotkeys: Vec<(Keycode, Hotkey)>,
+}
+
+impl ResolvedBindings {
+    /// Returns the Game Boy buttons held down on the keyboard or any of the controllers
+    pub fn joypad_state(
+        &self,
+        pressed_keys: &HashSet<Keycode>,
+        controllers: &[GameController],
+    ) -> JoypadState {
+        let mut state = JoypadState::new();
+
+        for (keycode, button) in &self.keyboard {
+            if pressed_keys.contains(keycode) {
+                state.press(*button);
+            }
+        }
+
+        for controller in controllers {
+            for (controller_button, button) in &self.controller_buttons {
+                if controller.button(*controller_button) {
+                    state.press(*button);
+                }
+            }
+
+            for (axis, positive, button) in &self.controller_axes {
+                let value = controller.axis(*axis);
+                if (*positive && value > AXIS_THRESHOLD) || (!*positive && value < -AXIS_THRESHOLD)
+                {
+                    state.press(*button);
+                }
+            }
+        }
+
+        state
+    }
+
+    pub fn hotkey(&self, keycode: Keycode) -> Option<Hotkey> {
+        self.hotkeys
+            .iter()
+            .find(|(key, _)| *key == keycode)
+            .map(|(_, hotkey)| *hotkey)
+    }
+
+    /// Returns true if any key bound to `hotkey` is held down
+    pub fn is_held(&self, hotkey: Hotkey, pressed_keys: &HashSet<Keycode>) -> bool {
+        self.hotkeys
+            .iter()
+            .any(|(key, h)| *h == hotkey && pressed_keys.contains(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_replaces_bindings_of_an_action() {
+        let mut bindings = Bindings::default();
+
+        bindings
+            .apply(
+                "
+                # Remap A and leave everything else alone
+                [keyboard]
+                a = Z, X
+                [controller]
+                start = rightx+
+                [hotkeys]
+                fast_forward = Space
+                ",
+            )
+            .unwrap();
+
+        let a_keys: Vec<&str> = bindings
+            .keyboard
+            .iter()
+            .filter(|(_, button)| *button == Button::A)
+            .map(|(key, _)| key.as_str())
+            .collect();
+        assert_eq!(a_keys, vec!["Z", "X"]);
+        assert!(bindings
+            .keyboard
+            .contains(&("Return".to_owned(), Button::Start)));
+
+        assert!(bindings.controller.contains(&(
+            ControllerInput::Axis("rightx".to_owned(), true),
+            Button::Start
+        )));
+        assert!(!bindings
+            .controller
+            .contains(&(ControllerInput::Button("start".to_owned()), Button::Start)));
+
+        assert!(bindings
+            .hotkeys
+            .contains(&("Space".to_owned(), Hotkey::FastForward)));
+        assert!(!bindings
+            .hotkeys
+            .contains(&("Tab".to_owned(), Hotkey::FastForward)));
+    }
+
+    #[test]
+    fn test_apply_hash_key() {
+        let mut bindings = Bindings::default();
+
+        bindings
+            .apply("[hotkeys]\n  # Screenshot on #\nscreenshot = #, F12")
+            .unwrap();
+
+        assert!(bindings
+            .hotkeys
+            .contains(&("#".to_owned(), Hotkey::Screenshot)));
+        assert!(bindings
+            .hotkeys
+            .contains(&("F12".to_owned(), Hotkey::Screenshot)));
+    }
+
+    #[test]
+    fn test_split_inputs() {
+        assert_eq!(split_inputs("Z, X"), vec!["Z", "X"]);
+        assert_eq!(split_inputs(",, Z"), vec![",", "Z"]);
+        assert_eq!(split_inputs("Z, ,"), vec!["Z", ","]);
+        assert_eq!(split_inputs(" , "), vec![","]);
+        assert_eq!(split_inputs("Left Shift,,"), vec!["Left Shift", ","]);
+        assert_eq!(split_inputs(""), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_apply_errors() {
+        let mut bindings = Bindings::default();
+
+        assert_eq!(
+            bindings.apply("a = Z"),
+            Err(ConfigError {
+                line: 1,
+                message: "binding outside of a section".to_owned()
+            })
+        );
+        assert_eq!(
+            bindings.apply("[keyboard]\n\nturbo = Z"),
+            Err(ConfigError {
+                line: 3,
+                message: "unknown button `turbo`".to_owned()
+            })
+        );
+        assert_eq!(
+            bindings.apply("[mouse]"),
+            Err(ConfigError {
+                line: 1,
+                message: "unknown section `mouse`".to_owned()
+            })
+        );
+    }
+}
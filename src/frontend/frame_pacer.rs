@@ -0,0 +1,84 @@
+use gameboy::gameboy::{CPU_FREQUENCY, CYCLES_PER_FRAME};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Time a frame takes on hardware, about 16.74 ms for a refresh rate of 59.73 Hz
+pub const FRAME_DURATION: Duration =
+    Duration::from_nanos(CYCLES_PER_FRAME * 1_000_000_000 / CPU_FREQUENCY);
+
+/// Presents frames at the hardware refresh rate, emulating more or fewer frames per present to change speed
+#[derive(Debug)]
+pub struct FramePacer {
+    next_present: Instant,
+    /// Emulated frames per presented frame
+    speed: f64,
+    /// Fraction of a frame owed from previous presents when the speed isn't a whole number
+    frame_budget: f64,
+}
+
+impl FramePacer {
+    pub fn new() -> FramePacer {
+        FramePacer {
+            next_present: Instant::now() + FRAME_DURATION,
+            speed: 1.0,
+            frame_budget: 0.0,
+        }
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed.max(0.0);
+    }
+
+    /// Returns how many frames to emulate before the next present
+    pub fn frames_to_run(&mut self) -> u32 {
+        self.frame_budget += self.speed;
+        let frames = self.frame_budget.floor();
+        self.frame_budget -= frames;
+        frames as u32
+    }
+
+    /// Sleep until the next frame should be presented
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+
+        if self.next_present > now {
+            thread::sleep(self.next_present - now);
+            self.next_present += FRAME_DURATION;
+        } else {
+            // Running behind, so drop the missed time rather than rushing to catch up
+            self.next_present = now + FRAME_DURATION;
+        }
+    }
+}
+
+impl Default for FramePacer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_duration() {
+        let refresh_rate = 1.0 / FRAME_DURATION.as_secs_f64();
+        assert!((refresh_rate - 59.73).abs() < 0.01, "{}", refresh_rate);
+    }
+
+    #[test]
+    fn test_frames_to_run() {
+        let mut pacer = FramePacer::new();
+        assert_eq!(pacer.frames_to_run(), 1);
+
+        pacer.set_speed(4.0);
+        assert_eq!(pacer.frames_to_run(), 4);
+
+        pacer.set_speed(0.5);
+        let frames: Vec<u32> = (0..4).map(|_| pacer.frames_to_run()).collect();
+        assert_eq!(frames, vec![0, 1, 0, 1]);
+    }
+}
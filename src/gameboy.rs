@@ -0,0 +1,616 @@
+use crate::{
+    apu::Apu,
+    cartridge_header::CartridgeHeader,
+    cpu::Cpu,
+    instructions::Instruction,
+    joypad::{Button, JoypadState},
+    memory::{LoadError, Memory},
+    model::Model,
+    ppu::{Ppu, Renderer},
+    profiler::Profiler,
+    trace::TraceWriter,
+};
+use std::{error, fmt, io::Write};
+
+/// Rate of the CPU clock in T-cycles per second
+pub const CPU_FREQUENCY: u64 = 4_194_304;
+
+/// T-cycles in one frame, 154 scanlines of 456 T-cycles each
+pub const CYCLES_PER_FRAME: u64 = 70224;
+
+/// The CPU fetched an opcode that doesn't exist, which locks up real hardware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidOpcode {
+    pub address: u16,
+}
+
+impl fmt::Display for InvalidOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid Instruction at {:0>4X}", self.address)
+    }
+}
+
+impl error::Error for InvalidOpcode {}
+
+/// A complete system: CPU, memory bus, PPU and APU running a single cartridge
+#[derive(Debug)]
+pub struct GameBoy {
+    pub cpu: Cpu,
+    pub memory: Memory,
+    pub ppu: Ppu,
+    pub apu: Apu,
+    /// Collects cycle counts around every executed instruction when set
+    pub profiler: Option<Profiler>,
+    /// Logs the CPU state before every executed instruction when set, cleared if a write fails
+    pub trace: Option<TraceWriter<Box<dyn Write>>>,
+    model: Model,
+    renderer: Renderer,
+    access_restrictions: bool,
+    cartridge: Vec<u8>,
+    boot_rom: Option<Vec<u8>>,
+    /// Value of `Memory::dots` when the APU was last stepped
+    last_dots: u64,
+    paused: bool,
+}
+
+impl GameBoy {
+    /// Create a system with a cartridge inserted, a CGB if the cartridge header says it supports
+    /// one and a DMG otherwise
+    ///
+    /// Without a boot ROM the system starts in the state the boot ROM leaves behind
+    pub fn new(cartridge: &[u8], boot_rom: Option<&[u8]>) -> Result<GameBoy, LoadError> {
+        let model = CartridgeHeader::parse(cartridge)
+            .map_or(Model::Dmg, |header| Model::for_cartridge(&header));
+        Self::with_model(cartridge, boot_rom, model)
+    }
+
+    /// Create a system of the given model with a cartridge inserted
+    ///
+    /// Without a boot ROM the system starts in the state the boot ROM of `model` leaves behind
+    pub fn with_model(
+        cartridge: &[u8],
+        boot_rom: Option<&[u8]>,
+        model: Model,
+    ) -> Result<GameBoy, LoadError> {
+        let mut gameboy = GameBoy {
+            cpu: Cpu::new(),
+            memory: Memory::new(),
+            ppu: Ppu::new(),
+            apu: Apu::default(),
+            profiler: None,
+            trace: None,
+            model,
+            renderer: Renderer::default(),
+            access_restrictions: true,
+            cartridge: cartridge.to_vec(),
+            boot_rom: boot_rom.map(|boot_rom| boot_rom.to_vec()),
+            last_dots: 0,
+            paused: false,
+        };
+        gameboy.power_on()?;
+        Ok(gameboy)
+    }
+
+    /// Power cycle the system, starting the cartridge from the beginning with cartridge RAM cleared
+    pub fn reset(&mut self) {
+        self.power_on()
+            .expect("The cartridge and boot ROM loaded when the system was created");
+    }
+
+    /// Put every component in its power on state and load the cartridge and boot ROM
+    fn power_on(&mut self) -> Result<(), LoadError> {
+        // Debugging tools keep collecting across resets
+        let code_data_log = self.memory.code_data_log.take();
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.restart();
+        }
+
+        self.cpu = Cpu::new();
+        self.memory = Memory::with_model(self.model);
+        self.memory.set_renderer(self.renderer);
+        self.memory
+            .set_access_restrictions(self.access_restrictions);
+        self.ppu = Ppu::new();
+        self.apu = Apu::new(self.apu.sample_rate());
+        self.last_dots = 0;
+
+        self.memory.load_cartridge(&self.cartridge)?;
+        self.memory.code_data_log = code_data_log;
+
+        match &self.boot_rom {
+            Some(boot_rom) => self.memory.load_boot_rom(boot_rom)?,
+            None => {
+                let header_checksum = self.memory.peek(0x14D);
+                self.memory.set_post_boot_state();
+                self.cpu
+                    .set_post_boot_state(self.model, self.memory.cgb_mode(), header_checksum);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restart the cartridge from the beginning, keeping the contents of cartridge RAM
+    pub fn soft_reset(&mut self) {
+        let cartridge_ram = std::mem::take(&mut self.memory.switchable_ram);
+        self.reset();
+        self.memory.switchable_ram = cartridge_ram;
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    /// Switch how lines are drawn, takes effect from the next line
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+        self.memory.set_renderer(renderer);
+    }
+
+    pub fn access_restrictions(&self) -> bool {
+        self.access_restrictions
+    }
+
+    /// Block CPU access to VRAM and OAM while the PPU uses them, as the hardware does
+    pub fn set_access_restrictions(&mut self, enabled: bool) {
+        self.access_restrictions = enabled;
+        self.memory.set_access_restrictions(enabled);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pause or resume `run_frame`, `step_frame` and `step_instruction` still advance while paused
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Fetch and execute a single instruction, returning it
+    ///
+    /// Invalid opcodes are returned as an error without being executed
+    pub fn step_instruction(&mut self) -> Result<Instruction, InvalidOpcode> {
+        let program_counter = self.cpu.program_counter;
+
+        // Halting re-executes HALT until an interrupt arrives, only log the first time
+        if let Some(trace) = self.trace.as_mut() {
+            if !self.memory.using_boot_rom() && !self.cpu.is_halted() && !self.cpu.is_stopped() {
+                // Stop logging rather than leave gaps in the log, without stopping the emulation
+                if trace.log(&self.cpu, &self.memory).is_err() {
+                    self.trace = None;
+                }
+            }
+        }
+
+        if let Some(code_data_log) = self.memory.code_data_log.as_mut() {
+            code_data_log.begin_instruction(program_counter);
+        }
+
+        let instruction = self.cpu.parse(&mut self.memory);
+
+        if instruction == Instruction::Invalid {
+            return Err(InvalidOpcode {
+                address: program_counter,
+            });
+        }
+
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.begin_instruction(&instruction, &self.cpu, &self.memory);
+            self.cpu.execute(instruction, &mut self.memory);
+            profiler.end_instruction(&self.cpu, &self.memory);
+        } else {
+            self.cpu.execute(instruction, &mut self.memory);
+        }
+
+        for y in self.memory.take_lines_to_render() {
+            self.ppu.render_line(&self.memory, y as usize);
+        }
+
+        let dots = self.memory.dots();
+        self.apu.step(dots - self.last_dots);
+        self.last_dots = dots;
+
+        Ok(instruction)
+    }
+
+    /// Run until the current frame has finished, leaving it in the framebuffer
+    pub fn step_frame(&mut self) -> Result<(), InvalidOpcode> {
+        while !self.memory.frame_happened {
+            self.step_instruction()?;
+
+            // Nothing is clocked while stopped, so the frame can't finish until a button is pressed
+            if self.cpu.is_stopped() {
+                return Ok(());
+            }
+        }
+
+        // Lines are drawn as they happen, the screen just needs blanking if the LCD was off
+        if self.memory.frame_blank() {
+            self.ppu.clear();
+        }
+        self.memory.frame_happened = false;
+
+        Ok(())
+    }
+
+    /// Run a frame like `step_frame` unless the system is paused
+    pub fn run_frame(&mut self) -> Result<(), InvalidOpcode> {
+        if self.paused {
+            return Ok(());
+        }
+
+        self.step_frame()
+    }
+
+    /// Returns the shade (0-3) of each pixel of the last completed frame, stored row by row
+    pub fn framebuffer(&self) -> &[u8] {
+        self.ppu.framebuffer()
+    }
+
+    /// Returns the 15-bit RGB color of each pixel of the last completed frame, stored row by row
+    ///
+    /// Only the CGB has RGB output, other models return `None`
+    pub fn rgb_framebuffer(&self) -> Option<&[u16]> {
+        (self.model == Model::Cgb).then(|| self.ppu.rgb_framebuffer())
+    }
+
+    /// Returns the interleaved left and right audio samples produced since the last call
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.apu.take_samples()
+    }
+
+    /// Replace the state of every button at once
+    pub fn set_buttons(&mut self, state: JoypadState) {
+        self.memory.set_joypad_state(state);
+    }
+
+    pub fn press(&mut self, button: Button) {
+        let mut state = self.memory.joypad_state();
+        state.press(button);
+        self.memory.set_joypad_state(state);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        let mut state = self.memory.joypad_state();
+        state.release(button);
+        self.memory.set_joypad_state(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+    fn cartridge(program: &[u8]) -> Vec<u8> {
+        let mut cartridge = vec![0; 0x8000];
+        cartridge[0x100..(0x100 + program.len())].copy_from_slice(program);
+        cartridge[0x14D] = 0xE7;
+        cartridge
+    }
+
+    #[test]
+    fn test_starts_in_post_boot_state_without_boot_rom() {
+        let gameboy = GameBoy::new(&cartridge(&[]), None).unwrap();
+
+        assert_eq!(gameboy.cpu.program_counter, 0x100);
+        assert_eq!(gameboy.cpu.flags_to_byte(), 0xB0);
+        assert!(!gameboy.memory.using_boot_rom());
+    }
+
+    #[test]
+    fn test_post_boot_state_per_model() {
+        let mut zero_checksum = cartridge(&[]);
+        zero_checksum[0x14D] = 0x00;
+        let gameboy = GameBoy::with_model(&zero_checksum, None, Model::Dmg).unwrap();
+        assert_eq!((gameboy.cpu.a, gameboy.cpu.flags_to_byte()), (0x01, 0x80));
+
+        let gameboy = GameBoy::with_model(&cartridge(&[]), None, Model::Mgb).unwrap();
+        assert_eq!((gameboy.cpu.a, gameboy.cpu.flags_to_byte()), (0xFF, 0xB0));
+
+        let gameboy = GameBoy::with_model(&cartridge(&[]), None, Model::Dmg0).unwrap();
+        assert_eq!((gameboy.cpu.b, gameboy.cpu.h), (0xFF, 0x84));
+        assert_eq!(gameboy.memory.peek(0xFF04), 0x18);
+
+        let gameboy = GameBoy::with_model(&cartridge(&[]), None, Model::Sgb).unwrap();
+        assert_eq!((gameboy.cpu.c, gameboy.cpu.h), (0x14, 0xC0));
+        assert_eq!(gameboy.memory.peek(0xFF26), 0xF0);
+
+        let gameboy = GameBoy::with_model(&cartridge(&[]), None, Model::Cgb).unwrap();
+        assert_eq!((gameboy.cpu.a, gameboy.cpu.flags_to_byte()), (0x11, 0x80));
+        assert_eq!(gameboy.cpu.stack_pointer, 0xFFFE);
+        assert_eq!(gameboy.cpu.program_counter, 0x100);
+    }
+
+    #[test]
+    fn test_model_from_header() {
+        assert_eq!(
+            GameBoy::new(&cartridge(&[]), None).unwrap().model(),
+            Model::Dmg
+        );
+
+        let mut cgb_cartridge = cartridge(&[]);
+        cgb_cartridge[0x143] = 0xC0;
+        let gameboy = GameBoy::new(&cgb_cartridge, None).unwrap();
+        assert_eq!(gameboy.model(), Model::Cgb);
+        assert!(gameboy.memory.cgb_mode());
+
+        // An explicit model overrides the header
+        let gameboy = GameBoy::with_model(&cgb_cartridge, None, Model::Dmg).unwrap();
+        assert_eq!(gameboy.model(), Model::Dmg);
+    }
+
+    #[test]
+    fn test_cgb_mode_from_header() {
+        let mut cgb_cartridge = cartridge(&[]);
+        cgb_cartridge[0x143] = 0x80;
+        let gameboy = GameBoy::with_model(&cgb_cartridge, None, Model::Cgb).unwrap();
+        assert!(gameboy.memory.cgb_mode());
+        assert_eq!(
+            (gameboy.cpu.d, gameboy.cpu.e, gameboy.cpu.l),
+            (0xFF, 0x56, 0x0D)
+        );
+        assert_eq!(gameboy.memory.peek(0xFF4C), 0x80);
+
+        // DMG cartridges run in compatibility mode
+        let gameboy = GameBoy::with_model(&cartridge(&[]), None, Model::Cgb).unwrap();
+        assert!(!gameboy.memory.cgb_mode());
+        assert_eq!(
+            (gameboy.cpu.d, gameboy.cpu.e, gameboy.cpu.l),
+            (0x00, 0x08, 0x7C)
+        );
+        assert_eq!(gameboy.memory.peek(0xFF4C), 0x04);
+        assert_eq!(gameboy.memory.peek(0xFF4F), 0xFF);
+
+        // The CGB boot ROM is 2304 bytes and starts in CGB mode
+        let gameboy = GameBoy::with_model(&cartridge(&[]), Some(&[0; 0x900]), Model::Cgb).unwrap();
+        assert!(gameboy.memory.cgb_mode());
+    }
+
+    #[test]
+    fn test_stop_switches_speed() {
+        // LD A,$01; LDH ($4D),A; STOP
+        let mut cgb_cartridge = cartridge(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00]);
+        cgb_cartridge[0x143] = 0xC0;
+        let mut gameboy = GameBoy::with_model(&cgb_cartridge, None, Model::Cgb).unwrap();
+
+        for _ in 0..3 {
+            gameboy.step_instruction().unwrap();
+        }
+        assert!(gameboy.memory.double_speed());
+        assert_eq!(gameboy.memory.peek(0xFF4D), 0xFE);
+        assert_eq!(gameboy.cpu.program_counter, 0x106);
+
+        // The PPU and APU run at half the CPU speed
+        let (cycles, dots) = (gameboy.memory.cycles(), gameboy.memory.dots());
+        gameboy.step_instruction().unwrap();
+        let elapsed_cycles = gameboy.memory.cycles() - cycles;
+        assert_eq!(gameboy.memory.dots() - dots, elapsed_cycles / 2);
+    }
+
+    #[test]
+    fn test_stop_freezes_the_system() {
+        // LD A,$10; LDH ($00),A; STOP
+        let mut gameboy =
+            GameBoy::new(&cartridge(&[0x3E, 0x10, 0xE0, 0x00, 0x10, 0x00]), None).unwrap();
+        for _ in 0..3 {
+            gameboy.step_instruction().unwrap();
+        }
+        assert!(gameboy.cpu.is_stopped());
+        assert_eq!(gameboy.memory.peek(0xFF04), 0x00, "STOP resets DIV");
+
+        // A pending VBlank isn't serviced either
+        gameboy.memory.write(0xFFFF, 0x01);
+        gameboy.memory.io_registers[0x0F] |= 0x01;
+        let (cycles, ly) = (gameboy.memory.cycles(), gameboy.memory.peek(0xFF44));
+        let div = gameboy.memory.peek(0xFF04);
+        for _ in 0..100 {
+            gameboy.step_instruction().unwrap();
+        }
+        gameboy.step_frame().unwrap();
+        assert_eq!(gameboy.memory.cycles(), cycles);
+        assert_eq!(gameboy.memory.peek(0xFF04), div);
+        assert_eq!(gameboy.memory.peek(0xFF44), ly);
+        assert_eq!(gameboy.cpu.program_counter, 0x104);
+
+        // Waking up services the VBlank interrupt that was held back
+        gameboy.press(Button::A);
+        gameboy.step_instruction().unwrap();
+        assert!(!gameboy.cpu.is_stopped());
+        assert_eq!(gameboy.cpu.serviced_interrupt(), Some(0x40));
+        assert_eq!(gameboy.cpu.stack_pointer, 0xFFFC);
+    }
+
+    /// Writer that fails every write, like a closed pipe
+    struct BrokenPipe;
+
+    impl Write for BrokenPipe {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace_dropped_on_write_error() {
+        let mut gameboy = GameBoy::new(&cartridge(&[0x00, 0x00]), None).unwrap();
+        gameboy.trace = Some(TraceWriter::new(Box::new(BrokenPipe)));
+
+        gameboy.step_instruction().unwrap();
+        assert!(gameboy.trace.is_none());
+        gameboy.step_instruction().unwrap();
+    }
+
+    #[test]
+    fn test_post_boot_logo_in_vram() {
+        let mut cartridge = cartridge(&[]);
+        cartridge[0x104] = 0xCE;
+        let gameboy = GameBoy::new(&cartridge, None).unwrap();
+
+        // 0xC doubled, written to two rows in bit plane 0
+        assert_eq!(gameboy.memory.vram[0x10..0x14], [0xF0, 0x00, 0xF0, 0x00]);
+        // 0xE doubled
+        assert_eq!(gameboy.memory.vram[0x14..0x18], [0xFC, 0x00, 0xFC, 0x00]);
+        assert_eq!(gameboy.memory.vram[0x190], 0x3C);
+        assert_eq!(gameboy.memory.vram[0x1904], 0x01);
+        assert_eq!(gameboy.memory.vram[0x1910], 0x19);
+        assert_eq!(gameboy.memory.vram[0x192F], 0x18);
+    }
+
+    #[test]
+    fn test_step_instruction() {
+        // LD A,$12
+        let mut gameboy = GameBoy::new(&cartridge(&[0x3E, 0x12]), None).unwrap();
+
+        assert_eq!(
+            gameboy.step_instruction(),
+            Ok(Instruction::LoadReg8 {
+                register: crate::instructions::Register::A
+            })
+        );
+        assert_eq!(gameboy.cpu.a, 0x12);
+        assert_eq!(gameboy.cpu.program_counter, 0x102);
+    }
+
+    #[test]
+    fn test_step_instruction_invalid_opcode() {
+        let mut gameboy = GameBoy::new(&cartridge(&[0xD3]), None).unwrap();
+
+        assert_eq!(
+            gameboy.step_instruction(),
+            Err(InvalidOpcode { address: 0x100 })
+        );
+    }
+
+    #[test]
+    fn test_step_frame() {
+        // JR -2
+        let mut gameboy = GameBoy::new(&cartridge(&[0x18, 0xFE]), None).unwrap();
+
+        gameboy.step_frame().unwrap();
+
+        assert_eq!(gameboy.framebuffer().len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert!(!gameboy.memory.frame_happened);
+        assert!(!gameboy.audio_samples().is_empty());
+    }
+
+    #[test]
+    fn test_step_frame_runs_one_frame_of_cycles() {
+        // JR -2
+        let mut gameboy = GameBoy::new(&cartridge(&[0x18, 0xFE]), None).unwrap();
+
+        gameboy.step_frame().unwrap();
+        let start = gameboy.memory.cycles();
+        gameboy.step_frame().unwrap();
+
+        // Frames can only end on instruction boundaries
+        let cycles = gameboy.memory.cycles() - start;
+        assert!(cycles.abs_diff(CYCLES_PER_FRAME) < 24, "{}", cycles);
+    }
+
+    #[test]
+    fn test_pixel_fifo_renderer() {
+        // JR -2
+        let mut cartridge = cartridge(&[0x18, 0xFE]);
+        // Something to draw in the logo left in VRAM
+        cartridge[0x104..0x134].fill(0xA5);
+        let mut gameboy = GameBoy::new(&cartridge, None).unwrap();
+        gameboy.set_renderer(Renderer::PixelFifo);
+        gameboy.step_frame().unwrap();
+        gameboy.step_frame().unwrap();
+        let pixel_fifo_frame = gameboy.framebuffer().to_vec();
+
+        gameboy.reset();
+        assert_eq!(gameboy.renderer(), Renderer::PixelFifo);
+        gameboy.set_renderer(Renderer::Scanline);
+        gameboy.step_frame().unwrap();
+        gameboy.step_frame().unwrap();
+
+        assert!(pixel_fifo_frame.contains(&3));
+        assert_eq!(pixel_fifo_frame, gameboy.framebuffer());
+    }
+
+    #[test]
+    fn test_run_frame_while_paused() {
+        // JR -2
+        let mut gameboy = GameBoy::new(&cartridge(&[0x18, 0xFE]), None).unwrap();
+        gameboy.set_paused(true);
+
+        gameboy.run_frame().unwrap();
+        assert_eq!(gameboy.memory.cycles(), 0);
+
+        gameboy.step_frame().unwrap();
+        assert!(gameboy.memory.cycles() > 0);
+    }
+
+    #[test]
+    fn test_soft_reset_keeps_cartridge_ram() {
+        let mut cartridge = cartridge(&[]);
+        // MBC1 with 8 KiB of RAM
+        cartridge[0x147] = 0x01;
+        cartridge[0x149] = 0x02;
+        let mut gameboy = GameBoy::new(&cartridge, None).unwrap();
+        gameboy.memory.write(0x0000, 0x0A);
+        gameboy.memory.write(0xA000, 0x12);
+
+        gameboy.soft_reset();
+        gameboy.memory.write(0x0000, 0x0A);
+        assert_eq!(gameboy.memory.read(0xA000), 0x12);
+
+        gameboy.reset();
+        gameboy.memory.write(0x0000, 0x0A);
+        assert_eq!(gameboy.memory.read(0xA000), 0x00);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut gameboy = GameBoy::new(&cartridge(&[0x3E, 0x12]), None).unwrap();
+        gameboy.step_instruction().unwrap();
+
+        gameboy.reset();
+
+        assert_eq!(gameboy.cpu.a, 0x01);
+        assert_eq!(gameboy.cpu.program_counter, 0x100);
+        assert_eq!(gameboy.memory.cycles(), 0);
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert_eq!(
+            GameBoy::new(&[0; 0x4000], None).unwrap_err(),
+            LoadError::TooSmall { size: 0x4000 }
+        );
+
+        let mut mbc3 = cartridge(&[]);
+        mbc3[0x147] = 0x13;
+        assert_eq!(
+            GameBoy::new(&mbc3, None).unwrap_err(),
+            LoadError::UnsupportedMapper(crate::cartridge_header::Mapper::Mbc3)
+        );
+
+        let mut truncated = cartridge(&[]);
+        truncated[0x148] = 0x01;
+        assert_eq!(
+            GameBoy::new(&truncated, None).unwrap_err(),
+            LoadError::SizeMismatch {
+                expected: 0x10000,
+                found: 0x8000
+            }
+        );
+
+        assert_eq!(
+            GameBoy::new(&cartridge(&[]), Some(&[0; 0x900])).unwrap_err(),
+            LoadError::BadBootRom {
+                size: 0x900,
+                expected: 0x100
+            }
+        );
+    }
+}
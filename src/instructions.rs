@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConditionalFlag {
     NZ,
@@ -26,7 +28,47 @@ pub enum DoubleRegister {
     SP,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl fmt::Display for ConditionalFlag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ConditionalFlag::NZ => "nz",
+            ConditionalFlag::Z => "z",
+            ConditionalFlag::NC => "nc",
+            ConditionalFlag::C => "c",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Register::B => "b",
+            Register::C => "c",
+            Register::D => "d",
+            Register::E => "e",
+            Register::H => "h",
+            Register::L => "l",
+            Register::A => "a",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for DoubleRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DoubleRegister::BC => "bc",
+            DoubleRegister::DE => "de",
+            DoubleRegister::HL => "hl",
+            DoubleRegister::AF => "af",
+            DoubleRegister::SP => "sp",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     Invalid,
     // 8-bit load instructions
@@ -337,3 +379,30 @@ pub enum Instruction {
         location: u8,
     },
 }
+
+/// Returns the length in bytes of the instruction starting with `opcode`, including any operands
+///
+/// Invalid opcodes are treated as a single byte
+pub fn instruction_length(opcode: u8) -> u16 {
+    match opcode {
+        // LD R,d8 / LD (HL),d8
+        0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => 2,
+        // STOP 0
+        0x10 => 2,
+        // JR r8 / JR f,r8
+        0x18 | 0x20 | 0x28 | 0x30 | 0x38 => 2,
+        // 8-bit arithmetic/logic with d8
+        0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => 2,
+        // LDH (a8),A / LDH A,(a8) / ADD SP,r8 / LD HL,SP+r8
+        0xE0 | 0xF0 | 0xE8 | 0xF8 => 2,
+        // Prefixed instructions
+        0xCB => 2,
+        // LD RR,d16 / LD (a16),SP
+        0x01 | 0x11 | 0x21 | 0x31 | 0x08 => 3,
+        // JP a16 / JP f,a16 / CALL a16 / CALL f,a16
+        0xC2 | 0xC3 | 0xC4 | 0xCA | 0xCC | 0xCD | 0xD2 | 0xD4 | 0xDA | 0xDC => 3,
+        // LD (a16),A / LD A,(a16)
+        0xEA | 0xFA => 3,
+        _ => 1,
+    }
+}
@@ -1,150 +1,260 @@
-use sdl2::keyboard::Keycode;
-use std::collections::HashSet;
-
-#[derive(Debug, Clone, Copy, Default)]
-pub enum ButtonType {
-    #[default]
-    None,
-    Action,
-    Direction,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
 }
 
-#[derive(Debug, Default)]
-pub struct Joypad {
-    pub selected_buttons: ButtonType,
-    down_pressed: bool,
-    up_pressed: bool,
-    left_pressed: bool,
-    right_pressed: bool,
-    start_pressed: bool,
-    select_pressed: bool,
-    b_pressed: bool,
-    a_pressed: bool,
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    /// Returns the bit of the button in a `JoypadState`
+    pub fn mask(self) -> u8 {
+        match self {
+            Button::Right => 0b0000_0001,
+            Button::Left => 0b0000_0010,
+            Button::Up => 0b0000_0100,
+            Button::Down => 0b0000_1000,
+            Button::A => 0b0001_0000,
+            Button::B => 0b0010_0000,
+            Button::Select => 0b0100_0000,
+            Button::Start => 0b1000_0000,
+        }
+    }
 }
 
-impl Joypad {
-    pub fn set_inputs(&mut self, pressed_keys: HashSet<Keycode>) {
-        self.reset_buttons();
+/// The set of pressed buttons, a set bit means the button is held down
+/// * Bits 0-3: Right, Left, Up, Down
+/// * Bits 4-7: A, B, Select, Start
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JoypadState(u8);
 
-        if pressed_keys.contains(&Keycode::Down) {
-            self.down_pressed = true;
-        }
+impl JoypadState {
+    pub fn new() -> JoypadState {
+        JoypadState(0)
+    }
 
-        if pressed_keys.contains(&Keycode::Up) {
-            self.up_pressed = true;
-        }
+    pub fn press(&mut self, button: Button) {
+        self.0 |= button.mask();
+    }
 
-        if pressed_keys.contains(&Keycode::Left) {
-            self.left_pressed = true;
-        }
+    pub fn release(&mut self, button: Button) {
+        self.0 &= !button.mask();
+    }
 
-        if pressed_keys.contains(&Keycode::Right) {
-            self.right_pressed = true;
-        }
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.0 & button.mask() != 0
+    }
 
-        if pressed_keys.contains(&Keycode::Return) {
-            self.start_pressed = true;
-        }
+    /// Replace every button at once, using the bit layout of `JoypadState`
+    pub fn set_state(&mut self, state: u8) {
+        self.0 = state;
+    }
 
-        if pressed_keys.contains(&Keycode::RShift) || pressed_keys.contains(&Keycode::LShift) {
-            self.select_pressed = true;
-        }
+    pub fn state(&self) -> u8 {
+        self.0
+    }
 
-        if pressed_keys.contains(&Keycode::A) {
-            self.b_pressed = true;
-        }
+    /// Returns the direction buttons as P1 reports them, where 0 means pressed
+    fn direction_bits(&self) -> u8 {
+        !self.0 & 0x0F
+    }
+
+    /// Returns the action buttons as P1 reports them, where 0 means pressed
+    fn action_bits(&self) -> u8 {
+        !(self.0 >> 4) & 0x0F
+    }
+}
 
-        if pressed_keys.contains(&Keycode::S) {
-            self.a_pressed = true;
+/// P1 select line for the direction buttons, selected when the bit is 0
+const SELECT_DIRECTION: u8 = 0b0001_0000;
+/// P1 select line for the action buttons, selected when the bit is 0
+const SELECT_ACTION: u8 = 0b0010_0000;
+
+#[derive(Debug)]
+pub struct Joypad {
+    /// Bits 4 and 5 of P1, the only writable bits
+    select: u8,
+    state: JoypadState,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: SELECT_DIRECTION | SELECT_ACTION,
+            state: JoypadState::new(),
         }
     }
 
     pub fn as_byte(&self) -> u8 {
-        match self.selected_buttons {
-            ButtonType::Action => {
-                let binary_string = format!(
-                    "1101{}{}{}{}",
-                    (!self.start_pressed) as u8,
-                    (!self.select_pressed) as u8,
-                    (!self.b_pressed) as u8,
-                    (!self.a_pressed) as u8
-                );
-                u8::from_str_radix(&binary_string, 2).unwrap()
-            }
-            ButtonType::Direction => {
-                let binary_string = format!(
-                    "1110{}{}{}{}",
-                    (!self.down_pressed) as u8,
-                    (!self.up_pressed) as u8,
-                    (!self.left_pressed) as u8,
-                    (!self.right_pressed) as u8
-                );
-                u8::from_str_radix(&binary_string, 2).unwrap()
-            }
-            ButtonType::None => 0xFF,
+        0b1100_0000 | self.select | self.input_lines()
+    }
+
+    pub fn state(&self) -> JoypadState {
+        self.state
+    }
+
+    /// Write the select lines of P1
+    ///
+    /// Returns true if an input line went from high to low, which requests the joypad interrupt
+    pub fn write(&mut self, data: u8) -> bool {
+        let old_lines = self.input_lines();
+        self.select = data & (SELECT_DIRECTION | SELECT_ACTION);
+        falling_edge(old_lines, self.input_lines())
+    }
+
+    /// Replace the pressed buttons
+    ///
+    /// Returns true if an input line went from high to low, which requests the joypad interrupt
+    pub fn set_state(&mut self, state: JoypadState) -> bool {
+        let old_lines = self.input_lines();
+        self.state = state;
+        falling_edge(old_lines, self.input_lines())
+    }
+
+    /// Returns the lower 4 bits of P1
+    ///
+    /// Each input line is shared by a direction and an action button,
+    /// so with both groups selected a line is low if either button is pressed
+    fn input_lines(&self) -> u8 {
+        let mut lines = 0x0F;
+
+        if self.select & SELECT_DIRECTION == 0 {
+            lines &= self.state.direction_bits();
+        }
+
+        if self.select & SELECT_ACTION == 0 {
+            lines &= self.state.action_bits();
         }
+
+        lines
     }
+}
 
-    fn reset_buttons(&mut self) {
-        self.down_pressed = false;
-        self.up_pressed = false;
-        self.left_pressed = false;
-        self.right_pressed = false;
-        self.start_pressed = false;
-        self.select_pressed = false;
-        self.b_pressed = false;
-        self.a_pressed = false;
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+fn falling_edge(old_lines: u8, new_lines: u8) -> bool {
+    old_lines & !new_lines & 0x0F != 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_as_byte_action() {
-        let mut joypad = Joypad::default();
-        joypad.selected_buttons = ButtonType::Action;
+        let mut joypad = Joypad::new();
+        joypad.write(0b0001_0000);
 
         assert_eq!(joypad.as_byte(), 0b1101_1111, "No Buttons");
 
-        joypad.start_pressed = true;
+        joypad.state.press(Button::Start);
         assert_eq!(joypad.as_byte(), 0b1101_0111, "Start button");
 
-        joypad.select_pressed = true;
+        joypad.state.press(Button::Select);
         assert_eq!(joypad.as_byte(), 0b1101_0011, "Select button");
 
-        joypad.b_pressed = true;
+        joypad.state.press(Button::B);
         assert_eq!(joypad.as_byte(), 0b1101_0001, "B button");
 
-        joypad.a_pressed = true;
+        joypad.state.press(Button::A);
         assert_eq!(joypad.as_byte(), 0b1101_0000, "A button");
     }
 
     #[test]
     fn test_as_byte_direction() {
-        let mut joypad = Joypad::default();
-        joypad.selected_buttons = ButtonType::Direction;
+        let mut joypad = Joypad::new();
+        joypad.write(0b0010_0000);
 
         assert_eq!(joypad.as_byte(), 0b1110_1111, "No Buttons");
 
-        joypad.down_pressed = true;
+        joypad.state.press(Button::Down);
         assert_eq!(joypad.as_byte(), 0b1110_0111, "Down button");
 
-        joypad.up_pressed = true;
+        joypad.state.press(Button::Up);
         assert_eq!(joypad.as_byte(), 0b1110_0011, "Up button");
 
-        joypad.left_pressed = true;
+        joypad.state.press(Button::Left);
         assert_eq!(joypad.as_byte(), 0b1110_0001, "Left button");
 
-        joypad.right_pressed = true;
+        joypad.state.press(Button::Right);
         assert_eq!(joypad.as_byte(), 0b1110_0000, "Right button");
     }
 
     #[test]
     fn test_as_byte_none() {
-        let joypad = Joypad::default();
+        let joypad = Joypad::new();
 
         assert_eq!(joypad.as_byte(), 0xFF);
     }
+
+    #[test]
+    fn test_joypad_state_press_and_release() {
+        let mut state = JoypadState::new();
+
+        state.press(Button::A);
+        state.press(Button::Down);
+        assert_eq!(state.state(), 0b0001_1000);
+        assert!(state.is_pressed(Button::A));
+
+        state.release(Button::A);
+        assert_eq!(state.state(), 0b0000_1000);
+        assert!(!state.is_pressed(Button::A));
+
+        state.set_state(0xFF);
+        assert!(Button::ALL.iter().all(|button| state.is_pressed(*button)));
+    }
+
+    #[test]
+    fn test_as_byte_both_groups_selected() {
+        let mut joypad = Joypad::new();
+        joypad.write(0b0000_0000);
+
+        joypad.state.press(Button::Right);
+        joypad.state.press(Button::B);
+        assert_eq!(joypad.as_byte(), 0b1100_1100);
+    }
+
+    #[test]
+    fn test_press_requests_interrupt_when_selected() {
+        let mut joypad = Joypad::new();
+        let mut state = JoypadState::new();
+        state.press(Button::A);
+
+        assert!(!joypad.set_state(state), "No group selected");
+
+        joypad.set_state(JoypadState::new());
+        joypad.write(0b0001_0000);
+        assert!(joypad.set_state(state), "Action buttons selected");
+        assert!(!joypad.set_state(state), "Button already held");
+    }
+
+    #[test]
+    fn test_select_requests_interrupt_when_button_held() {
+        let mut joypad = Joypad::new();
+        let mut state = JoypadState::new();
+        state.press(Button::Down);
+        joypad.set_state(state);
+
+        assert!(!joypad.write(0b0001_0000), "Action buttons selected");
+        assert!(joypad.write(0b0010_0000), "Direction buttons selected");
+    }
 }
@@ -1,9 +1,22 @@
 pub mod alu_result;
+pub mod apu;
+pub mod assembler;
+pub mod bg_attribute;
+pub mod cartridge_header;
+pub mod code_data_log;
 pub mod cpu;
+pub mod disassembler;
+pub mod gameboy;
 pub mod instructions;
 pub mod joypad;
 pub mod memory;
+pub mod model;
+pub mod pixel_fifo;
 pub mod ppu;
+pub mod profiler;
 pub mod sprite_attribute;
 pub mod tile_info;
+pub mod trace;
 pub mod util;
+
+pub use gameboy::GameBoy;
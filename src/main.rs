@@ -1,62 +1,221 @@
+mod frontend;
+
+use frontend::{
+    bindings::{Bindings, Hotkey},
+    frame_pacer::FramePacer,
+};
 use gameboy::{
-    cpu::Cpu,
-    instructions::Instruction,
-    memory::Memory,
-    ppu::{ColorRects, Ppu},
-    tile_info::TileType,
-    util::get_as_bits,
+    cartridge_header::CartridgeHeader,
+    code_data_log::CodeDataLog,
+    disassembler::Disassembler,
+    model::Model,
+    ppu::{Renderer, SCREEN_HEIGHT, SCREEN_WIDTH},
+    profiler::Profiler,
+    trace::{format_trace_line, TraceWriter},
+    GameBoy,
 };
 use sdl2::{
+    controller::GameController,
     event::{Event, WindowEvent},
     keyboard::Keycode,
-    pixels::Color,
+    pixels::{Color, PixelFormatEnum},
     rect::Rect,
+    render::Canvas,
+    surface::Surface,
+    video::Window,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
 };
-use std::{collections::HashSet, env, fs};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() == 1 {
-        println!("usage: gameboy <file>");
+        print_usage();
+        return;
+    }
+
+    if args[1] == "disasm" {
+        if args.len() < 3 {
+            print_usage();
+            return;
+        }
+
+        run_disassembler(&args[2], &args[3..]);
+        return;
+    }
+
+    if args[1] == "info" {
+        if args.len() < 3 {
+            print_usage();
+            return;
+        }
+
+        run_info(&args[2]);
+        return;
+    }
+
+    if args[1] == "trace" {
+        if args.len() < 4 {
+            print_usage();
+            return;
+        }
+
+        let max_instructions = match args.get(4) {
+            Some(count) => count.parse().expect("Invalid instruction count"),
+            None => u64::MAX,
+        };
+
+        run_trace(&args[2], &args[3], max_instructions);
         return;
     }
 
     let filename = &args[1];
 
-    let bios_contents = fs::read("boot.gb").expect("Error reading Boot ROM");
+    let mut profile_filename = None;
+    let mut folded_profile_filename = None;
+    let mut cdl_filename = None;
+    let mut config_filename = "gameboy.cfg".to_owned();
+    let mut boot_rom_filename = None;
+    // Chosen from the cartridge header unless given
+    let mut model = None;
+    let mut renderer = Renderer::Scanline;
+    let mut access_restrictions = true;
+    // Speed multipliers used while the fast-forward and slow-motion hotkeys are held
+    let mut fast_forward_speed = 4.0;
+    let mut slow_motion_speed = 0.5;
+
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--profile" => profile_filename = options.next().cloned(),
+            "--profile-folded" => folded_profile_filename = options.next().cloned(),
+            "--cdl" => cdl_filename = options.next().cloned(),
+            "--boot-rom" => boot_rom_filename = options.next().cloned(),
+            "--model" => match options.next().and_then(|name| Model::from_name(name)) {
+                Some(selected) => model = Some(selected),
+                None => {
+                    print_usage();
+                    return;
+                }
+            },
+            "--renderer" => match options.next().map(|name| name.as_str()) {
+                Some("scanline") => renderer = Renderer::Scanline,
+                Some("fifo") => renderer = Renderer::PixelFifo,
+                _ => {
+                    print_usage();
+                    return;
+                }
+            },
+            "--no-access-restrictions" => access_restrictions = false,
+            "--config" => match options.next() {
+                Some(filename) => config_filename = filename.clone(),
+                None => {
+                    print_usage();
+                    return;
+                }
+            },
+            "--fast-forward" | "--slow-motion" => {
+                let Some(speed) = options.next().and_then(|speed| speed.parse::<f64>().ok()) else {
+                    print_usage();
+                    return;
+                };
+
+                if option == "--fast-forward" {
+                    fast_forward_speed = speed;
+                } else {
+                    slow_motion_speed = speed;
+                }
+            }
+            _ => {
+                print_usage();
+                return;
+            }
+        }
+    }
+
+    // Bindings for a single ROM go in a config file next to it, eg. `tetris.cfg` for `tetris.gb`
+    let mut bindings = Bindings::default();
+    for config in [
+        Path::new(&config_filename).to_path_buf(),
+        Path::new(filename).with_extension("cfg"),
+    ] {
+        if let Err(error) = bindings.load(&config) {
+            eprintln!("Error reading {}: {}", config.display(), error);
+            return;
+        }
+    }
+
+    // Without a boot ROM the cartridge starts in the state the boot ROM would have left behind
+    let boot_rom = match boot_rom_filename {
+        Some(boot_rom_filename) => {
+            let Some(boot_rom) = read_file(&boot_rom_filename) else {
+                return;
+            };
+            Some(boot_rom)
+        }
+        None => None,
+    };
+
+    let Some(contents) = read_file(filename) else {
+        return;
+    };
+    // Without a model the cartridge header picks one
+    let loaded = match model {
+        Some(model) => GameBoy::with_model(&contents, boot_rom.as_deref(), model),
+        None => GameBoy::new(&contents, boot_rom.as_deref()),
+    };
 
-    let contents = fs::read(filename).expect("Error reading the given filename");
+    let mut gameboy = match loaded {
+        Ok(gameboy) => gameboy,
+        Err(error) => {
+            eprintln!("Error loading {}: {}", filename, error);
+            return;
+        }
+    };
 
-    let mut cpu = Cpu::new();
+    gameboy.set_renderer(renderer);
+    gameboy.set_access_restrictions(access_restrictions);
 
-    let mut memory = Memory::new();
+    if profile_filename.is_some() || folded_profile_filename.is_some() {
+        gameboy.profiler = Some(Profiler::new());
+    }
 
-    memory.load_boot_rom(&bios_contents);
-    memory.load_cartridge(&contents);
+    if let Some(filename) = &cdl_filename {
+        // Keep adding to an existing log so multiple play sessions build up coverage
+        let saved_log = fs::read(filename).ok();
+        gameboy.memory.enable_code_data_log(saved_log.as_deref());
+    }
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
+    let controller_subsystem = sdl_context.game_controller().unwrap();
 
     let window = video_subsystem
-        .window("GameBoy Emulator", 160 * 2, 144 * 2)
+        .window(
+            "GameBoy Emulator",
+            SCREEN_WIDTH as u32 * 2,
+            SCREEN_HEIGHT as u32 * 2,
+        )
         .position_centered()
         .build()
         .unwrap();
 
     let (width, height) = window.size();
-    let pixel_width = width / 160;
-    let pixel_height = height / 144;
-
-    let ppu = Ppu {
-        pixel_width,
-        pixel_height,
-    };
+    let pixel_width = width / SCREEN_WIDTH as u32;
+    let pixel_height = height / SCREEN_HEIGHT as u32;
 
-    let color0 = Color::RGB(0xE0, 0xF8, 0xD0);
-    let color1 = Color::RGB(0x88, 0xC0, 0x70);
-    let color2 = Color::RGB(0x34, 0x68, 0x56);
-    let color3 = Color::RGB(0x08, 0x18, 0x20);
+    let colors = [
+        Color::RGB(0xE0, 0xF8, 0xD0),
+        Color::RGB(0x88, 0xC0, 0x70),
+        Color::RGB(0x34, 0x68, 0x56),
+        Color::RGB(0x08, 0x18, 0x20),
+    ];
 
     let mut canvas = window.into_canvas().build().unwrap();
     canvas.set_draw_color(Color::RGB(0, 255, 255));
@@ -66,170 +225,381 @@ fn main() {
 
     let mut event_pump = sdl_context.event_pump().unwrap();
 
+    let bindings = bindings.resolve();
+    let mut controllers: Vec<GameController> = Vec::new();
+    let mut frame_pacer = FramePacer::new();
+
     'running: loop {
         for event in event_pump.poll_iter() {
             match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => break 'running,
+                Event::Quit { .. } => break 'running,
                 Event::KeyDown {
-                    keycode: Some(Keycode::F12),
+                    keycode: Some(keycode),
+                    repeat: false,
                     ..
-                } => {
-                    eprintln!("Toggling debug log");
-                    cpu.debug = !cpu.debug;
-                    memory.debug = !memory.debug;
+                } => match bindings.hotkey(keycode) {
+                    Some(Hotkey::Quit) => break 'running,
+                    Some(Hotkey::Pause) => gameboy.set_paused(!gameboy.is_paused()),
+                    Some(Hotkey::FrameAdvance) => {
+                        gameboy.set_paused(true);
+                        if let Err(error) = gameboy.step_frame() {
+                            eprintln!("{}", error);
+                        }
+                    }
+                    Some(Hotkey::StepInstruction) => {
+                        gameboy.set_paused(true);
+                        eprintln!("{}", format_trace_line(&gameboy.cpu, &gameboy.memory));
+                        if let Err(error) = gameboy.step_instruction() {
+                            eprintln!("{}", error);
+                        }
+                    }
+                    Some(Hotkey::SoftReset) => gameboy.soft_reset(),
+                    Some(Hotkey::HardReset) => gameboy.reset(),
+                    Some(Hotkey::Screenshot) => {
+                        match save_screenshot(filename, &frame_colors(&gameboy, &colors)) {
+                            Ok(path) => eprintln!("Saved screenshot to {}", path),
+                            Err(error) => eprintln!("Error saving screenshot: {}", error),
+                        }
+                    }
+                    Some(Hotkey::Debug) => {
+                        eprintln!("Toggling debug log");
+                        gameboy.trace = match gameboy.trace.take() {
+                            Some(_) => None,
+                            None => Some(TraceWriter::new(Box::new(io::stderr()))),
+                        };
+                    }
+                    Some(Hotkey::FastForward) | Some(Hotkey::SlowMotion) | None => {}
+                },
+                Event::ControllerDeviceAdded { which, .. } => {
+                    match controller_subsystem.open(which) {
+                        Ok(controller) => controllers.push(controller),
+                        Err(error) => eprintln!("Error opening controller: {}", error),
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    controllers.retain(|controller| controller.instance_id() != which);
                 }
                 _ => {}
             }
         }
 
         let pressed_keys = pressed_keycode_set(&event_pump);
-        memory.set_joypad_inputs(pressed_keys);
+        gameboy.set_buttons(bindings.joypad_state(&pressed_keys, &controllers));
+
+        frame_pacer.set_speed(if bindings.is_held(Hotkey::FastForward, &pressed_keys) {
+            fast_forward_speed
+        } else if bindings.is_held(Hotkey::SlowMotion, &pressed_keys) {
+            slow_motion_speed
+        } else {
+            1.0
+        });
+
+        for _ in 0..frame_pacer.frames_to_run() {
+            // The invalid opcode isn't executed, so pause on it where it can be inspected
+            if let Err(error) = gameboy.run_frame() {
+                eprintln!("{}, pausing", error);
+                gameboy.set_paused(true);
+                break;
+            }
+        }
 
-        for _ in 0..60 {
-            let instruction = cpu.parse(&mut memory);
+        // Sound isn't played yet, drop the samples so they don't build up
+        gameboy.audio_samples();
 
-            if !memory.using_boot_rom() && cpu.debug {
-                println!("addr={:0>4x}, a={:0>2x}, f={:0>2x}, b={:0>2x}, c={:0>2x}, d={:0>2x}, e={:0>2x}, h={:0>2x}, l={:0>2x}, sp={:0>4x}, code={:0>2x} {:?}",
-                cpu.program_counter, cpu.a, cpu.flags_to_byte(), cpu.b, cpu.c, cpu.d,cpu.e, cpu.h,cpu.l, cpu.stack_pointer, memory.read(cpu.program_counter), instruction);
-            }
+        draw_framebuffer(
+            &mut canvas,
+            &frame_colors(&gameboy, &colors),
+            pixel_width,
+            pixel_height,
+        );
 
-            if instruction == Instruction::Invalid {
-                panic!("Invalid Instruction");
-            }
+        canvas.present();
+        frame_pacer.wait();
+    }
 
-            cpu.execute(instruction, &mut memory);
-        }
-
-        if memory.frame_happened {
-            canvas.clear();
-            let tilemap = memory.read_bg_tile_map();
-            let window_tilemap = memory.read_window_tile_map();
-
-            let mut color_rects = ColorRects::default();
-
-            let palette_bits = get_as_bits(memory.io_registers[0x47]);
-
-            let color_values = [
-                (palette_bits[6] << 1) + palette_bits[7],
-                (palette_bits[4] << 1) + palette_bits[5],
-                (palette_bits[2] << 1) + palette_bits[3],
-                (palette_bits[0] << 1) + palette_bits[1],
-            ];
-
-            // Render an extra tile's worth of pixels to enable partially rendering tiles from offscreen
-            for y in 0..(144 + 8) {
-                // Check LCDC bit to see if window should be displayed or not
-                if memory.io_registers[0x40] & 0b0010_0000 == 0b0010_0000 {
-                    // Only draw if the window is actually visible
-                    if memory.wx <= 166 && memory.wy <= 143 && y >= memory.wy as usize {
-                        ppu.render_window_scanline(
-                            &memory,
-                            y,
-                            &window_tilemap,
-                            &color_values,
-                            &mut color_rects,
-                        );
-                    } else {
-                        ppu.render_scanline(&memory, y, &tilemap, &color_values, &mut color_rects);
-                    }
-                } else {
-                    ppu.render_scanline(&memory, y, &tilemap, &color_values, &mut color_rects);
-                }
-            }
+    if let (Some(filename), Some(code_data_log)) = (cdl_filename, &gameboy.memory.code_data_log) {
+        code_data_log
+            .save(filename)
+            .expect("Error writing the code/data log");
+    }
 
-            let mut palette: u8;
+    if let Some(profiler) = gameboy.profiler {
+        if let Some(filename) = profile_filename {
+            let mut file =
+                BufWriter::new(File::create(filename).expect("Error creating the profile"));
+            profiler
+                .write_report(&mut file)
+                .expect("Error writing the profile");
+        }
 
-            for sprite in memory.read_oam() {
-                if sprite.y == 0 || (memory.io_registers[0x40] & 0b0000_0100 == 0 && sprite.y <= 8)
-                {
-                    // LCDC bit 2 == false, use 8x8 sprite mode
-                    continue;
-                }
+        if let Some(filename) = folded_profile_filename {
+            let mut file =
+                BufWriter::new(File::create(filename).expect("Error creating the profile"));
+            profiler
+                .write_folded(&mut file)
+                .expect("Error writing the profile");
+        }
+    }
+}
 
-                //TODO: handle x == 0 (still effects scanline limit)
-                //TODO: handle scanline limits and selection priority
-                //TODO: handle bg+window over obj
-                //TODO: handle 8x16 sprites
+/// Returns the color of each pixel of the last frame, stored row by row
+///
+/// The CGB outputs its own colors, the shades of the other models are looked up in `colors`
+fn frame_colors(gameboy: &GameBoy, colors: &[Color; 4]) -> Vec<Color> {
+    match gameboy.rgb_framebuffer() {
+        Some(framebuffer) => framebuffer
+            .iter()
+            .map(|color| rgb555_color(*color))
+            .collect(),
+        None => gameboy
+            .framebuffer()
+            .iter()
+            .map(|shade| colors[*shade as usize])
+            .collect(),
+    }
+}
 
-                let tile = memory.vram_read_tile(TileType::Obj, sprite.index);
+/// Converts a 15-bit RGB color to 24-bit, repeating the top bits of each channel in the bottom
+fn rgb555_color(color: u16) -> Color {
+    let channel = |shift: u16| {
+        let value = ((color >> shift) & 0x1F) as u8;
+        (value << 3) | (value >> 2)
+    };
 
-                let x_pos = sprite.x as i32 - 8;
-                let y_pos = sprite.y as i32 - 16;
+    Color::RGB(channel(0), channel(5), channel(10))
+}
 
-                let mut colors = tile.get_color_ids_from_tile();
+/// Draw each pixel of the frame as a rectangle in its color
+fn draw_framebuffer(
+    canvas: &mut Canvas<Window>,
+    frame: &[Color],
+    pixel_width: u32,
+    pixel_height: u32,
+) {
+    let mut color_rects: HashMap<(u8, u8, u8), Vec<Rect>> = HashMap::new();
+
+    for (index, color) in frame.iter().enumerate() {
+        let x = (index % SCREEN_WIDTH) as i32;
+        let y = (index / SCREEN_WIDTH) as i32;
+
+        color_rects
+            .entry((color.r, color.g, color.b))
+            .or_default()
+            .push(Rect::new(
+                x * pixel_width as i32,
+                y * pixel_height as i32,
+                pixel_width,
+                pixel_height,
+            ));
+    }
 
-                if sprite.x_flip {
-                    for col in colors.iter_mut() {
-                        col.reverse();
-                    }
-                }
+    for ((r, g, b), rects) in color_rects {
+        canvas.set_draw_color(Color::RGB(r, g, b));
+        canvas.fill_rects(&rects).unwrap();
+    }
+}
 
-                if sprite.y_flip {
-                    colors.reverse();
-                }
+fn pressed_keycode_set(event_pump: &sdl2::EventPump) -> HashSet<Keycode> {
+    event_pump
+        .keyboard_state()
+        .pressed_scancodes()
+        .filter_map(Keycode::from_scancode)
+        .collect()
+}
 
-                let palette_reg = if sprite.palette == 0 { 0x48 } else { 0x49 };
-                let palette_bits = get_as_bits(memory.io_registers[palette_reg]);
-
-                // Lower 2 bits ignored since they are transparent
-                // Keep a value so indexing works as expected
-                let color_values = [
-                    0,
-                    (palette_bits[4] << 1) + palette_bits[5],
-                    (palette_bits[2] << 1) + palette_bits[3],
-                    (palette_bits[0] << 1) + palette_bits[1],
-                ];
-
-                for row in 0..colors.len() {
-                    for col in 0..colors[0].len() {
-                        let rect = Rect::new(
-                            (x_pos + col as i32) * pixel_width as i32,
-                            (y_pos + row as i32) * pixel_height as i32,
-                            pixel_width,
-                            pixel_height,
-                        );
-
-                        palette = color_values[colors[row][col] as usize];
-
-                        if palette == 1 {
-                            color_rects.color1_rects.push(rect);
-                        } else if palette == 2 {
-                            color_rects.color2_rects.push(rect);
-                        } else if palette == 3 {
-                            color_rects.color3_rects.push(rect);
-                        }
-                    }
-                }
-            }
+/// Save the frame as a BMP named after the ROM, returning the path it was saved to
+fn save_screenshot(rom_filename: &str, frame: &[Color]) -> Result<String, String> {
+    let stem = Path::new(rom_filename)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "screenshot".to_owned());
+
+    let path = (1..)
+        .map(|number| format!("{}-{}.bmp", stem, number))
+        .find(|path| !Path::new(path).exists())
+        .unwrap();
+
+    let mut pixels: Vec<u8> = frame
+        .iter()
+        .flat_map(|color| [color.r, color.g, color.b])
+        .collect();
+
+    let surface = Surface::from_data(
+        &mut pixels,
+        SCREEN_WIDTH as u32,
+        SCREEN_HEIGHT as u32,
+        SCREEN_WIDTH as u32 * 3,
+        PixelFormatEnum::RGB24,
+    )?;
+    surface.save_bmp(&path)?;
+
+    Ok(path)
+}
+
+/// Read a file given on the command line, printing the error if it can't be read
+fn read_file(filename: &str) -> Option<Vec<u8>> {
+    match fs::read(filename) {
+        Ok(contents) => Some(contents),
+        Err(error) => {
+            eprintln!("Error reading {}: {}", filename, error);
+            None
+        }
+    }
+}
+
+fn print_usage() {
+    println!("usage: gameboy <file> [--config <bindings file>] [--cdl <cdl file>]");
+    println!("                      [--boot-rom <file>] [--model <dmg0|dmg|mgb|sgb|cgb>]");
+    println!("                      [--renderer <scanline|fifo>] [--no-access-restrictions]");
+    println!("                      [--fast-forward <speed>] [--slow-motion <speed>]");
+    println!(
+        "                      [--profile <report file>] [--profile-folded <folded stack file>]"
+    );
+    println!("       gameboy disasm <file> [--cdl <cdl file>] [--output <asm file>]");
+    println!("       gameboy info <file>");
+    println!("       gameboy trace <file> <log file> [instruction count]");
+}
+
+/// Print the cartridge header of a ROM
+fn run_info(filename: &str) {
+    let Some(contents) = read_file(filename) else {
+        return;
+    };
 
-            canvas.set_draw_color(color0);
-            canvas.fill_rects(&color_rects.color0_rects).unwrap();
+    let Some(header) = CartridgeHeader::parse(&contents) else {
+        eprintln!("{} is too small to have a cartridge header", filename);
+        return;
+    };
+
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+    let size = |size: Option<usize>| match size {
+        Some(size) => format!("{} KiB", size / 1024),
+        None => "unknown".to_owned(),
+    };
+    let checksum = |valid: bool| if valid { "ok" } else { "mismatch" };
+
+    println!("Title:             {}", header.title);
+    if let Some(code) = &header.manufacturer_code {
+        println!("Manufacturer code: {}", code);
+    }
+    println!("CGB support:       {:?}", header.cgb_support);
+    println!("SGB support:       {}", yes_no(header.sgb_support));
+    println!("Licensee:          {}", header.licensee);
+    println!(
+        "Cartridge type:    0x{:0>2X} ({})",
+        header.cartridge_type, header.mapper
+    );
+    println!(
+        "Hardware:          RAM: {}, battery: {}, timer: {}, rumble: {}",
+        yes_no(header.has_ram),
+        yes_no(header.has_battery),
+        yes_no(header.has_timer),
+        yes_no(header.has_rumble)
+    );
+    println!(
+        "ROM size:          {} (file is {} KiB)",
+        size(header.rom_size),
+        contents.len() / 1024
+    );
+    println!("RAM size:          {}", size(header.ram_size));
+    println!("Mask ROM version:  {}", header.mask_rom_version);
+    println!(
+        "Header checksum:   0x{:0>2X} ({})",
+        header.header_checksum,
+        checksum(header.header_checksum_valid())
+    );
+    println!(
+        "Global checksum:   0x{:0>4X} ({})",
+        header.global_checksum,
+        checksum(header.global_checksum_valid())
+    );
+}
+
+/// Run a cartridge without a window or boot ROM, logging the CPU state before every instruction
+///
+/// Stops after `max_instructions` or once the CPU is stuck jumping to the same address
+fn run_trace(filename: &str, log_filename: &str, max_instructions: u64) {
+    let Some(contents) = read_file(filename) else {
+        return;
+    };
 
-            canvas.set_draw_color(color1);
-            canvas.fill_rects(&color_rects.color1_rects).unwrap();
+    // Gameboy Doctor logs start from the DMG post boot state
+    let mut gameboy = match GameBoy::with_model(&contents, None, Model::Dmg) {
+        Ok(gameboy) => gameboy,
+        Err(error) => {
+            eprintln!("Error loading {}: {}", filename, error);
+            return;
+        }
+    };
+    gameboy.memory.fixed_ly = Some(0x90);
 
-            canvas.set_draw_color(color2);
-            canvas.fill_rects(&color_rects.color2_rects).unwrap();
+    let mut trace = TraceWriter::create(log_filename).expect("Error creating the log file");
 
-            canvas.set_draw_color(color3);
-            canvas.fill_rects(&color_rects.color3_rects).unwrap();
+    for _ in 0..max_instructions {
+        let program_counter = gameboy.cpu.program_counter;
 
+        // Halting re-executes HALT until an interrupt arrives, only log the first time
+        if !gameboy.cpu.is_halted() {
+            trace
+                .log(&gameboy.cpu, &gameboy.memory)
+                .expect("Error writing to the log file");
+        }
 
-            memory.frame_happened = false;
+        if let Err(error) = gameboy.step_instruction() {
+            eprintln!("{}", error);
+            break;
         }
 
-        canvas.present();
+        if gameboy.cpu.program_counter == program_counter && !gameboy.cpu.is_halted() {
+            break;
+        }
     }
+
+    trace.flush().expect("Error writing to the log file");
 }
 
-fn pressed_keycode_set(event_pump: &sdl2::EventPump) -> HashSet<Keycode> {
-    event_pump
-        .keyboard_state()
-        .pressed_scancodes()
-        .filter_map(Keycode::from_scancode)
-        .collect()
+/// Write RGBDS source for a cartridge to the output file, or stdout if none is given
+fn run_disassembler(filename: &str, options: &[String]) {
+    let Some(contents) = read_file(filename) else {
+        return;
+    };
+
+    let mut cdl_filename = None;
+    let mut output_filename = None;
+
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--cdl" => cdl_filename = options.next(),
+            "--output" => output_filename = options.next(),
+            _ => {
+                print_usage();
+                return;
+            }
+        }
+    }
+
+    let mut disassembler = Disassembler::new(&contents);
+    disassembler.add_default_entry_points();
+
+    if let Some(cdl_filename) = cdl_filename {
+        let Some(saved_log) = read_file(cdl_filename) else {
+            return;
+        };
+        disassembler.add_code_data_log(&CodeDataLog::from_bytes(&saved_log, contents.len()));
+    }
+
+    disassembler.run();
+
+    let mut writer: Box<dyn Write> = match output_filename {
+        Some(filename) => Box::new(BufWriter::new(
+            File::create(filename).expect("Error creating the output file"),
+        )),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    disassembler
+        .write_source(&mut writer)
+        .expect("Error writing the disassembly");
+    writer.flush().expect("Error writing the disassembly");
 }
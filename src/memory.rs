@@ -1,11 +1,15 @@
 use crate::{
+    cartridge_header::{CartridgeHeader, Mapper},
+    code_data_log::CodeDataLog,
     cpu::CpuBus,
-    joypad::{ButtonType, Joypad},
+    joypad::{Joypad, JoypadState},
+    model::Model,
+    pixel_fifo::PixelFifo,
+    ppu::Renderer,
     sprite_attribute::SpriteAttribute,
     tile_info::{TileInfo, TileType},
 };
-use sdl2::keyboard::Keycode;
-use std::collections::HashSet;
+use std::{error, fmt};
 
 #[derive(Debug, PartialEq, Eq)]
 enum CartridgeType {
@@ -13,9 +17,76 @@ enum CartridgeType {
     Mbc1,
 }
 
+/// A cartridge or boot ROM that can't be loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// The cartridge is smaller than the two ROM banks that are always mapped
+    TooSmall { size: usize },
+    /// The cartridge uses a memory bank controller that isn't emulated
+    UnsupportedMapper(Mapper),
+    /// The ROM size code at 0x148 isn't a known size
+    UnknownRomSize(u8),
+    /// The RAM size code at 0x149 isn't a known size
+    UnknownRamSize(u8),
+    /// The file is smaller than the ROM size in the header
+    SizeMismatch { expected: usize, found: usize },
+    /// The boot ROM isn't the size of the boot ROM of the model
+    BadBootRom { size: usize, expected: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::TooSmall { size } => {
+                write!(f, "cartridge is {} bytes, it needs at least 32 KiB", size)
+            }
+            LoadError::UnsupportedMapper(mapper) => {
+                write!(f, "cartridge uses an unsupported mapper: {}", mapper)
+            }
+            LoadError::UnknownRomSize(code) => write!(f, "unknown ROM size code 0x{:0>2X}", code),
+            LoadError::UnknownRamSize(code) => write!(f, "unknown RAM size code 0x{:0>2X}", code),
+            LoadError::SizeMismatch { expected, found } => write!(
+                f,
+                "cartridge header declares {} bytes of ROM but the file is {} bytes",
+                expected, found
+            ),
+            LoadError::BadBootRom { size, expected } => {
+                write!(f, "boot ROM is {} bytes, it should be {}", size, expected)
+            }
+        }
+    }
+}
+
+impl error::Error for LoadError {}
+
+/// Length of OAM DMA in M-cycles, one for each byte of OAM
+const DMA_LENGTH: u8 = 0xA0;
+
+#[derive(Debug, Clone, Copy)]
+struct OamDma {
+    source: u16,
+    /// Next byte to copy, or M-cycles left before the transfer starts if it is pending
+    index: u8,
+    /// Byte read from the source during the current M-cycle
+    value: u8,
+}
+
+/// Ways a CPU access to `0xFE00..=0xFEFF` during OAM scan corrupts OAM on DMG models
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OamCorruption {
+    Read,
+    Write,
+    /// A read while the register holding the address is incremented or decremented
+    ReadIncrease,
+}
+
 #[derive(Debug)]
 pub struct Memory {
-    boot_rom: [u8; 0x100],
+    model: Model,
+    /// Running with the CGB registers and banks enabled, false for DMG cartridges on a CGB
+    cgb_mode: bool,
+    /// 256 bytes mapped from `0x0000`, CGB boot ROMs are 2304 bytes and also map `0x0200` to `0x08FF`
+    boot_rom: Vec<u8>,
     use_boot_rom: bool,
     /// 16 KiB ROM Bank 00
     /// * Addressed from `0x0000` to `0x3FFF`
@@ -23,20 +94,28 @@ pub struct Memory {
     ////16 KiB ROM Bank 01 ~ NN
     /// * Addressed from `0x4000` to `0x7FFF`
     switchable_rom: Vec<[u8; 0x4000]>,
-    /// 8 KiB Video RAM (VRAM)
+    /// 16 KiB Video RAM (VRAM), two 8 KiB banks
     /// * Addressed from `0x8000` to `0x9FFF`
-    pub vram: [u8; 0x2000],
+    /// * Bank 1 starts at `0x2000` and is only used in CGB mode
+    pub vram: [u8; 0x4000],
+    /// VRAM bank mapped at `0x8000`, selected by VBK
+    vram_bank: u8,
     /// 8 KiB External RAM
     /// * Addressed from `0xA000` to `0xBFFF`
     pub switchable_ram: Vec<[u8; 0x2000]>,
-    /// 8 KiB Work RAM (WRAM)
+    /// 32 KiB Work RAM (WRAM), eight 4 KiB banks
     /// * Addressed from `0xC000` to `0xDFFF`
-    // In Color GameBoy (CGB) mode, the second half (0xD000 - 0xDFFF) of this block is a switchable bank
-    pub wram: [u8; 0x2000],
+    /// * Bank 0 is at `0xC000`, `0xD000` maps bank 1, or banks 1-7 in CGB mode
+    pub wram: [u8; 0x8000],
+    /// WRAM bank mapped at `0xD000`, selected by SVBK
+    wram_bank: u8,
     /// Sprite Attribute Table
     /// * also Object Attribute Memory (OAM)
     /// * Addressed from `0xFE00` to `0xFE9F`
     pub sprite_attribute_table: [u8; 0xA0],
+    /// CGB palette RAM, 8 BG palettes followed by 8 OBJ palettes of four 15-bit colors each
+    /// * Accessed through BCPS/BCPD (`0xFF68`/`0xFF69`) and OCPS/OCPD (`0xFF6A`/`0xFF6B`)
+    pub palette_ram: [u8; 0x80],
     /// I/O Registers
     /// * Addressed from `0xFF00` to `0xFF7F`
     pub io_registers: [u8; 0x80],
@@ -48,13 +127,48 @@ pub struct Memory {
     pub enabled_interupts: u8,
     pub interrupts_enabled: bool,
     cartridge_type: CartridgeType,
+    /// ROM bank mapped at `0x4000`
     rom_bank: u16,
+    /// ROM bank mapped at `0x0000`, only switched by MBC1 banking mode 1
+    zero_rom_bank: u16,
     max_rom_bank: u16,
+    /// Lower 5 bits of the MBC1 ROM bank number, written to `0x2000`-`0x3FFF`
+    rom_bank_low_bits: u8,
+    /// 2 bit MBC1 register written to `0x4000`-`0x5FFF`, the upper bits of the ROM bank number or
+    /// the RAM bank number
+    bank_upper_bits: u8,
+    /// MBC1 banking mode 1 applies `bank_upper_bits` to the `0x0000` ROM area and to RAM
+    banking_mode: bool,
     ram_enable: bool,
     ram_bank: u8,
     max_ram_bank: u8,
     time: u16,
+    /// T-cycles elapsed since power on
+    cycles: u64,
+    /// T-cycles at normal speed elapsed since power on, the PPU and APU run at this rate
+    dots: u64,
+    /// The CPU, timer and OAM DMA run at twice the normal speed
+    double_speed: bool,
+    /// KEY1 bit 0, the next STOP switches speed
+    speed_switch_armed: bool,
     pub frame_happened: bool,
+    /// The frame that just finished wasn't output, because the LCD was off for some or all of it
+    frame_blank: bool,
+    /// The LCD was turned on during the current frame, which is never shown
+    lcd_enabled_this_frame: bool,
+    /// Position in the frame in T-cycles while the LCD is off, frames are still counted so the
+    /// system keeps a steady frame rate
+    lcd_off_time: u32,
+    /// Lines that are ready to be drawn by the PPU
+    lines_to_render: Vec<u8>,
+    /// OAM DMA that is copying a byte every M-cycle
+    dma: Option<OamDma>,
+    /// OAM DMA waiting for its setup M-cycle, which replaces `dma` once it starts
+    pending_dma: Option<OamDma>,
+    /// Block CPU access to VRAM and OAM while the PPU is using them
+    access_restrictions: bool,
+    /// Draws mode 3 dot by dot when the pixel FIFO renderer is selected
+    pixel_fifo: Option<Box<PixelFifo>>,
     joypad: Joypad,
     divider_register: u32,
     timer_counter: u32,
@@ -67,32 +181,62 @@ pub struct Memory {
     pub wy: u8,
     pub wx: u8,
     lcd_stat: u8,
-    pub debug: bool,
+    /// State of the STAT interrupt line, the interrupt is only requested when it goes high
+    stat_line: bool,
+    /// Value returned for reads of LY instead of the current scanline
+    /// * Gameboy Doctor logs assume LY always reads `0x90`
+    pub fixed_ly: Option<u8>,
+    pub code_data_log: Option<CodeDataLog>,
 }
 
 impl Memory {
     pub fn new() -> Memory {
+        Self::with_model(Model::Dmg)
+    }
+
+    pub fn with_model(model: Model) -> Memory {
         Memory {
-            boot_rom: [0; 0x100],
+            model,
+            cgb_mode: model == Model::Cgb,
+            boot_rom: vec![0; 0x100],
             use_boot_rom: true,
             rom: [0; 0x4000],
             switchable_rom: Vec::new(),
-            vram: [0; 0x2000],
+            vram: [0; 0x4000],
+            vram_bank: 0,
             switchable_ram: Vec::new(),
-            wram: [0; 0x2000],
+            wram: [0; 0x8000],
+            wram_bank: 1,
             sprite_attribute_table: [0; 0xA0],
+            palette_ram: [0; 0x80],
             io_registers: [0; 0x80],
             hram: [0; 0x7F],
             enabled_interupts: 0,
             interrupts_enabled: true,
             cartridge_type: CartridgeType::Rom,
             rom_bank: 1,
+            zero_rom_bank: 0,
             max_rom_bank: 2,
+            rom_bank_low_bits: 1,
+            bank_upper_bits: 0,
+            banking_mode: false,
             ram_enable: false,
             ram_bank: 0,
             max_ram_bank: 0,
             time: 0,
+            cycles: 0,
+            dots: 0,
+            double_speed: false,
+            speed_switch_armed: false,
             frame_happened: false,
+            frame_blank: false,
+            lcd_enabled_this_frame: false,
+            lcd_off_time: 0,
+            lines_to_render: Vec::new(),
+            dma: None,
+            pending_dma: None,
+            access_restrictions: true,
+            pixel_fifo: None,
             joypad: Joypad::default(),
             divider_register: 0,
             timer_counter: 0,
@@ -105,109 +249,320 @@ impl Memory {
             wy: 0,
             wx: 0,
             lcd_stat: 1,
-            debug: false,
+            stat_line: false,
+            fixed_ly: None,
+            code_data_log: None,
         }
     }
 
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Returns true if the CGB features are enabled, which needs a CGB and a cartridge that supports it
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
     pub fn using_boot_rom(&self) -> bool {
         self.use_boot_rom
     }
 
-    pub fn load_boot_rom(&mut self, contents: &[u8]) {
-        self.boot_rom[..].clone_from_slice(contents);
+    /// Returns true if `address` reads from the boot ROM instead of the cartridge
+    fn boot_rom_mapped(&self, address: u16) -> bool {
+        self.use_boot_rom
+            && (address < 0x100
+                || (0x200..0x900).contains(&address) && self.boot_rom.len() == 0x900)
     }
 
-    pub fn load_cartridge(&mut self, contents: &Vec<u8>) {
-        self.rom[..].clone_from_slice(&contents[..0x4000]);
+    /// Returns the number of T-cycles elapsed since power on
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
 
-        let cartridge_type = self.rom[0x147];
-        let rom_size = self.rom[0x148];
-        let ram_size = self.rom[0x149];
+    /// Returns the number of T-cycles at normal speed elapsed since power on, which is half of
+    /// `cycles` while running at double speed
+    pub fn dots(&self) -> u64 {
+        self.dots
+    }
 
-        println!(
-            "Cartridge Type: 0x{:0>2X}, ROM Size: 0x{:0>2X}, RAM Size: 0x{:0>2X}",
-            cartridge_type, rom_size, ram_size
-        );
+    /// Returns the ROM bank currently mapped at `address`
+    ///
+    /// Addresses outside of the switchable ROM area always report bank 0
+    pub fn bank_of(&self, address: u16) -> u16 {
+        if address <= 0x3FFF {
+            self.zero_rom_bank
+        } else if address <= 0x7FFF {
+            self.rom_bank
+        } else {
+            0
+        }
+    }
 
-        if cartridge_type == 0x00 {
-            self.cartridge_type = CartridgeType::Rom;
-        } else if cartridge_type == 0x01 {
-            self.cartridge_type = CartridgeType::Mbc1;
-        }
-
-        match rom_size {
-            0x0 => self.max_rom_bank = 2,
-            0x1 => self.max_rom_bank = 4,
-            0x2 => self.max_rom_bank = 8,
-            0x3 => self.max_rom_bank = 16,
-            0x4 => self.max_rom_bank = 32,
-            0x5 => self.max_rom_bank = 64,
-            0x6 => self.max_rom_bank = 128,
-            0x7 => self.max_rom_bank = 256,
-            _ => self.max_rom_bank = 512, //0x8
-        }
-
-        match ram_size {
-            0x0 => self.max_ram_bank = 0,
-            0x1 => self.max_ram_bank = 0, // Unused
-            0x2 => self.max_ram_bank = 1,
-            0x3 => self.max_ram_bank = 4,
-            0x4 => self.max_ram_bank = 16,
-            _ => self.max_ram_bank = 8, //0x5
-        }
-
-        let content_size = contents.len();
-        for i in 1..self.max_rom_bank as usize {
-            let mut data = [0; 0x4000];
-            let start_address = 0x4000 * i;
-            if content_size >= start_address + 0x4000 {
-                data.clone_from_slice(&contents[start_address..(start_address + 0x4000)]);
-                self.switchable_rom.push(data);
-            } else if content_size > start_address {
-                data[..content_size - start_address].clone_from_slice(&contents[start_address..]);
-                self.switchable_rom.push(data);
-            } else {
-                self.switchable_rom.push(data);
+    /// Set the IO registers and VRAM to the values the boot ROM of the model leaves behind and unmap the boot ROM
+    ///
+    /// Registers the boot ROM leaves in an unpredictable state are left at 0
+    pub fn set_post_boot_state(&mut self) {
+        let model = self.model;
+        self.use_boot_rom = false;
+        // The CGB boot ROM switches to DMG compatibility mode unless the cartridge supports the CGB
+        self.cgb_mode = model == Model::Cgb && self.rom[0x143] & 0x80 != 0;
+        self.divider_register = match model {
+            Model::Dmg0 => 0x18 * 256,
+            Model::Dmg | Model::Mgb => 0xAB * 256,
+            Model::Sgb | Model::Cgb => 0,
+        };
+
+        let registers = [
+            (0x02, 0x7E), // SC
+            (0x07, 0xF8), // TAC
+            (0x0F, 0xE1), // IF
+            (0x10, 0x80), // NR10
+            (0x11, 0xBF), // NR11
+            (0x12, 0xF3), // NR12
+            (0x13, 0xFF), // NR13
+            (0x14, 0xBF), // NR14
+            (0x16, 0x3F), // NR21
+            (0x18, 0xFF), // NR23
+            (0x19, 0xBF), // NR24
+            (0x1A, 0x7F), // NR30
+            (0x1B, 0xFF), // NR31
+            (0x1C, 0x9F), // NR32
+            (0x1D, 0xFF), // NR33
+            (0x1E, 0xBF), // NR34
+            (0x20, 0xFF), // NR41
+            (0x23, 0xBF), // NR44
+            (0x24, 0x77), // NR50
+            (0x25, 0xF3), // NR51
+            (0x26, 0xF1), // NR52
+            (0x40, 0x91), // LCDC
+            (0x46, 0xFF), // DMA
+            (0x47, 0xFC), // BGP
+        ];
+
+        for (register, value) in registers {
+            self.io_registers[register] = value;
+        }
+
+        match model {
+            Model::Sgb => self.io_registers[0x26] = 0xF0, // NR52
+            Model::Cgb => {
+                // KEY0
+                self.io_registers[0x4C] = if self.cgb_mode { self.rom[0x143] } else { 0x04 };
+                self.io_registers[0x02] = 0x7F; // SC
+                self.io_registers[0x46] = 0x00; // DMA
+                self.write_boot_palettes();
             }
+            _ => {}
+        }
+
+        // P1 reads 0xCF, both button groups are left selected
+        self.joypad.write(0x00);
+
+        self.lcd_stat = if model == Model::Dmg0 { 0x81 } else { 0x85 };
+
+        if matches!(model, Model::Dmg0 | Model::Dmg | Model::Mgb) {
+            self.write_boot_logo();
+        }
+    }
+
+    /// Set up palette RAM the way the CGB boot ROM does
+    ///
+    /// CGB mode starts with white BG palettes. In DMG compatibility mode the shades picked by BGP,
+    /// OBP0 and OBP1 index BG palette 0 and OBJ palettes 0 and 1, which are set to greys here
+    /// instead of the palette the boot ROM picks from the title.
+    fn write_boot_palettes(&mut self) {
+        const GREYS: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+        if self.cgb_mode {
+            self.palette_ram[..0x40].fill(0xFF);
+        } else {
+            for palette in [0, 8, 9] {
+                for (index, color) in GREYS.iter().enumerate() {
+                    let offset = palette * 8 + index * 2;
+                    self.palette_ram[offset..offset + 2].copy_from_slice(&color.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    /// Copy the logo from the cartridge header into VRAM the way the DMG boot ROM does
+    ///
+    /// Each pixel of the header logo is doubled in both directions, followed by the ® tile,
+    /// and the tiles are placed in the middle of the background map
+    fn write_boot_logo(&mut self) {
+        const REGISTERED_TILE: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
+        // Tile 0 is left blank, the 24 logo tiles start at tile 1
+        let mut address = 0x0010;
+        for byte in self.rom[0x104..0x134].iter().copied() {
+            for nibble in [byte >> 4, byte & 0x0F] {
+                // Only bit plane 0 is set, so the logo uses colour 1
+                let row = double_bits(nibble);
+                self.vram[address] = row;
+                self.vram[address + 2] = row;
+                address += 4;
+            }
+        }
+
+        for (index, row) in REGISTERED_TILE.iter().enumerate() {
+            self.vram[0x0190 + index * 2] = *row;
         }
 
-        for _ in 0..self.max_ram_bank {
-            self.switchable_ram.push([0; 0x2000]);
+        for index in 0..12 {
+            self.vram[0x1904 + index] = index as u8 + 0x01;
+            self.vram[0x1924 + index] = index as u8 + 0x0D;
         }
+        self.vram[0x1910] = 0x19;
+    }
+
+    /// Returns the size in bytes of the loaded cartridge ROM
+    pub fn rom_size(&self) -> usize {
+        (self.switchable_rom.len() + 1) * 0x4000
+    }
+
+    /// Returns the offset into the cartridge ROM of `address` with the current bank mapping
+    pub fn rom_offset(&self, address: u16) -> usize {
+        if address <= 0x3FFF {
+            self.zero_rom_bank as usize * 0x4000 + address as usize
+        } else {
+            self.rom_bank as usize * 0x4000 + (address as usize - 0x4000)
+        }
+    }
+
+    /// Start logging ROM accesses, continuing from the contents of a previously saved log if given
+    pub fn enable_code_data_log(&mut self, saved_log: Option<&[u8]>) {
+        let rom_size = self.rom_size();
+        self.code_data_log = Some(match saved_log {
+            Some(bytes) => CodeDataLog::from_bytes(bytes, rom_size),
+            None => CodeDataLog::new(rom_size),
+        });
+    }
+
+    pub fn load_boot_rom(&mut self, contents: &[u8]) -> Result<(), LoadError> {
+        let expected = if self.model == Model::Cgb {
+            0x900
+        } else {
+            0x100
+        };
+        if contents.len() != expected {
+            return Err(LoadError::BadBootRom {
+                size: contents.len(),
+                expected,
+            });
+        }
+
+        self.boot_rom = contents.to_vec();
+        Ok(())
+    }
+
+    /// Map a cartridge ROM, checking that the header describes hardware that is emulated
+    pub fn load_cartridge(&mut self, contents: &[u8]) -> Result<(), LoadError> {
+        // The two ROM banks are always mapped, so anything smaller isn't a valid cartridge
+        if contents.len() < 0x8000 {
+            return Err(LoadError::TooSmall {
+                size: contents.len(),
+            });
+        }
+
+        let header = CartridgeHeader::parse(contents).expect("ROM is large enough for a header");
+
+        let cartridge_type = match header.mapper {
+            Mapper::RomOnly => CartridgeType::Rom,
+            Mapper::Mbc1 => CartridgeType::Mbc1,
+            mapper => return Err(LoadError::UnsupportedMapper(mapper)),
+        };
+
+        let Some(rom_size) = header.rom_size else {
+            return Err(LoadError::UnknownRomSize(contents[0x148]));
+        };
+        let Some(ram_size) = header.ram_size else {
+            return Err(LoadError::UnknownRamSize(contents[0x149]));
+        };
+
+        // Overdumps and padded files have bytes past the end of the ROM, which are ignored
+        if contents.len() < rom_size {
+            return Err(LoadError::SizeMismatch {
+                expected: rom_size,
+                found: contents.len(),
+            });
+        }
+
+        self.cartridge_type = cartridge_type;
+        self.max_rom_bank = (rom_size / 0x4000) as u16;
+        self.max_ram_bank = (ram_size / 0x2000) as u8;
+
+        self.rom[..].clone_from_slice(&contents[..0x4000]);
+
+        self.switchable_rom = contents[0x4000..rom_size]
+            .chunks_exact(0x4000)
+            .map(|bank| bank.try_into().unwrap())
+            .collect();
+
+        self.switchable_ram = vec![[0; 0x2000]; self.max_ram_bank as usize];
+
+        Ok(())
     }
 
     pub fn read(&mut self, address: u16) -> u8 {
+        self.read_corrupting(address, OamCorruption::Read)
+    }
+
+    fn read_corrupting(&mut self, address: u16, corruption: OamCorruption) -> u8 {
         self.step();
         self.step();
         self.step();
         self.step();
 
-        if self.use_boot_rom && address < 256 {
+        if let Some(value) = self.dma_bus_conflict(address) {
+            return value;
+        }
+
+        self.corrupt_oam(address, corruption);
+
+        if self.blocked_by_ppu(address) {
+            return 0xFF;
+        }
+
+        let value = self.peek(address);
+
+        if address <= 0x7FFF && !self.boot_rom_mapped(address) {
+            let offset = self.rom_offset(address);
+            if let Some(code_data_log) = self.code_data_log.as_mut() {
+                code_data_log.record_read(address, offset, value);
+            }
+        }
+
+        value
+    }
+
+    /// Read a byte from the bus without advancing time
+    pub fn peek(&self, address: u16) -> u8 {
+        if self.boot_rom_mapped(address) {
             self.boot_rom[address as usize]
         } else if address <= 0x3FFF {
-            self.rom[address as usize]
+            self.read_rom_bank(self.zero_rom_bank, address as usize)
         } else if address <= 0x7FFF {
-            let mapped = address - 0x4000;
-            // Bank $00 is the unswitchable rom bank so subtract one to get the correct index
-            self.switchable_rom[self.rom_bank as usize - 1][mapped as usize]
+            self.read_rom_bank(self.rom_bank, address as usize - 0x4000)
         } else if address <= 0x9FFF {
-            let mapped = address - 0x8000;
-            self.vram[mapped as usize]
+            self.vram[self.vram_offset(address)]
         } else if address <= 0xBFFF {
-            if self.ram_enable {
+            if self.ram_enable && !self.switchable_ram.is_empty() {
                 let mapped = address - 0xA000;
                 self.switchable_ram[self.ram_bank as usize][mapped as usize]
             } else {
                 0xFF
             }
         } else if address <= 0xDFFF {
-            let mapped = address - 0xC000;
-            self.wram[mapped as usize]
+            self.wram[self.wram_offset(address)]
         } else if address <= 0xFDFF {
             // Echo RAM
             // Nintendo prohibits developers from using this memory range
-            let mapped = address - 0xE000;
-            self.wram[mapped as usize]
+            self.wram[self.wram_offset(address - 0x2000)]
         } else if address <= 0xFE9F {
             let mapped = address - 0xFE00;
             self.sprite_attribute_table[mapped as usize]
@@ -229,12 +584,23 @@ impl Memory {
                 0xFF05 => (self.timer_counter / self.timer_clock as u32) as u8,
                 0xFF06 => self.timer_modulo,
                 0xFF07 => self.io_registers[0x07], // TAC: Timer Control
-                0xFF41 => self.lcd_stat,
+                0xFF41 => 0b1000_0000 | self.lcd_stat,
                 0xFF42 => self.scy,
                 0xFF43 => self.scx,
-                0xFF44 => self.ly,
+                0xFF44 => self.fixed_ly.unwrap_or(self.ly),
                 0xFF4A => self.wy,
                 0xFF4B => self.wx,
+                // The CGB registers read 0xFF outside of CGB mode
+                0xFF4D if self.cgb_mode => {
+                    0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8
+                }
+                0xFF4F if self.cgb_mode => 0xFE | self.vram_bank,
+                0xFF70 if self.cgb_mode => 0xF8 | self.wram_bank,
+                0xFF68 if self.cgb_mode => 0x40 | self.io_registers[0x68],
+                0xFF69 if self.cgb_mode => self.read_palette_data(0x68, 0x00),
+                0xFF6A if self.cgb_mode => 0x40 | self.io_registers[0x6A],
+                0xFF6B if self.cgb_mode => self.read_palette_data(0x6A, 0x40),
+                0xFF4D | 0xFF4F | 0xFF68..=0xFF6B | 0xFF70 => 0xFF,
                 _ => {
                     let mapped = address - 0xFF00;
                     self.io_registers[mapped as usize]
@@ -254,49 +620,38 @@ impl Memory {
         self.step();
         self.step();
 
-        if address <= 0x3FFF {
-            if address <= 0x1FFF {
-                if data & 0x0F == 0xA {
-                    self.ram_enable = true;
-                } else {
-                    self.ram_enable = false;
-                }
-            } else {
-                let bank = data as u16 & 0x1F;
-                self.rom_bank = if bank == 0 { 1 } else { bank };
-            }
-        } else if address <= 0x7FFF {
-            if address <= 0x5FFF {
-                // Only settable if we have more than 32 KiB ram (ie more than 4 banks)
-                if self.max_ram_bank >= 4 {
-                    self.ram_bank = data;
-                }
+        // The bus is busy with the DMA transfer or the PPU, so the write is lost
+        if self.dma_bus_conflict(address).is_some() {
+            return;
+        }
 
-                // Only settable if we have more than 1 MiB of rom (ie more than 64 banks)
-                if self.max_rom_bank >= 64 {
-                    // Set top upper two bits (bits 5-6) of the ROM bank bank number
-                    todo!()
-                }
-            } else {
-                // Banking mode select
-                todo!()
+        self.corrupt_oam(address, OamCorruption::Write);
+
+        if self.blocked_by_ppu(address) {
+            return;
+        }
+
+        if address <= 0x7FFF {
+            // Cartridges without a mapper ignore writes to ROM
+            if self.cartridge_type == CartridgeType::Mbc1 {
+                self.write_mbc1(address, data);
             }
         } else if address <= 0x9FFF {
-            let mapped = address - 0x8000;
-            self.vram[mapped as usize] = data;
+            let offset = self.vram_offset(address);
+            self.vram[offset] = data;
         } else if address <= 0xBFFF {
-            if self.ram_enable {
+            if self.ram_enable && !self.switchable_ram.is_empty() {
                 let mapped = address - 0xA000;
                 self.switchable_ram[self.ram_bank as usize][mapped as usize] = data;
             }
         } else if address <= 0xDFFF {
-            let mapped = address - 0xC000;
-            self.wram[mapped as usize] = data;
+            let offset = self.wram_offset(address);
+            self.wram[offset] = data;
         } else if address <= 0xFDFF {
             // Echo RAM
             // Nintendo prohibits developers from using this memory range
-            let mapped = address - 0xE000;
-            self.wram[mapped as usize] = data;
+            let offset = self.wram_offset(address - 0x2000);
+            self.wram[offset] = data;
         } else if address <= 0xFE9F {
             let mapped = address - 0xFE00;
             self.sprite_attribute_table[mapped as usize] = data;
@@ -304,15 +659,10 @@ impl Memory {
             // Nintendo indicates that this area is prohibited
         } else if address <= 0xFF7F {
             match address {
-                // Only the top 4 bits of $FF00 are writeable, lower 4 are read only controller inputs
+                // Only bits 4 and 5 of $FF00 are writeable, the lower 4 are read only controller inputs
                 0xFF00 => {
-                    // If Bit 5 == 0 then Action buttons are selected, Bit 4 is unset for Direction buttons
-                    if (data & 0x20) == 0 {
-                        self.joypad.selected_buttons = ButtonType::Action;
-                    } else if (data & 0x10) == 0 {
-                        self.joypad.selected_buttons = ButtonType::Direction;
-                    } else {
-                        self.joypad.selected_buttons = ButtonType::None;
+                    if self.joypad.write(data) {
+                        self.io_registers[0x0F] |= 0b0001_0000;
                     }
                 }
                 0xFF04 => self.divider_register = 0,
@@ -330,18 +680,59 @@ impl Memory {
                         _ => 256,
                     };
                 }
-                0xFF41 => {} //read-only value
+                0xFF40 => self.write_lcdc(data),
+                0xFF41 => self.write_stat(data),
                 0xFF42 => self.scy = data,
                 0xFF43 => self.scx = data,
                 0xFF44 => {} //read-only value
                 0xFF46 => {
-                    self.dma_transfer(data);
+                    self.io_registers[0x46] = data;
+                    self.start_dma(data);
                 }
                 0xFF4A => self.wy = data,
                 0xFF4B => self.wx = data,
+                // KEY0 can only be written by the boot ROM
+                0xFF4C => {
+                    if self.use_boot_rom && self.model == Model::Cgb {
+                        self.io_registers[0x4C] = data;
+                    }
+                }
+                0xFF4D => {
+                    if self.cgb_mode {
+                        self.speed_switch_armed = data & 0x01 == 0x01;
+                    }
+                }
+                0xFF4F => {
+                    if self.cgb_mode {
+                        self.vram_bank = data & 0x01;
+                    }
+                }
                 0xFF50 => {
                     if self.use_boot_rom {
                         self.use_boot_rom = false;
+                        // KEY0 bit 2 selects DMG compatibility mode
+                        self.cgb_mode = self.cgb_mode && self.io_registers[0x4C] & 0x04 == 0;
+                    }
+                }
+                0xFF68 | 0xFF6A => {
+                    if self.cgb_mode {
+                        self.io_registers[address as usize - 0xFF00] = data & 0b1011_1111;
+                    }
+                }
+                0xFF69 => {
+                    if self.cgb_mode {
+                        self.write_palette_data(0x68, 0x00, data);
+                    }
+                }
+                0xFF6B => {
+                    if self.cgb_mode {
+                        self.write_palette_data(0x6A, 0x40, data);
+                    }
+                }
+                0xFF70 => {
+                    if self.cgb_mode {
+                        // Bank 0 is always at 0xC000, selecting it maps bank 1
+                        self.wram_bank = (data & 0x07).max(1);
                     }
                 }
                 _ => {
@@ -357,16 +748,346 @@ impl Memory {
         }
     }
 
-    fn dma_transfer(&mut self, start_address: u8) {
-        let base_address = start_address as u16 * 0x100;
-        for address in 0..0xA0 {
-            let data = self.read(base_address + address);
-            self.write(0xFE00 + address, data);
+    /// Returns the offset into `vram` of `address` with the current VRAM bank
+    fn vram_offset(&self, address: u16) -> usize {
+        self.vram_bank as usize * 0x2000 + (address as usize - 0x8000)
+    }
+
+    /// Returns the offset into `wram` of an address from `0xC000` to `0xDFFF` with the current
+    /// WRAM bank
+    fn wram_offset(&self, address: u16) -> usize {
+        if address <= 0xCFFF {
+            address as usize - 0xC000
+        } else {
+            self.wram_bank as usize * 0x1000 + (address as usize - 0xD000)
+        }
+    }
+
+    /// Returns byte `offset` of ROM bank `bank`
+    fn read_rom_bank(&self, bank: u16, offset: usize) -> u8 {
+        match bank {
+            0 => self.rom[offset],
+            // Bank $00 is not part of the switchable rom so subtract one to get the correct index
+            bank => self.switchable_rom[bank as usize - 1][offset],
         }
     }
 
+    /// Write to the MBC1 registers mapped over the ROM
+    fn write_mbc1(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enable = data & 0x0F == 0xA,
+            0x2000..=0x3FFF => {
+                // Bank 0 can't be selected, but the check only looks at these 5 bits
+                let bank = data & 0x1F;
+                self.rom_bank_low_bits = if bank == 0 { 1 } else { bank };
+            }
+            0x4000..=0x5FFF => self.bank_upper_bits = data & 0b11,
+            _ => self.banking_mode = data & 0b1 == 1,
+        }
+
+        // Bank numbers wrap around to the banks the cartridge has, which drops the upper bits on
+        // ROMs smaller than 1 MiB
+        let rom_banks = self.max_rom_bank as usize;
+        let upper_bank = (self.bank_upper_bits as usize) << 5;
+        self.rom_bank = ((upper_bank | self.rom_bank_low_bits as usize) % rom_banks) as u16;
+
+        if self.banking_mode {
+            self.zero_rom_bank = (upper_bank % rom_banks) as u16;
+            self.ram_bank = self.bank_upper_bits % self.max_ram_bank.max(1);
+        } else {
+            self.zero_rom_bank = 0;
+            self.ram_bank = 0;
+        }
+    }
+
+    /// The PPU reads palette RAM while drawing, so it is locked during mode 3
+    fn palette_ram_locked(&self) -> bool {
+        self.access_restrictions && self.lcd_stat & 0b11 == 3
+    }
+
+    /// Read the byte of palette RAM selected by BCPS or OCPS at `spec_register`, `offset` is the
+    /// start of the BG or OBJ palettes
+    fn read_palette_data(&self, spec_register: usize, offset: usize) -> u8 {
+        if self.palette_ram_locked() {
+            return 0xFF;
+        }
+
+        self.palette_ram[offset + (self.io_registers[spec_register] & 0x3F) as usize]
+    }
+
+    /// Write the byte of palette RAM selected by BCPS or OCPS at `spec_register`, advancing the
+    /// index if its auto-increment bit is set
+    fn write_palette_data(&mut self, spec_register: usize, offset: usize, data: u8) {
+        let spec = self.io_registers[spec_register];
+        if !self.palette_ram_locked() {
+            self.palette_ram[offset + (spec & 0x3F) as usize] = data;
+        }
+
+        // The index still advances when the write is blocked
+        if spec & 0b1000_0000 != 0 {
+            self.io_registers[spec_register] = 0b1000_0000 | (spec.wrapping_add(1) & 0x3F);
+        }
+    }
+
+    /// Returns color `index` (0-3) of CGB palette `palette` as 15-bit RGB
+    ///
+    /// Palettes 0-7 are the BG palettes and 8-15 the OBJ palettes
+    pub fn palette_color(&self, palette: u8, index: u8) -> u16 {
+        let offset = palette as usize * 8 + index as usize * 2;
+        u16::from_le_bytes([self.palette_ram[offset], self.palette_ram[offset + 1]]) & 0x7FFF
+    }
+
+    /// Switch between normal and double speed if KEY1 armed a switch, which is how STOP behaves
+    /// in CGB mode
+    ///
+    /// Returns true if the speed was switched
+    pub fn switch_speed(&mut self) -> bool {
+        if !(self.cgb_mode && self.speed_switch_armed) {
+            return false;
+        }
+
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        self.divider_register = 0;
+        true
+    }
+
+    /// Returns true if LCDC bit 7 is set
+    pub fn lcd_enabled(&self) -> bool {
+        self.io_registers[0x40] & 0b1000_0000 == 0b1000_0000
+    }
+
+    /// Returns true if the last finished frame shouldn't be shown
+    ///
+    /// The screen is blank while the LCD is off and for the first frame after it is turned back on
+    pub fn frame_blank(&self) -> bool {
+        self.frame_blank
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        if self.pixel_fifo.is_some() {
+            Renderer::PixelFifo
+        } else {
+            Renderer::Scanline
+        }
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.pixel_fifo = match renderer {
+            Renderer::Scanline => None,
+            Renderer::PixelFifo => Some(Box::default()),
+        };
+    }
+
+    pub fn access_restrictions(&self) -> bool {
+        self.access_restrictions
+    }
+
+    /// Let the CPU access VRAM and OAM in every PPU mode when disabled, which some debugging
+    /// setups rely on
+    pub fn set_access_restrictions(&mut self, enabled: bool) {
+        self.access_restrictions = enabled;
+    }
+
+    /// Returns the pixel FIFO, which holds the last line it finished, if it is the selected renderer
+    pub fn pixel_fifo(&self) -> Option<&PixelFifo> {
+        self.pixel_fifo.as_deref()
+    }
+
+    /// Returns the lines that are ready to be drawn since the last call, in order
+    ///
+    /// The scanline renderer draws lines when mode 3 starts, the pixel FIFO has drawn them by the
+    /// time mode 3 ends
+    pub fn take_lines_to_render(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.lines_to_render)
+    }
+
+    fn write_lcdc(&mut self, data: u8) {
+        let was_enabled = self.lcd_enabled();
+        self.io_registers[0x40] = data;
+
+        if was_enabled && !self.lcd_enabled() {
+            // LY is held at 0 in HBlank while the LCD is off, frames continue from the same point
+            self.lcd_off_time = self.ly as u32 * 456 + self.time as u32;
+            self.ly = 0;
+            self.time = 0;
+            self.lcd_stat &= 0b1111_1100;
+            self.stat_line = false;
+        } else if !was_enabled && self.lcd_enabled() {
+            // The LCD restarts at the beginning of line 0
+            self.time = 0;
+            self.ly = 0;
+            self.lcd_enabled_this_frame = true;
+        }
+    }
+
+    /// Write the interrupt sources of STAT, the LYC flag and mode bits are read only
+    fn write_stat(&mut self, data: u8) {
+        // On DMG the write briefly enables every source, so it can request an interrupt
+        // during HBlank, VBlank or when LY=LYC
+        if self.model != Model::Cgb && self.lcd_enabled() {
+            self.update_stat_line(self.lcd_stat | 0b0101_1000);
+        }
+
+        self.lcd_stat = (self.lcd_stat & 0b0000_0111) | (data & 0b0111_1000);
+    }
+
+    /// Returns true if any enabled STAT interrupt source is active for the given STAT value
+    fn stat_sources_active(&self, stat: u8) -> bool {
+        let mode = stat & 0b0000_0011;
+        let lyc = stat & 0b0100_0100 == 0b0100_0100;
+        let hblank = mode == 0 && stat & 0b0000_1000 != 0;
+        // The mode 2 source also triggers when VBlank starts
+        let vblank =
+            mode == 1 && (stat & 0b0001_0000 != 0 || (self.ly == 144 && stat & 0b0010_0000 != 0));
+        let oam = mode == 2 && stat & 0b0010_0000 != 0;
+
+        lyc || hblank || vblank || oam
+    }
+
+    /// Request the STAT interrupt on a rising edge of the OR of the enabled sources
+    ///
+    /// While one source holds the line high, other sources becoming active don't request
+    /// another interrupt
+    fn update_stat_line(&mut self, stat: u8) {
+        let line = self.stat_sources_active(stat);
+        if line && !self.stat_line {
+            self.io_registers[0x0F] |= 0b0000_0010;
+        }
+        self.stat_line = line;
+    }
+
+    /// Start copying 160 bytes from `start_address * 0x100` to OAM
+    ///
+    /// The transfer starts after a setup M-cycle. Starting a transfer while one is running restarts
+    /// it, with the old transfer continuing until the new one has finished its setup.
+    fn start_dma(&mut self, start_address: u8) {
+        self.pending_dma = Some(OamDma {
+            source: start_address as u16 * 0x100,
+            index: 1,
+            value: 0xFF,
+        });
+    }
+
+    /// Advance OAM DMA by one M-cycle
+    fn step_dma(&mut self) {
+        if let Some(pending_dma) = self.pending_dma.as_mut() {
+            if pending_dma.index == 0 {
+                self.dma = self.pending_dma.take();
+            } else {
+                pending_dma.index -= 1;
+            }
+        }
+
+        if let Some(mut dma) = self.dma {
+            if dma.index == DMA_LENGTH {
+                self.dma = None;
+                return;
+            }
+
+            // Sources past WRAM read from echo RAM
+            let mut address = dma.source + dma.index as u16;
+            if address >= 0xE000 {
+                address -= 0x2000;
+            }
+
+            dma.value = self.peek(address);
+            self.sprite_attribute_table[dma.index as usize] = dma.value;
+            dma.index += 1;
+            self.dma = Some(dma);
+        }
+    }
+
+    /// Returns the value the CPU sees at `address` while OAM DMA is copying, if it isn't the
+    /// value at `address`
+    ///
+    /// OAM is unavailable during DMA and reads `0xFF`. The video bus (VRAM) and the external bus
+    /// (everything else below OAM) are separate, so the bus DMA reads from returns the byte being
+    /// copied and the other bus can still be used. HRAM and the IO registers are never blocked.
+    fn dma_bus_conflict(&self, address: u16) -> Option<u8> {
+        let dma = self.dma.as_ref()?;
+
+        let video_bus = |address: u16| (0x8000..=0x9FFF).contains(&address);
+
+        if (0xFE00..=0xFEFF).contains(&address) {
+            Some(0xFF)
+        } else if address < 0xFE00 && video_bus(address) == video_bus(dma.source) {
+            Some(dma.value)
+        } else {
+            None
+        }
+    }
+
+    /// The PPU reads VRAM during mode 3 and OAM during modes 2 and 3, the CPU reads `0xFF` and
+    /// can't write there in the meantime
+    fn blocked_by_ppu(&self, address: u16) -> bool {
+        if !self.access_restrictions {
+            return false;
+        }
+
+        let mode = self.lcd_stat & 0b11;
+        match address {
+            0x8000..=0x9FFF => mode == 3,
+            0xFE00..=0xFEFF => mode == 2 || mode == 3,
+            _ => false,
+        }
+    }
+
+    /// Emulate the OAM corruption bug of DMG models, triggered when `address` is on the bus while
+    /// the PPU is scanning OAM
+    ///
+    /// The PPU reads one 8 byte row of OAM per M-cycle during mode 2, and the row it is reading
+    /// gets mixed with the row before it. The first row is never corrupted.
+    fn corrupt_oam(&mut self, address: u16, corruption: OamCorruption) {
+        if self.model == Model::Cgb
+            || !(0xFE00..=0xFEFF).contains(&address)
+            || self.lcd_stat & 0b11 != 2
+            || self.dma.is_some()
+        {
+            return;
+        }
+
+        let row = self.time as usize / 4;
+        if row == 0 || row >= 20 {
+            return;
+        }
+
+        let oam = &mut self.sprite_attribute_table;
+        let word = |oam: &[u8; 0xA0], row: usize, index: usize| {
+            u16::from_le_bytes([oam[row * 8 + index * 2], oam[row * 8 + index * 2 + 1]])
+        };
+
+        if corruption == OamCorruption::ReadIncrease && (4..19).contains(&row) {
+            let a = word(oam, row - 2, 0);
+            let b = word(oam, row - 1, 0);
+            let c = word(oam, row, 0);
+            let d = word(oam, row - 1, 2);
+            let preceding = (b & (a | c | d)) | (a & c & d);
+            oam[(row - 1) * 8..(row - 1) * 8 + 2].copy_from_slice(&preceding.to_le_bytes());
+
+            oam.copy_within((row - 1) * 8..row * 8, row * 8);
+            oam.copy_within((row - 1) * 8..row * 8, (row - 2) * 8);
+        }
+
+        let a = word(oam, row, 0);
+        let b = word(oam, row - 1, 0);
+        let c = word(oam, row - 1, 2);
+        let first = match corruption {
+            OamCorruption::Write => ((a ^ c) & (b ^ c)) ^ c,
+            OamCorruption::Read | OamCorruption::ReadIncrease => b | (a & c),
+        };
+
+        oam[row * 8..row * 8 + 2].copy_from_slice(&first.to_le_bytes());
+        oam.copy_within((row - 1) * 8 + 2..row * 8, row * 8 + 2);
+    }
+
     fn step(&mut self) {
-        self.time += 1;
+        self.cycles += 1;
+
+        if self.cycles.is_multiple_of(4) {
+            self.step_dma();
+        }
+
         self.divider_register += 1;
 
         if self.divider_register / 256 > 255 {
@@ -380,6 +1101,25 @@ impl Memory {
             }
         }
 
+        // The PPU keeps running at normal speed, so it only steps every other T-cycle at double speed
+        if self.double_speed && self.cycles % 2 == 1 {
+            return;
+        }
+        self.dots += 1;
+
+        if !self.lcd_enabled() {
+            self.lcd_off_time += 1;
+
+            if self.lcd_off_time == 154 * 456 {
+                self.lcd_off_time = 0;
+                self.frame_happened = true;
+                self.frame_blank = true;
+            }
+
+            return;
+        }
+
+        self.time += 1;
         if self.time == 456 {
             self.time = 0;
             self.ly += 1;
@@ -391,6 +1131,8 @@ impl Memory {
             if self.ly == 154 {
                 self.ly = 0;
                 self.frame_happened = true;
+                self.frame_blank = self.lcd_enabled_this_frame;
+                self.lcd_enabled_this_frame = false;
             }
         }
 
@@ -404,7 +1146,7 @@ impl Memory {
         //      0: HBlank, 1: VBlank, 2: Searching OAM, 3: Transferring Data to LCD Controller
 
         // Mode 2 - OAM Scan: lasts 80 dots
-        // Mode 3 - Drawing Pixels: lasts 172-289 dots (median: 230.5 ~ 230)
+        // Mode 3 - Drawing Pixels: lasts 172-289 dots (median: 230.5 ~ 230), the pixel FIFO times it exactly
         // Mode 0 - Horizontal Blank: lasts 87-204 dots (based off time remaining after mode 3) (median: 145.5 ~ 146)
         // Mode 1 - Vertical Blank: 10 "scanlines" (lines 144-153)
         if self.ly >= 144 {
@@ -414,43 +1156,58 @@ impl Memory {
                 self.lcd_stat = (self.lcd_stat & 0b1111_1100) + 2;
             } else if self.time == 81 {
                 self.lcd_stat = (self.lcd_stat & 0b1111_1100) + 3;
-            } else if self.time == 311 {
+
+                if let Some(mut pixel_fifo) = self.pixel_fifo.take() {
+                    pixel_fifo.start_line(self, self.ly);
+                    self.pixel_fifo = Some(pixel_fifo);
+                } else {
+                    self.lines_to_render.push(self.ly);
+                }
+            } else if self.lcd_stat & 0b0000_0011 == 3 && self.pixel_fifo.is_some() {
+                // Mode 3 lasts until the pixel FIFO has output the whole line
+                let mut pixel_fifo = self.pixel_fifo.take().unwrap();
+                if pixel_fifo.step(self) {
+                    self.lcd_stat &= 0b1111_1100;
+                    self.lines_to_render.push(self.ly);
+                }
+                self.pixel_fifo = Some(pixel_fifo);
+            } else if self.time == 311 && self.pixel_fifo.is_none() {
                 self.lcd_stat &= 0b1111_1100;
             }
         }
+
+        if self.ly == self.io_registers[0x45] {
+            self.lcd_stat |= 0b0000_0100;
+        } else {
+            self.lcd_stat &= 0b1111_1011;
+        }
+
+        self.update_stat_line(self.lcd_stat);
     }
 
-    pub fn vram_read_tile(&self, tile_type: TileType, index: u8) -> TileInfo {
+    /// Read tile `index` from VRAM `bank`, background and window tiles use the addressing mode
+    /// selected by LCDC bit 4
+    pub fn vram_read_tile(&self, tile_type: TileType, index: u8, bank: u8) -> TileInfo {
         // Get LCDC bit 4 to toggle indexing modes (from IO registers)
         // TODO: Better way to do this...
         let lcdc4 = self.io_registers[0x40] & 0b0001_0000 == 0b0001_0000;
 
-        match tile_type {
-            TileType::Obj => {
-                let address = index as usize * 16;
-                let mut tile = [0; 16];
-                tile.copy_from_slice(&self.vram[address..(address + 16)]);
-                TileInfo { tile, tile_type }
-            }
+        let address = match tile_type {
+            TileType::Obj => index as usize * 16,
             TileType::Window | TileType::Background => {
                 if lcdc4 {
-                    let address = index as usize * 16;
-                    let mut tile = [0; 16];
-                    tile.copy_from_slice(&self.vram[address..(address + 16)]);
-                    TileInfo { tile, tile_type }
+                    index as usize * 16
                 } else if index >= 128 {
-                    let address = 0x0800 + ((index as usize - 128) * 16);
-                    let mut tile = [0; 16];
-                    tile.copy_from_slice(&self.vram[address..(address + 16)]);
-                    TileInfo { tile, tile_type }
+                    0x0800 + ((index as usize - 128) * 16)
                 } else {
-                    let address = 0x1000 + (index as usize * 16);
-                    let mut tile = [0; 16];
-                    tile.copy_from_slice(&self.vram[address..(address + 16)]);
-                    TileInfo { tile, tile_type }
+                    0x1000 + (index as usize * 16)
                 }
             }
-        }
+        } + bank as usize * 0x2000;
+
+        let mut tile = [0; 16];
+        tile.copy_from_slice(&self.vram[address..(address + 16)]);
+        TileInfo { tile, tile_type }
     }
 
     pub fn read_bg_tile_map(&self) -> [[u8; 32]; 32] {
@@ -495,6 +1252,40 @@ impl Memory {
         result
     }
 
+    /// Returns the CGB attributes of the background tile map, from VRAM bank 1
+    pub fn read_bg_attribute_map(&self) -> [[u8; 32]; 32] {
+        let start_address = if self.io_registers[0x40] & 0b0000_1000 == 0b0000_1000 {
+            0x3C00
+        } else {
+            0x3800
+        };
+
+        self.read_attribute_map(start_address)
+    }
+
+    /// Returns the CGB attributes of the window tile map, from VRAM bank 1
+    pub fn read_window_attribute_map(&self) -> [[u8; 32]; 32] {
+        let start_address = if self.io_registers[0x40] & 0b0100_0000 == 0b0100_0000 {
+            0x3C00
+        } else {
+            0x3800
+        };
+
+        self.read_attribute_map(start_address)
+    }
+
+    fn read_attribute_map(&self, start_address: usize) -> [[u8; 32]; 32] {
+        let mut result = [[0; 32]; 32];
+        for (row, attributes) in self.vram[start_address..(start_address + 0x400)]
+            .chunks_exact(32)
+            .zip(result.iter_mut())
+        {
+            attributes.copy_from_slice(row);
+        }
+
+        result
+    }
+
     pub fn read_oam(&self) -> Vec<SpriteAttribute> {
         let mut result = Vec::new();
 
@@ -515,8 +1306,15 @@ impl Memory {
         result
     }
 
-    pub fn set_joypad_inputs(&mut self, pressed_keys: HashSet<Keycode>) {
-        self.joypad.set_inputs(pressed_keys);
+    pub fn joypad_state(&self) -> JoypadState {
+        self.joypad.state()
+    }
+
+    /// Replace the pressed buttons, requesting the joypad interrupt if a selected button was pressed
+    pub fn set_joypad_state(&mut self, state: JoypadState) {
+        if self.joypad.set_state(state) {
+            self.io_registers[0x0F] |= 0b0001_0000;
+        }
     }
 }
 
@@ -528,6 +1326,26 @@ impl CpuBus for Memory {
     fn write(&mut self, address: u16, val: u8) {
         self.write(address, val)
     }
+
+    fn increment_address(&mut self, address: u16) {
+        self.corrupt_oam(address, OamCorruption::Write);
+    }
+
+    fn read_increment(&mut self, address: u16) -> u8 {
+        self.read_corrupting(address, OamCorruption::ReadIncrease)
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        self.switch_speed()
+    }
+
+    fn stop(&mut self) {
+        self.divider_register = 0;
+    }
+
+    fn joypad_line_low(&self) -> bool {
+        self.peek(0xFF00) & 0x0F != 0x0F
+    }
 }
 
 impl Default for Memory {
@@ -536,6 +1354,17 @@ impl Default for Memory {
     }
 }
 
+/// Stretch the 4 bits of `nibble` to 8 bits, each bit repeated twice
+fn double_bits(nibble: u8) -> u8 {
+    (0..4).fold(0, |doubled, bit| {
+        if nibble & (1 << bit) != 0 {
+            doubled | (0b11 << (bit * 2))
+        } else {
+            doubled
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -555,4 +1384,571 @@ mod tests {
         assert_eq!(tile_map[0], values);
         assert_eq!(tile_map[31], values);
     }
+
+    #[test]
+    fn test_code_data_log_records_rom_reads() {
+        use crate::code_data_log::{CDL_DATA, CDL_EXEC_FIRST};
+
+        let mut memory = Memory::new();
+        memory.load_cartridge(&[0; 0x8000]).unwrap();
+        memory.set_post_boot_state();
+        memory.enable_code_data_log(None);
+
+        memory
+            .code_data_log
+            .as_mut()
+            .unwrap()
+            .begin_instruction(0x100);
+        memory.read(0x100);
+        memory.read(0x101);
+        memory.read(0x4000);
+        memory.read(0xC000);
+
+        let code_data_log = memory.code_data_log.as_ref().unwrap();
+        assert_eq!(code_data_log.as_bytes().len(), 0x8000);
+        assert_eq!(code_data_log.flags(0x100), CDL_EXEC_FIRST);
+        // NOP has no operands, so the next byte was read as data
+        assert_eq!(code_data_log.flags(0x101), CDL_DATA);
+        assert_eq!(code_data_log.flags(0x4000), CDL_DATA);
+    }
+
+    #[test]
+    fn test_joypad_interrupt_requested() {
+        let mut memory = Memory::new();
+        memory.write(0xFF00, 0b0010_0000);
+        assert_eq!(memory.read(0xFF0F) & 0b0001_0000, 0);
+
+        let mut state = JoypadState::new();
+        state.press(crate::joypad::Button::Up);
+        memory.set_joypad_state(state);
+
+        assert_eq!(memory.read(0xFF00), 0b1110_1011);
+        assert_eq!(memory.read(0xFF0F) & 0b0001_0000, 0b0001_0000);
+    }
+
+    #[test]
+    fn test_lcd_off() {
+        let mut memory = Memory::new();
+        memory.set_post_boot_state();
+
+        // Run into line 2, then turn the LCD off
+        for _ in 0..(456 * 2 + 100) {
+            memory.step();
+        }
+        memory.write(0xFF40, 0x11);
+        assert_eq!(memory.read(0xFF44), 0);
+        assert_eq!(memory.read(0xFF41) & 0b11, 0);
+
+        memory.io_registers[0x0F] = 0;
+        while !memory.frame_happened {
+            memory.step();
+        }
+        assert!(memory.frame_blank());
+        assert_eq!(memory.read(0xFF44), 0);
+        assert_eq!(memory.io_registers[0x0F] & 0x01, 0, "No VBlank interrupt");
+
+        // The first frame after turning the LCD back on is blank too
+        memory.frame_happened = false;
+        memory.write(0xFF40, 0x91);
+        let start = memory.cycles();
+        while !memory.frame_happened {
+            memory.step();
+        }
+        assert!(memory.frame_blank());
+        assert_eq!(memory.cycles() - start, 154 * 456);
+
+        memory.frame_happened = false;
+        while !memory.frame_happened {
+            memory.step();
+        }
+        assert!(!memory.frame_blank());
+    }
+
+    fn step_until(memory: &mut Memory, ly: u8, mode: u8) {
+        while memory.ly != ly || memory.lcd_stat & 0b11 != mode {
+            memory.step();
+        }
+    }
+
+    #[test]
+    fn test_lyc_interrupt() {
+        let mut memory = Memory::new();
+        memory.set_post_boot_state();
+        memory.write(0xFF45, 2);
+        memory.write(0xFF41, 0b0100_0000);
+        memory.io_registers[0x0F] = 0;
+
+        step_until(&mut memory, 1, 0);
+        assert_eq!(memory.read(0xFF41) & 0b0000_0100, 0);
+        assert_eq!(memory.io_registers[0x0F] & 0b10, 0);
+
+        step_until(&mut memory, 2, 2);
+        assert_eq!(memory.read(0xFF41), 0b1100_0110);
+        assert_eq!(memory.io_registers[0x0F] & 0b10, 0b10);
+    }
+
+    #[test]
+    fn test_stat_interrupt_on_rising_edge_only() {
+        let mut memory = Memory::new();
+        memory.set_post_boot_state();
+        // HBlank and OAM sources
+        memory.write(0xFF41, 0b0010_1000);
+
+        step_until(&mut memory, 1, 0);
+        memory.io_registers[0x0F] = 0;
+
+        // HBlank is followed by OAM scan, the line never goes low in between
+        step_until(&mut memory, 2, 2);
+        assert_eq!(memory.io_registers[0x0F] & 0b10, 0);
+
+        step_until(&mut memory, 2, 3);
+        step_until(&mut memory, 2, 0);
+        assert_eq!(memory.io_registers[0x0F] & 0b10, 0b10);
+    }
+
+    #[test]
+    fn test_stat_write_quirk() {
+        let mut memory = Memory::new();
+        memory.set_post_boot_state();
+        memory.write(0xFF45, 0xFF);
+        step_until(&mut memory, 144, 1);
+
+        memory.io_registers[0x0F] = 0;
+        memory.write(0xFF41, 0x00);
+        assert_eq!(memory.io_registers[0x0F] & 0b10, 0b10, "DMG");
+
+        let mut memory = Memory::with_model(Model::Cgb);
+        memory.set_post_boot_state();
+        memory.write(0xFF45, 0xFF);
+        step_until(&mut memory, 144, 1);
+
+        memory.io_registers[0x0F] = 0;
+        memory.write(0xFF41, 0x00);
+        assert_eq!(memory.io_registers[0x0F] & 0b10, 0, "CGB");
+    }
+
+    #[test]
+    fn test_stat_write_quirk_ignores_oam_scan() {
+        let mut memory = Memory::new();
+        memory.set_post_boot_state();
+        memory.write(0xFF45, 0xFF);
+        step_until(&mut memory, 10, 2);
+
+        memory.io_registers[0x0F] = 0;
+        memory.write(0xFF41, 0x00);
+        assert_eq!(memory.lcd_stat & 0b11, 2);
+        assert_eq!(memory.io_registers[0x0F] & 0b10, 0);
+    }
+
+    #[test]
+    fn test_pixel_fifo_mode_3_length() {
+        let mut memory = Memory::new();
+        memory.set_post_boot_state();
+        memory.set_renderer(Renderer::PixelFifo);
+
+        step_until(&mut memory, 1, 3);
+        let start = memory.cycles();
+        step_until(&mut memory, 1, 0);
+        assert_eq!(memory.cycles() - start, 172);
+        assert_eq!(memory.take_lines_to_render().last(), Some(&1));
+
+        memory.scx = 2;
+        step_until(&mut memory, 2, 3);
+        let start = memory.cycles();
+        step_until(&mut memory, 2, 0);
+        assert_eq!(memory.cycles() - start, 174);
+    }
+
+    fn dma_memory() -> Memory {
+        let mut memory = Memory::new();
+        memory.load_cartridge(&[0; 0x8000]).unwrap();
+        memory.set_post_boot_state();
+        // Keep the PPU out of OAM
+        memory.write_lcdc(0x00);
+        for i in 0..0xA0 {
+            memory.wram[0x100 + i] = i as u8 + 1;
+        }
+        memory
+    }
+
+    #[test]
+    fn test_dma_start_delay_and_length() {
+        let mut memory = dma_memory();
+        memory.sprite_attribute_table[0] = 0x55;
+        memory.write(0xFF46, 0xC1);
+
+        // OAM is still readable during the setup M-cycle
+        assert_eq!(memory.read(0xFE00), 0x55);
+        assert_eq!(memory.read(0xFE00), 0xFF);
+
+        // OAM stays blocked for 160 M-cycles, the read above was the first
+        for _ in 0..158 {
+            assert_eq!(memory.read(0xFF80), memory.hram[0]);
+        }
+        assert_eq!(memory.read(0xFE00), 0xFF);
+        assert_eq!(memory.read(0xFE00), 0x01);
+        assert_eq!(memory.read(0xFE9F), 0xA0);
+    }
+
+    #[test]
+    fn test_dma_restart() {
+        let mut memory = dma_memory();
+        memory.wram[0x200] = 0x42;
+        memory.write(0xFF46, 0xC1);
+        for _ in 0..10 {
+            memory.read(0xFF80);
+        }
+
+        // The old transfer keeps OAM blocked while the new one is set up
+        memory.write(0xFF46, 0xC2);
+        assert_eq!(memory.read(0xFE00), 0xFF);
+        for _ in 0..160 {
+            assert_eq!(memory.read(0xFE00), 0xFF);
+        }
+        assert_eq!(memory.read(0xFE00), 0x42);
+        assert_eq!(memory.read(0xFE01), 0x00);
+    }
+
+    #[test]
+    fn test_dma_bus_conflicts() {
+        let mut memory = dma_memory();
+        memory.vram[0] = 0x99;
+        memory.write(0xFF46, 0xC1);
+        memory.read(0xFF80);
+
+        // Reads on the external bus see the byte DMA is copying, VRAM is on its own bus
+        assert_eq!(memory.read(0x0000), 0x01);
+        assert_eq!(memory.read(0xC000), 0x02);
+        assert_eq!(memory.read(0x8000), 0x99);
+        memory.write(0xC000, 0x77);
+        memory.write(0xFF80, 0x77);
+        assert_eq!(memory.read(0xFF80), 0x77);
+        assert_eq!(memory.wram[0], 0x00);
+    }
+
+    #[test]
+    fn test_vram_and_oam_blocked_by_ppu_mode() {
+        let mut memory = Memory::new();
+        memory.set_post_boot_state();
+        memory.vram[0] = 0x12;
+        memory.sprite_attribute_table[0] = 0x34;
+
+        step_until(&mut memory, 1, 2);
+        assert_eq!(memory.read(0x8000), 0x12);
+        assert_eq!(memory.read(0xFE00), 0xFF);
+        memory.write(0xFE00, 0x00);
+        assert_eq!(memory.sprite_attribute_table[0], 0x34);
+
+        step_until(&mut memory, 1, 3);
+        assert_eq!(memory.read(0x8000), 0xFF);
+        assert_eq!(memory.read(0xFE00), 0xFF);
+        memory.write(0x8000, 0x00);
+        assert_eq!(memory.vram[0], 0x12);
+
+        memory.set_access_restrictions(false);
+        assert_eq!(memory.read(0x8000), 0x12);
+        assert_eq!(memory.read(0xFE00), 0x34);
+        memory.set_access_restrictions(true);
+
+        step_until(&mut memory, 1, 0);
+        assert_eq!(memory.read(0x8000), 0x12);
+        assert_eq!(memory.read(0xFE00), 0x34);
+        memory.write(0x8000, 0x56);
+        assert_eq!(memory.vram[0], 0x56);
+    }
+
+    /// Returns memory with every OAM byte holding its own offset, `dots` into OAM scan of line 1
+    fn oam_bug_memory(model: Model, dots: u32) -> Memory {
+        let mut memory = Memory::with_model(model);
+        memory.set_post_boot_state();
+        for (i, byte) in memory.sprite_attribute_table.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        step_until(&mut memory, 1, 2);
+        for _ in 0..dots {
+            memory.step();
+        }
+        memory
+    }
+
+    fn oam_row(memory: &Memory, row: usize) -> [u16; 4] {
+        let oam = &memory.sprite_attribute_table[row * 8..row * 8 + 8];
+        [0, 1, 2, 3].map(|i| u16::from_le_bytes([oam[i * 2], oam[i * 2 + 1]]))
+    }
+
+    #[test]
+    fn test_oam_bug_write() {
+        // The access lands on dot 24, when the PPU is reading row 6
+        let mut memory = oam_bug_memory(Model::Dmg, 20);
+        let preceding = oam_row(&memory, 5);
+        let (a, b, c) = (oam_row(&memory, 6)[0], preceding[0], preceding[2]);
+
+        memory.write(0xFE00, 0xAA);
+        let row = oam_row(&memory, 6);
+        assert_eq!(row[0], ((a ^ c) & (b ^ c)) ^ c);
+        assert_eq!(row[1..], preceding[1..]);
+        assert_eq!(oam_row(&memory, 5), preceding);
+    }
+
+    #[test]
+    fn test_oam_bug_read() {
+        let mut memory = oam_bug_memory(Model::Dmg, 20);
+        let preceding = oam_row(&memory, 5);
+        let (a, b, c) = (oam_row(&memory, 6)[0], preceding[0], preceding[2]);
+
+        assert_eq!(memory.read(0xFE10), 0xFF);
+        let row = oam_row(&memory, 6);
+        assert_eq!(row[0], b | (a & c));
+        assert_eq!(row[1..], preceding[1..]);
+    }
+
+    #[test]
+    fn test_oam_bug_read_increase() {
+        let mut memory = oam_bug_memory(Model::Dmg, 20);
+        let (a, b, c, d) = (
+            oam_row(&memory, 4)[0],
+            oam_row(&memory, 5)[0],
+            oam_row(&memory, 6)[0],
+            oam_row(&memory, 5)[2],
+        );
+
+        CpuBus::read_increment(&mut memory, 0xFE10);
+        let preceding = (b & (a | c | d)) | (a & c & d);
+        assert_eq!(oam_row(&memory, 4)[0], preceding);
+        assert_eq!(oam_row(&memory, 5)[0], preceding);
+        assert_eq!(oam_row(&memory, 6)[0], preceding | (preceding & d));
+        assert_eq!(oam_row(&memory, 6)[1..], oam_row(&memory, 5)[1..]);
+    }
+
+    #[test]
+    fn test_oam_bug_only_during_oam_scan_on_dmg() {
+        // The first row is never corrupted
+        let mut memory = oam_bug_memory(Model::Dmg, 0);
+        let oam = memory.sprite_attribute_table;
+        CpuBus::increment_address(&mut memory, 0xFE00);
+        assert_eq!(memory.sprite_attribute_table, oam);
+
+        let mut memory = oam_bug_memory(Model::Cgb, 20);
+        let oam = memory.sprite_attribute_table;
+        CpuBus::increment_address(&mut memory, 0xFE00);
+        assert_eq!(memory.sprite_attribute_table, oam);
+
+        let mut memory = oam_bug_memory(Model::Dmg, 20);
+        let oam = memory.sprite_attribute_table;
+        memory.read(0xFF80);
+        CpuBus::increment_address(&mut memory, 0xFF00);
+        assert_eq!(memory.sprite_attribute_table, oam);
+
+        step_until(&mut memory, 1, 3);
+        CpuBus::increment_address(&mut memory, 0xFE00);
+        assert_eq!(memory.sprite_attribute_table, oam);
+    }
+
+    fn cgb_memory() -> Memory {
+        let mut cartridge = vec![0; 0x8000];
+        cartridge[0x143] = 0x80;
+        let mut memory = Memory::with_model(Model::Cgb);
+        memory.load_cartridge(&cartridge).unwrap();
+        memory.set_post_boot_state();
+        memory.write_lcdc(0x00);
+        memory
+    }
+
+    #[test]
+    fn test_cgb_vram_banks() {
+        let mut memory = cgb_memory();
+        memory.write(0x8000, 0x11);
+        memory.write(0xFF4F, 0x01);
+        assert_eq!(memory.read(0xFF4F), 0xFF);
+        memory.write(0x8000, 0x22);
+        assert_eq!(memory.read(0x8000), 0x22);
+        assert_eq!(memory.vram[0x0000], 0x11);
+        assert_eq!(memory.vram[0x2000], 0x22);
+
+        memory.write(0xFF4F, 0xFE);
+        assert_eq!(memory.read(0xFF4F), 0xFE);
+        assert_eq!(memory.read(0x8000), 0x11);
+    }
+
+    #[test]
+    fn test_cgb_wram_banks() {
+        let mut memory = cgb_memory();
+        assert_eq!(memory.read(0xFF70), 0xF9);
+        memory.write(0xC000, 0x11);
+        memory.write(0xFF70, 0x03);
+        memory.write(0xD000, 0x33);
+        assert_eq!(memory.wram[0x3000], 0x33);
+        assert_eq!(memory.read(0xF000), 0x33);
+        assert_eq!(memory.read(0xC000), 0x11);
+
+        // Bank 0 can't be mapped at 0xD000
+        memory.write(0xFF70, 0x00);
+        assert_eq!(memory.read(0xFF70), 0xF9);
+        assert_eq!(memory.read(0xD000), 0x00);
+    }
+
+    #[test]
+    fn test_cgb_registers_outside_cgb_mode() {
+        let mut memory = Memory::new();
+        memory.load_cartridge(&[0; 0x8000]).unwrap();
+        memory.set_post_boot_state();
+        memory.write_lcdc(0x00);
+
+        memory.write(0xFF4F, 0x01);
+        memory.write(0xFF70, 0x03);
+        memory.write(0xFF4D, 0x01);
+        assert_eq!(memory.read(0xFF4F), 0xFF);
+        assert_eq!(memory.read(0xFF70), 0xFF);
+        assert_eq!(memory.read(0xFF4D), 0xFF);
+
+        memory.write(0xD000, 0x11);
+        assert_eq!(memory.wram[0x1000], 0x11);
+        assert!(!memory.switch_speed());
+    }
+
+    #[test]
+    fn test_speed_switch() {
+        let mut memory = cgb_memory();
+        assert!(!memory.switch_speed());
+        memory.write(0xFF4D, 0x01);
+        assert_eq!(memory.read(0xFF4D), 0x7F);
+
+        assert!(memory.switch_speed());
+        assert_eq!(memory.read(0xFF4D), 0xFE);
+        assert_eq!(memory.read(0xFF04), 0x00);
+
+        memory.write(0xFF4D, 0x01);
+        assert!(memory.switch_speed());
+        assert_eq!(memory.read(0xFF4D), 0x7E);
+    }
+
+    #[test]
+    fn test_cgb_palette_ram() {
+        let mut memory = cgb_memory();
+        memory.write(0xFF68, 0x80 | 0x3E);
+        memory.write(0xFF69, 0x1F);
+        memory.write(0xFF69, 0x7C);
+        assert_eq!(memory.read(0xFF68), 0xC0, "Index wraps around");
+        assert_eq!(memory.palette_color(7, 3), 0x7C1F);
+
+        memory.write(0xFF6A, 0x02);
+        memory.write(0xFF6B, 0xE0);
+        memory.write(0xFF6B, 0x03);
+        assert_eq!(memory.read(0xFF6A), 0x42, "No auto-increment");
+        assert_eq!(memory.read(0xFF6B), 0x03);
+        assert_eq!(memory.palette_color(8, 1), 0x0003);
+    }
+
+    #[test]
+    fn test_cgb_palette_ram_locked_in_mode_3() {
+        let mut memory = cgb_memory();
+        memory.write(0xFF68, 0x80);
+        memory.lcd_stat = (memory.lcd_stat & !0b11) | 3;
+
+        memory.write(0xFF69, 0x12);
+        assert_eq!(memory.palette_ram[0], 0xFF);
+        assert_eq!(memory.read(0xFF69), 0xFF);
+        assert_eq!(memory.read(0xFF68), 0xC1, "Blocked writes still increment");
+    }
+
+    #[test]
+    fn test_cgb_palettes_outside_cgb_mode() {
+        let mut memory = Memory::with_model(Model::Cgb);
+        memory.load_cartridge(&[0; 0x8000]).unwrap();
+        memory.set_post_boot_state();
+        memory.write_lcdc(0x00);
+
+        memory.write(0xFF68, 0x80);
+        memory.write(0xFF69, 0x00);
+        assert_eq!(memory.read(0xFF68), 0xFF);
+        assert_eq!(memory.read(0xFF69), 0xFF);
+        assert_eq!(memory.palette_color(0, 0), 0x7FFF, "Boot ROM palette");
+        assert_eq!(memory.palette_color(8, 3), 0x0000);
+    }
+
+    /// MBC1 cartridge with the number of each ROM bank at offset `0x1000` in the bank
+    fn mbc1_cartridge(rom_size_code: u8, ram_size_code: u8) -> Vec<u8> {
+        let mut cartridge = vec![0; 0x8000 << rom_size_code];
+        for (bank, contents) in cartridge.chunks_exact_mut(0x4000).enumerate() {
+            contents[0x1000] = bank as u8;
+        }
+        cartridge[0x147] = 0x03;
+        cartridge[0x148] = rom_size_code;
+        cartridge[0x149] = ram_size_code;
+        cartridge
+    }
+
+    #[test]
+    fn test_mbc1_rom_banks() {
+        // 2 MiB, 128 banks
+        let mut memory = Memory::new();
+        memory.load_cartridge(&mbc1_cartridge(0x06, 0x00)).unwrap();
+
+        memory.write(0x2000, 0x00);
+        assert_eq!(memory.read(0x5000), 1, "Bank 0 selects bank 1");
+        memory.write(0x2000, 0x25);
+        memory.write(0x4000, 0x02);
+        assert_eq!(memory.read(0x5000), 0x45);
+        assert_eq!(memory.read(0x1000), 0, "Mode 0");
+
+        memory.write(0x6000, 0x01);
+        assert_eq!(memory.read(0x1000), 0x40, "Mode 1 switches the first bank");
+        assert_eq!(memory.read(0x5000), 0x45);
+        assert_eq!(memory.bank_of(0x1000), 0x40);
+        assert_eq!(memory.rom_offset(0x1000), 0x40 * 0x4000 + 0x1000);
+
+        memory.write(0x6000, 0x00);
+        assert_eq!(memory.read(0x1000), 0);
+    }
+
+    #[test]
+    fn test_mbc1_small_rom_and_ram_banks() {
+        // 256 KiB of ROM, 32 KiB of RAM
+        let mut memory = Memory::new();
+        memory.load_cartridge(&mbc1_cartridge(0x03, 0x03)).unwrap();
+        memory.write(0x0000, 0x0A);
+
+        memory.write(0x2000, 0x0D);
+        memory.write(0x4000, 0x01);
+        assert_eq!(
+            memory.read(0x5000),
+            0x0D,
+            "Upper bits wrap around on small ROMs"
+        );
+
+        memory.write(0xA000, 0x11);
+        memory.write(0x6000, 0x01);
+        memory.write(0xA000, 0x22);
+        assert_eq!(
+            memory.switchable_ram[1][0], 0x22,
+            "Mode 1 switches RAM banks"
+        );
+        memory.write(0x6000, 0x00);
+        assert_eq!(memory.read(0xA000), 0x11);
+    }
+
+    #[test]
+    fn test_load_cartridge_sizes() {
+        let mut memory = Memory::new();
+        let mut overdump = mbc1_cartridge(0x00, 0x00);
+        overdump.extend([0xFF; 0x100]);
+        memory.load_cartridge(&overdump).unwrap();
+        assert_eq!(memory.rom_size(), 0x8000, "Trailing bytes are ignored");
+
+        // Without RAM, reads are open bus and writes go nowhere
+        memory.write(0x0000, 0x0A);
+        memory.write(0xA000, 0x12);
+        assert_eq!(memory.read(0xA000), 0xFF);
+
+        let mut rom_only = [0; 0x8000];
+        rom_only[0x5000] = 0x01;
+        memory.load_cartridge(&rom_only).unwrap();
+        memory.write(0x2000, 0x02);
+        assert_eq!(
+            memory.read(0x5000),
+            0x01,
+            "ROM only cartridges have no bank switching"
+        );
+    }
 }
@@ -0,0 +1,40 @@
+use crate::cartridge_header::{CartridgeHeader, CgbSupport};
+
+/// Game Boy hardware revisions, which differ in the state the boot ROM leaves behind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    /// Original Game Boy with the early boot ROM
+    Dmg0,
+    /// Original Game Boy
+    #[default]
+    Dmg,
+    /// Game Boy Pocket
+    Mgb,
+    /// Super Game Boy
+    Sgb,
+    /// Game Boy Color
+    Cgb,
+}
+
+impl Model {
+    /// Parse a model from its lowercase name, eg. `dmg0` or `cgb`
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name {
+            "dmg0" => Some(Model::Dmg0),
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "cgb" => Some(Model::Cgb),
+            _ => None,
+        }
+    }
+
+    /// The model to run a cartridge on when none is chosen, the CGB for cartridges that support it
+    pub fn for_cartridge(header: &CartridgeHeader) -> Model {
+        if header.cgb_support == CgbSupport::None {
+            Model::Dmg
+        } else {
+            Model::Cgb
+        }
+    }
+}
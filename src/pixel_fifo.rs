@@ -0,0 +1,542 @@
+use crate::{
+    bg_attribute::BgAttribute,
+    memory::Memory,
+    ppu::{
+        get_row_from_tile, obj_over_bg, palette_shades, select_sprites, sprite_height, sprite_row,
+        SCREEN_WIDTH,
+    },
+    sprite_attribute::SpriteAttribute,
+    tile_info::TileType,
+};
+use std::collections::VecDeque;
+
+/// Dots the fetcher needs to fetch a sprite once the background fetch it interrupts is done
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    /// Waiting for the background FIFO to empty so the fetched row can be pushed
+    Push,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct BgPixel {
+    color: u8,
+    /// CGB palette (0-7)
+    palette: u8,
+    /// The CGB attributes give the tile priority over sprites
+    priority: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ObjPixel {
+    /// 0 is transparent
+    color: u8,
+    palette: u8,
+    cgb_palette: u8,
+    bg_over_obj: bool,
+    /// Order the OAM scan found the sprite in, which decides overlaps in CGB mode
+    oam_index: usize,
+}
+
+/// Dot by dot implementation of mode 3
+///
+/// A fetcher reads a row of 8 background or window pixels every 6 dots and pushes it into the
+/// background FIFO once that is empty, and one pixel is shifted out to the LCD every dot the FIFO
+/// isn't empty. The length of mode 3 comes out of how long that takes: pixels discarded for the
+/// fine scroll of SCX, restarting the fetcher when the window starts, and pausing to fetch sprites
+/// all add dots on top of the 172 of a plain line.
+#[derive(Debug)]
+pub struct PixelFifo {
+    bg_fifo: VecDeque<BgPixel>,
+    /// Sprite pixels lined up with the next pixels of `bg_fifo`
+    obj_fifo: VecDeque<ObjPixel>,
+    fetch_step: FetchStep,
+    /// Dots spent in the current fetch step
+    fetch_dots: u8,
+    /// Tile column the fetcher reads next, relative to the start of the background or window
+    fetch_x: u8,
+    tile_index: u8,
+    tile_attribute: BgAttribute,
+    tile_row: [u8; 8],
+    /// The first fetch of every line is thrown away
+    first_fetch: bool,
+    /// Dots left in the sprite fetch that is stalling the pipeline
+    sprite_fetch_dots: Option<u8>,
+    /// Background pixels left to throw away at the start of the line
+    discard: u8,
+    /// Next pixel of the line to output
+    x: u8,
+    ly: u8,
+    /// Sprites on the line with the order the OAM scan found them in
+    sprites: Vec<(usize, SpriteAttribute)>,
+    /// Index into `sprites` of the next sprite to fetch, they are sorted by X
+    next_sprite: usize,
+    /// The fetcher switched to the window on this line
+    window_active: bool,
+    window_line: u8,
+    window_y_triggered: bool,
+    /// Shade (0-3) of each pixel of the line being drawn, or its color id in CGB mode
+    line: [u8; SCREEN_WIDTH],
+    /// CGB palette of each pixel of the line being drawn, 0-7 for BG palettes and 8-15 for OBJ
+    /// palettes
+    palettes: [u8; SCREEN_WIDTH],
+}
+
+impl PixelFifo {
+    pub fn new() -> PixelFifo {
+        PixelFifo {
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(16),
+            fetch_step: FetchStep::Tile,
+            fetch_dots: 0,
+            fetch_x: 0,
+            tile_index: 0,
+            tile_attribute: BgAttribute::default(),
+            tile_row: [0; 8],
+            first_fetch: true,
+            sprite_fetch_dots: None,
+            discard: 0,
+            x: 0,
+            ly: 0,
+            sprites: Vec::with_capacity(10),
+            next_sprite: 0,
+            window_active: false,
+            window_line: 0,
+            window_y_triggered: false,
+            line: [0; SCREEN_WIDTH],
+            palettes: [0; SCREEN_WIDTH],
+        }
+    }
+
+    /// Returns the shades of the last line drawn, or the line being drawn
+    pub fn line(&self) -> &[u8; SCREEN_WIDTH] {
+        &self.line
+    }
+
+    /// Returns the CGB palette of each pixel of `line()`
+    pub fn palettes(&self) -> &[u8; SCREEN_WIDTH] {
+        &self.palettes
+    }
+
+    /// Start mode 3 of line `ly`, after the OAM scan
+    pub fn start_line(&mut self, memory: &Memory, ly: u8) {
+        if ly == 0 {
+            self.window_line = 0;
+            self.window_y_triggered = false;
+        }
+
+        if ly == memory.wy {
+            self.window_y_triggered = true;
+        }
+
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.fetch_step = FetchStep::Tile;
+        self.fetch_dots = 0;
+        self.fetch_x = 0;
+        self.first_fetch = true;
+        self.sprite_fetch_dots = None;
+        self.discard = memory.scx % 8;
+        self.x = 0;
+        self.ly = ly;
+        self.window_active = false;
+
+        let oam = memory.read_oam();
+        let height = sprite_height(memory);
+        self.sprites.clear();
+        self.sprites.extend(
+            select_sprites(&oam, ly as usize, height)
+                .into_iter()
+                .copied()
+                .enumerate(),
+        );
+        // Stable, so sprites with the same X stay in OAM order
+        self.sprites.sort_by_key(|(_, sprite)| sprite.x);
+        self.next_sprite = 0;
+    }
+
+    /// Advance mode 3 by one dot, returns true once the whole line has been output
+    pub fn step(&mut self, memory: &Memory) -> bool {
+        let lcdc = memory.io_registers[0x40];
+
+        if self.sprite_fetch_dots.is_none() {
+            if !self.window_active && self.window_starts(memory) {
+                // The fetcher restarts from the first tile of the window, throwing away the background
+                self.window_active = true;
+                self.bg_fifo.clear();
+                self.fetch_step = FetchStep::Tile;
+                self.fetch_dots = 0;
+                self.fetch_x = 0;
+                // WX below 7 cuts off the first pixels of the window instead
+                self.discard = 7u8.saturating_sub(memory.wx);
+
+                self.step_fetcher(memory);
+                return false;
+            }
+
+            if lcdc & 0b0000_0010 != 0 && self.sprite_starts() {
+                // A background fetch in progress has to finish before the sprite can be fetched
+                let fetcher_idle = self.fetch_step == FetchStep::Push
+                    || (self.fetch_step == FetchStep::Tile && self.fetch_dots == 0);
+
+                if fetcher_idle && !self.bg_fifo.is_empty() {
+                    self.sprite_fetch_dots = Some(SPRITE_FETCH_DOTS);
+                } else {
+                    self.step_fetcher(memory);
+                    return false;
+                }
+            }
+        }
+
+        // Nothing is output while a sprite is being fetched
+        if let Some(dots) = self.sprite_fetch_dots {
+            if dots > 1 {
+                self.sprite_fetch_dots = Some(dots - 1);
+            } else {
+                self.sprite_fetch_dots = None;
+                self.fetch_sprite(memory);
+            }
+            return false;
+        }
+
+        self.shift_out_pixel(memory);
+        self.step_fetcher(memory);
+
+        if self.x as usize == SCREEN_WIDTH {
+            if self.window_active {
+                self.window_line = self.window_line.wrapping_add(1);
+            }
+            return true;
+        }
+
+        false
+    }
+
+    fn window_starts(&self, memory: &Memory) -> bool {
+        let lcdc = memory.io_registers[0x40];
+        // In CGB mode LCDC bit 0 only takes away background priority, the window is still drawn
+        let background_enabled = lcdc & 0b0000_0001 != 0 || memory.cgb_mode();
+
+        lcdc & 0b0010_0000 != 0
+            && background_enabled
+            && self.window_y_triggered
+            && memory.wx <= 166
+            && self.x as u16 + 7 >= memory.wx as u16
+    }
+
+    /// Returns true if the next sprite starts at the pixel about to be output
+    fn sprite_starts(&self) -> bool {
+        self.sprites
+            .get(self.next_sprite)
+            .is_some_and(|(_, sprite)| sprite.x as i32 - 8 <= self.x as i32)
+    }
+
+    fn shift_out_pixel(&mut self, memory: &Memory) {
+        let Some(bg) = self.bg_fifo.pop_front() else {
+            return;
+        };
+
+        // Sprites line up with the screen, so they aren't affected by discarded pixels
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+
+        let obj = self.obj_fifo.pop_front().unwrap_or_default();
+
+        let lcdc = memory.io_registers[0x40];
+        let cgb_mode = memory.cgb_mode();
+        // On DMG LCDC bit 0 blanks both the background and the window
+        let bg_color = if lcdc & 0b0000_0001 == 0 && !cgb_mode {
+            0
+        } else {
+            bg.color
+        };
+
+        let (shade, palette) =
+            if obj.color != 0 && obj_over_bg(memory, obj.bg_over_obj, bg_color, bg.priority) {
+                if cgb_mode {
+                    (obj.color, 8 + obj.cgb_palette)
+                } else {
+                    let register = if obj.palette == 0 { 0x48 } else { 0x49 };
+                    let shades = palette_shades(memory.io_registers[register]);
+                    (shades[obj.color as usize], 8 + obj.palette)
+                }
+            } else if cgb_mode {
+                (bg_color, bg.palette)
+            } else {
+                (
+                    palette_shades(memory.io_registers[0x47])[bg_color as usize],
+                    0,
+                )
+            };
+
+        self.line[self.x as usize] = shade;
+        self.palettes[self.x as usize] = palette;
+        self.x += 1;
+    }
+
+    fn step_fetcher(&mut self, memory: &Memory) {
+        if self.fetch_step == FetchStep::Push {
+            self.push_tile_row();
+            return;
+        }
+
+        self.fetch_dots += 1;
+        if self.fetch_dots < 2 {
+            return;
+        }
+        self.fetch_dots = 0;
+
+        match self.fetch_step {
+            FetchStep::Tile => {
+                let map_offset = self.tile_map_offset(memory);
+                self.tile_index = memory.vram[map_offset];
+                // The attributes sit in VRAM bank 1 at the same address as the tile index
+                self.tile_attribute = if memory.cgb_mode() {
+                    BgAttribute::new(memory.vram[0x2000 + map_offset])
+                } else {
+                    BgAttribute::default()
+                };
+                self.fetch_step = FetchStep::DataLow;
+            }
+            FetchStep::DataLow => self.fetch_step = FetchStep::DataHigh,
+            FetchStep::DataHigh => {
+                self.tile_row = self.fetch_tile_row(memory);
+
+                if self.first_fetch {
+                    self.first_fetch = false;
+                    self.fetch_step = FetchStep::Tile;
+                } else {
+                    self.fetch_step = FetchStep::Push;
+                    self.push_tile_row();
+                }
+            }
+            FetchStep::Push => {}
+        }
+    }
+
+    fn push_tile_row(&mut self) {
+        if !self.bg_fifo.is_empty() {
+            return;
+        }
+
+        let attribute = self.tile_attribute;
+        self.bg_fifo
+            .extend(self.tile_row.iter().map(|color| BgPixel {
+                color: *color,
+                palette: attribute.palette,
+                priority: attribute.priority,
+            }));
+        self.fetch_x = self.fetch_x.wrapping_add(1);
+        self.fetch_step = FetchStep::Tile;
+    }
+
+    /// Returns the offset into VRAM of the tile map entry the fetcher reads next
+    fn tile_map_offset(&self, memory: &Memory) -> usize {
+        let lcdc = memory.io_registers[0x40];
+
+        let (map_bit, column, row) = if self.window_active {
+            (0b0100_0000, self.fetch_x, self.window_line / 8)
+        } else {
+            (
+                0b0000_1000,
+                (memory.scx / 8).wrapping_add(self.fetch_x),
+                memory.scy.wrapping_add(self.ly) / 8,
+            )
+        };
+
+        let map = if lcdc & map_bit != 0 { 0x1C00 } else { 0x1800 };
+        map + (row as usize % 32) * 32 + (column as usize % 32)
+    }
+
+    fn fetch_tile_row(&self, memory: &Memory) -> [u8; 8] {
+        let (tile_type, row) = if self.window_active {
+            (TileType::Window, self.window_line % 8)
+        } else {
+            (TileType::Background, memory.scy.wrapping_add(self.ly) % 8)
+        };
+
+        let attribute = self.tile_attribute;
+        let row = if attribute.y_flip { 7 - row } else { row };
+
+        let tile = memory.vram_read_tile(tile_type, self.tile_index, attribute.bank);
+        let mut colors = get_row_from_tile(tile, row as i32);
+        if attribute.x_flip {
+            colors.reverse();
+        }
+        colors
+    }
+
+    /// Mix the row of the next sprite into the sprite FIFO
+    ///
+    /// On DMG sprites are fetched in priority order, so pixels already in the FIFO win over later
+    /// ones. In CGB mode the sprite first in OAM wins instead.
+    fn fetch_sprite(&mut self, memory: &Memory) {
+        let cgb_mode = memory.cgb_mode();
+        let (oam_index, sprite) = self.sprites[self.next_sprite];
+        self.next_sprite += 1;
+
+        let height = sprite_height(memory);
+        let row = sprite_row(memory, &sprite, self.ly as usize, height);
+
+        while self.obj_fifo.len() < 8 {
+            self.obj_fifo.push_back(ObjPixel::default());
+        }
+
+        for (col, color) in row.iter().enumerate() {
+            // Pixels left of the current position were already output, or are off screen
+            let offset = sprite.x as i32 - 8 + col as i32 - self.x as i32;
+            if offset < 0 {
+                continue;
+            }
+
+            let pixel = &mut self.obj_fifo[offset as usize];
+            if pixel.color == 0 || (cgb_mode && *color != 0 && oam_index < pixel.oam_index) {
+                *pixel = ObjPixel {
+                    color: *color,
+                    palette: sprite.palette,
+                    cgb_palette: sprite.cgb_palette,
+                    bg_over_obj: sprite.bg_over_obj,
+                    oam_index,
+                };
+            }
+        }
+    }
+}
+
+impl Default for PixelFifo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the number of dots mode 3 takes for line 0
+    fn mode_3_length(memory: &Memory) -> u32 {
+        let mut fifo = PixelFifo::new();
+        fifo.start_line(memory, 0);
+
+        let mut dots = 1;
+        while !fifo.step(memory) {
+            dots += 1;
+            assert!(dots < 400, "Line never finished");
+        }
+        dots
+    }
+
+    fn lcd_memory() -> Memory {
+        let mut memory = Memory::new();
+        memory.io_registers[0x40] = 0b1001_0011;
+        memory.io_registers[0x47] = 0b1110_0100;
+        memory.io_registers[0x48] = 0b1110_0100;
+        memory
+    }
+
+    #[test]
+    fn test_mode_3_length() {
+        let mut memory = lcd_memory();
+        assert_eq!(mode_3_length(&memory), 172);
+
+        memory.scx = 3;
+        assert_eq!(mode_3_length(&memory), 175, "Fine scroll");
+
+        memory.scx = 0;
+        memory.io_registers[0x40] |= 0b0010_0000;
+        memory.wx = 87;
+        assert_eq!(mode_3_length(&memory), 178, "Window");
+
+        memory.io_registers[0x40] &= !0b0010_0000;
+        memory.sprite_attribute_table[0..4].copy_from_slice(&[16, 88, 0, 0]);
+        let length = mode_3_length(&memory);
+        assert!((178..=183).contains(&length), "Sprite: {}", length);
+    }
+
+    #[test]
+    fn test_matches_scanline_renderer() {
+        let mut memory = lcd_memory();
+        // Tile 1 has colors 1 and 2 in alternating columns, tile 2 is color 3
+        for row in 0..8 {
+            memory.vram[0x10 + row * 2] = 0b1010_1010;
+            memory.vram[0x11 + row * 2] = 0b0101_0101;
+            memory.vram[0x20 + row * 2] = 0xFF;
+            memory.vram[0x21 + row * 2] = 0xFF;
+        }
+        for column in (0..32).step_by(3) {
+            memory.vram[0x1800 + column] = 1;
+        }
+        memory.scx = 5;
+        memory.sprite_attribute_table[0..8].copy_from_slice(&[16, 30, 2, 0, 16, 90, 2, 0x20]);
+
+        let mut fifo = PixelFifo::new();
+        fifo.start_line(&memory, 0);
+        while !fifo.step(&memory) {}
+
+        let mut ppu = crate::ppu::Ppu::new();
+        ppu.render_line(&memory, 0);
+
+        assert_eq!(fifo.line()[..], ppu.framebuffer()[..SCREEN_WIDTH]);
+    }
+
+    #[test]
+    fn test_matches_scanline_renderer_in_cgb_mode() {
+        let mut memory = Memory::with_model(crate::model::Model::Cgb);
+        memory.io_registers[0x40] = 0b1001_0011;
+        // Tile 1 has colors 1 and 2 in alternating columns in bank 0 and is color 3 in bank 1
+        for row in 0..8 {
+            memory.vram[0x10 + row * 2] = 0b1010_1010;
+            memory.vram[0x11 + row * 2] = 0b0101_0101;
+            memory.vram[0x2010 + row * 2] = 0xFF;
+            memory.vram[0x2011 + row * 2] = 0xFF;
+        }
+        memory.vram[0x1800..0x1820].fill(1);
+        // Alternate between X flip with palette 1, bank 1 with BG priority and palette 2, and
+        // plain tiles
+        for column in 0..32 {
+            memory.vram[0x3800 + column] = [0x21, 0x8A, 0x00][column % 3];
+        }
+        memory.scx = 5;
+        // Overlapping sprites where OAM order and X order disagree
+        memory.sprite_attribute_table[0..12]
+            .copy_from_slice(&[16, 34, 1, 0x03, 16, 30, 1, 0x20, 16, 90, 1, 0x85]);
+        // Every color of every palette is different
+        for (index, byte) in memory.palette_ram.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+
+        assert_matches_scanline_renderer_in_color(&memory);
+
+        // LCDC bit 0 doesn't hide the window in CGB mode, so it still lengthens mode 3
+        let length = mode_3_length(&memory);
+        memory.io_registers[0x40] = 0b1011_0010;
+        memory.wx = 87;
+        assert!(
+            mode_3_length(&memory) > length,
+            "Window with LCDC bit 0 clear"
+        );
+        assert_matches_scanline_renderer_in_color(&memory);
+    }
+
+    /// Draw line 0 with both renderers and compare the 15-bit colors they output
+    fn assert_matches_scanline_renderer_in_color(memory: &Memory) {
+        let mut fifo = PixelFifo::new();
+        fifo.start_line(memory, 0);
+        while !fifo.step(memory) {}
+
+        let mut ppu = crate::ppu::Ppu::new();
+        ppu.render_line(memory, 0);
+
+        assert_eq!(fifo.line()[..], ppu.framebuffer()[..SCREEN_WIDTH]);
+        let colors: Vec<u16> = (0..SCREEN_WIDTH)
+            .map(|x| memory.palette_color(fifo.palettes()[x], fifo.line()[x]))
+            .collect();
+        assert_eq!(colors[..], ppu.rgb_framebuffer()[..SCREEN_WIDTH]);
+    }
+}
@@ -1,114 +1,426 @@
-use sdl2::rect::Rect;
-
 use crate::{
+    bg_attribute::BgAttribute,
     memory::Memory,
+    model::Model,
+    sprite_attribute::SpriteAttribute,
     tile_info::{TileInfo, TileType},
+    util::get_as_bits,
 };
 
-#[derive(Debug)]
-pub struct Ppu {
-    pub pixel_width: u32,
-    pub pixel_height: u32,
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/// 15-bit RGB white, the color of the CGB screen while the LCD is off
+const WHITE: u16 = 0x7FFF;
+
+/// Most sprites the OAM scan selects for a single scanline
+pub const SPRITES_PER_LINE: usize = 10;
+
+/// How lines are drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Renderer {
+    /// Draw each line in one go when mode 3 starts, with a fixed mode 3 length
+    #[default]
+    Scanline,
+    /// Draw each line a dot at a time with `PixelFifo`, slower but mode 3 takes as long as on
+    /// hardware
+    PixelFifo,
 }
 
-#[derive(Debug, Default)]
-pub struct ColorRects {
-    pub color0_rects: Vec<Rect>,
-    pub color1_rects: Vec<Rect>,
-    pub color2_rects: Vec<Rect>,
-    pub color3_rects: Vec<Rect>,
-    pub transparent_rects: Vec<Rect>,
+#[derive(Debug)]
+pub struct Ppu {
+    /// Shade (0-3) of each pixel after applying the palettes, stored row by row
+    /// * In CGB mode this is the color id (0-3) in the CGB palette instead
+    framebuffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    /// 15-bit RGB color of each pixel on the CGB, stored row by row
+    rgb_framebuffer: [u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+    /// Color id (0-3) of the background or window on the line being drawn, before the palette
+    bg_color_ids: [u8; SCREEN_WIDTH],
+    /// The background or window has priority over sprites through its CGB attributes
+    bg_priority: [bool; SCREEN_WIDTH],
+    /// CGB palette of each pixel of the line being drawn, 0-7 for BG palettes and 8-15 for OBJ
+    /// palettes
+    line_palettes: [u8; SCREEN_WIDTH],
+    /// Row of the window to draw next, only advances on lines where the window was drawn
+    window_line: u8,
+    /// LY has matched WY during this frame, the window can only be drawn after this
+    window_y_triggered: bool,
 }
 
 impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu {
+            framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            rgb_framebuffer: [WHITE; SCREEN_WIDTH * SCREEN_HEIGHT],
+            bg_color_ids: [0; SCREEN_WIDTH],
+            bg_priority: [false; SCREEN_WIDTH],
+            line_palettes: [0; SCREEN_WIDTH],
+            window_line: 0,
+            window_y_triggered: false,
+        }
+    }
+
+    /// Returns the shade (0-3) of each pixel of the last rendered frame, stored row by row
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// Returns the 15-bit RGB color of each pixel of the last rendered frame, stored row by row
+    ///
+    /// Only drawn on the CGB, where colors come from palette RAM
+    pub fn rgb_framebuffer(&self) -> &[u16] {
+        &self.rgb_framebuffer
+    }
+
+    /// Blank the screen, for frames where the LCD wasn't outputting anything
+    pub fn clear(&mut self) {
+        self.framebuffer.fill(0);
+        self.rgb_framebuffer.fill(WHITE);
+    }
+
+    /// Render every line of a frame using the current state of `memory`
+    pub fn render_frame(&mut self, memory: &Memory) {
+        // Nothing is output while the LCD is off or during the first frame after it is turned on
+        if memory.frame_blank() {
+            self.clear();
+            return;
+        }
+
+        for y in 0..SCREEN_HEIGHT {
+            self.render_line(memory, y);
+        }
+    }
+
+    /// Render line `y` using the current state of `memory`
+    ///
+    /// Lines are expected in order once per frame, starting from 0, so changes to the
+    /// registers between lines show up on screen
+    pub fn render_line(&mut self, memory: &Memory, y: usize) {
+        // The pixel FIFO already drew the line while mode 3 ran
+        if let Some(pixel_fifo) = memory.pixel_fifo() {
+            self.framebuffer[(y * SCREEN_WIDTH)..((y + 1) * SCREEN_WIDTH)]
+                .copy_from_slice(pixel_fifo.line());
+            self.line_palettes.copy_from_slice(pixel_fifo.palettes());
+            self.render_rgb_line(memory, y);
+            return;
+        }
+
+        let lcdc = memory.io_registers[0x40];
+        let cgb_mode = memory.cgb_mode();
+
+        if y == 0 {
+            self.window_line = 0;
+            self.window_y_triggered = false;
+        }
+
+        // WY is only compared at the start of each line, so moving it to a line that has already
+        // passed keeps the window hidden until the next frame
+        if y == memory.wy as usize {
+            self.window_y_triggered = true;
+        }
+
+        // CGB mode ignores BGP, the color ids pick colors from the CGB palettes directly
+        let color_values = if cgb_mode {
+            [0, 1, 2, 3]
+        } else {
+            palette_shades(memory.io_registers[0x47])
+        };
+
+        // On DMG LCDC bit 0 blanks both the background and the window, sprites are still drawn.
+        // In CGB mode it only takes away their priority over sprites.
+        if lcdc & 0b0000_0001 == 0 && !cgb_mode {
+            self.framebuffer[(y * SCREEN_WIDTH)..((y + 1) * SCREEN_WIDTH)].fill(0);
+            self.bg_color_ids.fill(0);
+            self.bg_priority.fill(false);
+            self.line_palettes.fill(0);
+        } else {
+            self.render_scanline(memory, y, &memory.read_bg_tile_map(), &color_values);
+
+            // Check LCDC bit to see if window should be displayed or not
+            // Only draw if the window is actually visible
+            if lcdc & 0b0010_0000 == 0b0010_0000 && self.window_y_triggered && memory.wx <= 166 {
+                let tilemap = memory.read_window_tile_map();
+                self.render_window_scanline(memory, y, &tilemap, &color_values);
+                self.window_line = self.window_line.wrapping_add(1);
+            }
+        }
+
+        if lcdc & 0b0000_0010 == 0b0000_0010 {
+            self.render_sprite_scanline(memory, y, &memory.read_oam());
+        }
+
+        self.render_rgb_line(memory, y);
+    }
+
+    /// Look up the colors of line `y` in palette RAM, on the CGB
+    ///
+    /// In DMG compatibility mode the shades from BGP, OBP0 and OBP1 pick the color instead of the
+    /// color ids
+    fn render_rgb_line(&mut self, memory: &Memory, y: usize) {
+        if memory.model() != Model::Cgb {
+            return;
+        }
+
+        let start = y * SCREEN_WIDTH;
+        for (x, palette) in self.line_palettes.iter().enumerate() {
+            self.rgb_framebuffer[start + x] =
+                memory.palette_color(*palette, self.framebuffer[start + x]);
+        }
+    }
+
     pub fn render_scanline(
-        &self,
+        &mut self,
         memory: &Memory,
         y: usize,
         tilemap: &[[u8; 32]; 32],
         color_values: &[u8; 4],
-        color_rects: &mut ColorRects,
     ) {
+        let attribute_map = memory.cgb_mode().then(|| memory.read_bg_attribute_map());
+
         // Render an extra tile for smooth scrolling
         for x in 0..21 {
-            let tile = memory.vram_read_tile(
-                TileType::Background,
-                tilemap[((memory.scy as usize + y) / 8) % 32]
-                    [((memory.scx as usize + (8 * x)) / 8) % 32],
-            );
+            let row = ((memory.scy as usize + y) / 8) % 32;
+            let column = ((memory.scx as usize + (8 * x)) / 8) % 32;
+            let attribute = attribute_map
+                .map(|attributes| BgAttribute::new(attributes[row][column]))
+                .unwrap_or_default();
+            let tile =
+                memory.vram_read_tile(TileType::Background, tilemap[row][column], attribute.bank);
 
             let x_pos = x as i32 * 8;
             let x_offset = memory.scx as i32 % 8;
 
-            self.get_rects_for_tile(
+            self.draw_tile_row(
                 tile,
                 x_pos - x_offset,
                 y as i32,
                 memory.scy as i32 % 8,
                 color_values,
-                color_rects,
+                attribute,
             );
         }
     }
 
+    /// Draw the window over the background from WX - 7 to the end of line `y`
+    ///
+    /// WX values below 7 start the window off screen, cutting off its first pixels
     pub fn render_window_scanline(
-        &self,
+        &mut self,
         memory: &Memory,
         y: usize,
         tilemap: &[[u8; 32]; 32],
         color_values: &[u8; 4],
-        color_rects: &mut ColorRects,
     ) {
-        let y_tile_index = if y >= memory.wy as usize {
-            y - memory.wy as usize
-        } else {
-            0
-        };
+        let window_x = memory.wx as i32 - 7;
+        let tile_count = (SCREEN_WIDTH as i32 - window_x + 7) / 8;
+        // Offset that makes draw_tile_row pick the row of the window line instead of line `y`
+        let y_offset = self.window_line as i32 - y as i32;
+        let row = (self.window_line as usize / 8) % 32;
+        let attribute_map = memory
+            .cgb_mode()
+            .then(|| memory.read_window_attribute_map());
 
-        for x in 0..20 {
-            let tile = memory.vram_read_tile(TileType::Window, tilemap[(y_tile_index / 8) % 32][x]);
+        for (x, tile_index) in tilemap[row].iter().take(tile_count as usize).enumerate() {
+            let attribute = attribute_map
+                .map(|attributes| BgAttribute::new(attributes[row][x]))
+                .unwrap_or_default();
+            let tile = memory.vram_read_tile(TileType::Window, *tile_index, attribute.bank);
 
-            let x_pos = x as i32 * 8;
+            let x_pos = window_x + x as i32 * 8;
+
+            self.draw_tile_row(tile, x_pos, y as i32, y_offset, color_values, attribute);
+        }
+    }
+
+    /// Draw the sprites on line `y` over the background already drawn there
+    ///
+    /// On DMG the sprite with the lowest X wins where sprites overlap, with ties going to the
+    /// sprite first in OAM, while in CGB mode the sprite first in OAM always wins. The winning
+    /// pixel is then hidden behind background colors 1-3 if the sprite has its BG over OBJ flag
+    /// set, even if another sprite below it would have shown.
+    fn render_sprite_scanline(&mut self, memory: &Memory, y: usize, oam: &[SpriteAttribute]) {
+        let cgb_mode = memory.cgb_mode();
+        let height = sprite_height(memory);
+        let mut sprites = select_sprites(oam, y, height);
+        if !cgb_mode {
+            // Stable, so sprites with the same X stay in OAM order
+            sprites.sort_by_key(|sprite| sprite.x);
+        }
 
-            self.get_rects_for_tile(tile, x_pos, y as i32, 0, color_values, color_rects);
+        let rows: Vec<[u8; 8]> = sprites
+            .iter()
+            .map(|sprite| sprite_row(memory, sprite, y, height))
+            .collect();
+
+        let shades = [
+            palette_shades(memory.io_registers[0x48]),
+            palette_shades(memory.io_registers[0x49]),
+        ];
+
+        for x in 0..SCREEN_WIDTH {
+            // Sprites are placed with an 8 pixel offset, so x == 0 hides the sprite completely
+            let pixel = sprites.iter().zip(rows.iter()).find_map(|(sprite, row)| {
+                let col = x as i32 + 8 - sprite.x as i32;
+                if !(0..8).contains(&col) {
+                    return None;
+                }
+
+                // Color 0 is transparent for sprites, letting lower priority sprites show through
+                let color = row[col as usize];
+                (color != 0).then_some((sprite, color))
+            });
+
+            if let Some((sprite, color)) = pixel {
+                if obj_over_bg(
+                    memory,
+                    sprite.bg_over_obj,
+                    self.bg_color_ids[x],
+                    self.bg_priority[x],
+                ) {
+                    if cgb_mode {
+                        self.set_pixel(x as i32, y as i32, color, 8 + sprite.cgb_palette);
+                    } else {
+                        let shade = shades[sprite.palette as usize][color as usize];
+                        self.set_pixel(x as i32, y as i32, shade, 8 + sprite.palette);
+                    }
+                }
+            }
         }
     }
 
-    fn get_rects_for_tile(
-        &self,
+    fn draw_tile_row(
+        &mut self,
         tile: TileInfo,
         tile_start: i32,
         line: i32,
         y_offset: i32,
         color_values: &[u8; 4],
-        color_rects: &mut ColorRects,
+        attribute: BgAttribute,
     ) {
-        let line_colors = get_row_from_tile(tile, line + y_offset);
-
-        for col in 0..line_colors.len() {
-            let rect = Rect::new(
-                (tile_start + col as i32) * self.pixel_width as i32,
-                line * self.pixel_height as i32,
-                self.pixel_width,
-                self.pixel_height,
-            );
+        let mut row = line + y_offset;
+        if attribute.y_flip {
+            row = 7 - row % 8;
+        }
+
+        let mut line_colors = get_row_from_tile(tile, row);
+        if attribute.x_flip {
+            line_colors.reverse();
+        }
 
-            let palette = color_values[line_colors[col] as usize];
+        for (col, color) in line_colors.iter().enumerate() {
+            let x = tile_start + col as i32;
+            self.set_pixel(x, line, color_values[*color as usize], attribute.palette);
 
-            if palette == 0 {
-                color_rects.color0_rects.push(rect);
-            } else if palette == 1 {
-                color_rects.color1_rects.push(rect);
-            } else if palette == 2 {
-                color_rects.color2_rects.push(rect);
-            } else {
-                color_rects.color3_rects.push(rect);
+            if (0..SCREEN_WIDTH as i32).contains(&x) {
+                self.bg_color_ids[x as usize] = *color;
+                self.bg_priority[x as usize] = attribute.priority;
             }
         }
     }
+
+    /// Set a pixel of the framebuffer and the CGB palette it uses, ignoring pixels that are off
+    /// screen
+    fn set_pixel(&mut self, x: i32, y: i32, shade: u8, palette: u8) {
+        if (0..SCREEN_WIDTH as i32).contains(&x) && (0..SCREEN_HEIGHT as i32).contains(&y) {
+            self.framebuffer[y as usize * SCREEN_WIDTH + x as usize] = shade;
+            self.line_palettes[x as usize] = palette;
+        }
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns true if a sprite pixel is drawn over a background or window pixel of color `bg_color`
+///
+/// Sprites with the BG over OBJ flag set are hidden behind background colors 1-3, as are all
+/// sprites where the CGB attributes give the background priority. In CGB mode LCDC bit 0 clears
+/// to put every sprite on top.
+pub(crate) fn obj_over_bg(
+    memory: &Memory,
+    bg_over_obj: bool,
+    bg_color: u8,
+    bg_priority: bool,
+) -> bool {
+    if memory.cgb_mode() && memory.io_registers[0x40] & 0b0000_0001 == 0 {
+        return true;
+    }
+
+    bg_color == 0 || !(bg_over_obj || bg_priority)
+}
+
+/// Returns the shade each color id maps to in a palette register
+pub(crate) fn palette_shades(palette: u8) -> [u8; 4] {
+    let palette_bits = get_as_bits(palette);
+
+    [
+        (palette_bits[6] << 1) + palette_bits[7],
+        (palette_bits[4] << 1) + palette_bits[5],
+        (palette_bits[2] << 1) + palette_bits[3],
+        (palette_bits[0] << 1) + palette_bits[1],
+    ]
+}
+
+/// Returns the height of sprites in pixels, selected by LCDC bit 2
+pub(crate) fn sprite_height(memory: &Memory) -> u8 {
+    if memory.io_registers[0x40] & 0b0000_0100 == 0b0000_0100 {
+        16
+    } else {
+        8
+    }
 }
 
-fn get_row_from_tile(tile: TileInfo, line: i32) -> [u8; 8] {
+/// OAM scan: returns the first sprites in OAM that cover line `y`, up to `SPRITES_PER_LINE`
+///
+/// X isn't checked, so sprites that are off screen horizontally still count towards the limit
+pub(crate) fn select_sprites(
+    oam: &[SpriteAttribute],
+    y: usize,
+    height: u8,
+) -> Vec<&SpriteAttribute> {
+    oam.iter()
+        .filter(|sprite| {
+            let top = sprite.y as i32 - 16;
+            (top..(top + height as i32)).contains(&(y as i32))
+        })
+        .take(SPRITES_PER_LINE)
+        .collect()
+}
+
+/// Returns the color ids of the row of `sprite` that is drawn on line `y`, flipped as needed
+pub(crate) fn sprite_row(
+    memory: &Memory,
+    sprite: &SpriteAttribute,
+    y: usize,
+    height: u8,
+) -> [u8; 8] {
+    let mut row = (y as i32 - (sprite.y as i32 - 16)) as u8;
+    if sprite.y_flip {
+        row = height - 1 - row;
+    }
+
+    // 8x16 sprites use an even tile for the top half and the next tile for the bottom
+    let index = if height == 16 {
+        (sprite.index & 0xFE) + row / 8
+    } else {
+        sprite.index
+    };
+
+    let bank = if memory.cgb_mode() { sprite.bank } else { 0 };
+    let tile = memory.vram_read_tile(TileType::Obj, index, bank);
+    let mut colors = get_row_from_tile(tile, row as i32);
+
+    if sprite.x_flip {
+        colors.reverse();
+    }
+
+    colors
+}
+
+pub(crate) fn get_row_from_tile(tile: TileInfo, line: i32) -> [u8; 8] {
     let colors = tile.get_color_ids_from_tile();
 
     colors[(line as usize % 8)]
@@ -169,38 +481,280 @@ mod tests {
     }
 
     #[test]
-    fn test_get_rects_for_tile_line_0() {
-        let ppu = Ppu {
-            pixel_width: 2,
-            pixel_height: 2,
-        };
-        let mut color_rects = ColorRects::default();
-        ppu.get_rects_for_tile(BASIC_TILE, 16, 16, 0, &[0, 1, 2, 3], &mut color_rects);
+    fn test_draw_tile_row_line_0() {
+        let mut ppu = Ppu::new();
+        ppu.draw_tile_row(BASIC_TILE, 16, 16, 0, &[0, 1, 2, 3], BgAttribute::default());
 
-        assert_eq!(
-            vec![
-                Rect::new(16 * 2, 16 * 2, 2, 2),
-                Rect::new(23 * 2, 16 * 2, 2, 2)
-            ],
-            color_rects.color0_rects
-        )
+        let start = 16 * SCREEN_WIDTH + 16;
+        assert_eq!(BASIC_TILE_COLORS[0], ppu.framebuffer()[start..(start + 8)])
     }
 
     #[test]
-    fn test_get_rects_for_tile_line_5() {
-        let ppu = Ppu {
-            pixel_width: 2,
-            pixel_height: 2,
-        };
-        let mut color_rects = ColorRects::default();
-        ppu.get_rects_for_tile(BASIC_TILE, 16, 20, 0, &[0, 1, 2, 3], &mut color_rects);
-
-        assert_eq!(
-            vec![
-                Rect::new(16 * 2, 20 * 2, 2, 2),
-                Rect::new(23 * 2, 20 * 2, 2, 2)
-            ],
-            color_rects.color0_rects
-        )
+    fn test_draw_tile_row_line_5() {
+        let mut ppu = Ppu::new();
+        ppu.draw_tile_row(BASIC_TILE, 16, 20, 0, &[0, 1, 2, 3], BgAttribute::default());
+
+        let start = 20 * SCREEN_WIDTH + 16;
+        assert_eq!(BASIC_TILE_COLORS[4], ppu.framebuffer()[start..(start + 8)])
+    }
+
+    #[test]
+    fn test_draw_tile_row_clips_off_screen() {
+        let mut ppu = Ppu::new();
+        ppu.draw_tile_row(BASIC_TILE, -4, 0, 0, &[3, 3, 3, 3], BgAttribute::default());
+
+        assert_eq!([3, 3, 3, 3, 0], ppu.framebuffer()[0..5]);
+    }
+
+    #[test]
+    fn test_palette_shades() {
+        assert_eq!(palette_shades(0b1110_0100), [0, 1, 2, 3]);
+        assert_eq!(palette_shades(0xFC), [0, 3, 3, 3]);
+    }
+
+    /// Memory with identity palettes, tile 1 filled with color 3 and tile 2 filled with color 1
+    fn sprite_memory() -> Memory {
+        let mut memory = Memory::new();
+        // LCD, sprites and background enabled
+        memory.io_registers[0x40] = 0b1000_0011;
+        memory.io_registers[0x47] = 0b1110_0100;
+        memory.io_registers[0x48] = 0b1110_0100;
+
+        for row in 0..8 {
+            memory.vram[0x10 + row * 2] = 0xFF;
+            memory.vram[0x11 + row * 2] = 0xFF;
+            memory.vram[0x20 + row * 2] = 0xFF;
+        }
+
+        memory
+    }
+
+    fn set_sprite(memory: &mut Memory, number: usize, y: u8, x: u8, index: u8, flags: u8) {
+        memory.sprite_attribute_table[(number * 4)..(number * 4 + 4)]
+            .copy_from_slice(&[y, x, index, flags]);
+    }
+
+    fn pixel(ppu: &Ppu, x: usize, y: usize) -> u8 {
+        ppu.framebuffer()[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn test_sprites_per_line_limit() {
+        let mut memory = sprite_memory();
+        for number in 0..11 {
+            set_sprite(&mut memory, number, 16, 8 + number as u8 * 8, 1, 0);
+        }
+
+        let mut ppu = Ppu::new();
+        ppu.render_frame(&memory);
+
+        assert_eq!(pixel(&ppu, 9 * 8, 0), 3, "10th sprite");
+        assert_eq!(pixel(&ppu, 10 * 8, 0), 0, "11th sprite");
+    }
+
+    #[test]
+    fn test_sprite_x_priority() {
+        let mut memory = sprite_memory();
+        // The color 1 sprite is later in OAM but further left, so it wins where they overlap
+        set_sprite(&mut memory, 0, 16, 12, 1, 0);
+        set_sprite(&mut memory, 1, 16, 8, 2, 0);
+        // Same X, the first in OAM wins
+        set_sprite(&mut memory, 2, 32, 8, 2, 0);
+        set_sprite(&mut memory, 3, 32, 8, 1, 0);
+
+        let mut ppu = Ppu::new();
+        ppu.render_frame(&memory);
+
+        assert_eq!(pixel(&ppu, 5, 0), 1);
+        assert_eq!(pixel(&ppu, 9, 0), 3);
+        assert_eq!(pixel(&ppu, 0, 16), 1);
+    }
+
+    #[test]
+    fn test_sprite_behind_background() {
+        let mut memory = sprite_memory();
+        // Background tile 0 has color 1 on its first row and color 0 below
+        memory.vram[0x1000] = 0xFF;
+        set_sprite(&mut memory, 0, 16, 8, 1, 0b1000_0000);
+
+        let mut ppu = Ppu::new();
+        ppu.render_frame(&memory);
+
+        assert_eq!(pixel(&ppu, 0, 0), 1, "Background color 1 covers the sprite");
+        assert_eq!(pixel(&ppu, 0, 1), 3, "Background color 0 doesn't");
+    }
+
+    #[test]
+    fn test_8x16_sprites() {
+        let mut memory = sprite_memory();
+        memory.io_registers[0x40] |= 0b0000_0100;
+        // Index 3 uses tile 2 on top and tile 3 (blank) below, flipping swaps them
+        set_sprite(&mut memory, 0, 16, 8, 3, 0);
+        set_sprite(&mut memory, 1, 16, 16, 3, 0b0100_0000);
+
+        let mut ppu = Ppu::new();
+        ppu.render_frame(&memory);
+
+        assert_eq!(pixel(&ppu, 0, 7), 1);
+        assert_eq!(pixel(&ppu, 0, 8), 0);
+        assert_eq!(pixel(&ppu, 8, 7), 0);
+        assert_eq!(pixel(&ppu, 8, 8), 1);
+    }
+
+    /// Like `sprite_memory`, on a CGB in CGB mode
+    fn cgb_sprite_memory() -> Memory {
+        let dmg_memory = sprite_memory();
+        let mut memory = Memory::with_model(Model::Cgb);
+        memory.io_registers[0x40] = dmg_memory.io_registers[0x40];
+        memory.vram[..0x2000].copy_from_slice(&dmg_memory.vram[..0x2000]);
+        memory
+    }
+
+    fn set_palette_color(memory: &mut Memory, palette: usize, index: usize, color: u16) {
+        let offset = palette * 8 + index * 2;
+        memory.palette_ram[offset..(offset + 2)].copy_from_slice(&color.to_le_bytes());
+    }
+
+    #[test]
+    fn test_cgb_bg_attributes() {
+        let mut memory = Memory::with_model(Model::Cgb);
+        memory.io_registers[0x40] = 0b1000_0001;
+        // Tile 0 in bank 0 has a single color 1 pixel in its top left, in bank 1 it's color 3
+        memory.vram[0x1000] = 0x80;
+        for row in 0..8 {
+            memory.vram[0x3000 + row * 2] = 0xFF;
+            memory.vram[0x3001 + row * 2] = 0xFF;
+        }
+        // X flipped with palette 2, bank 1 with palette 5, then Y flipped
+        memory.vram[0x3800..0x3803].copy_from_slice(&[0x22, 0x0D, 0x40]);
+        set_palette_color(&mut memory, 2, 1, 0x001F);
+        set_palette_color(&mut memory, 5, 3, 0x7C00);
+
+        let mut ppu = Ppu::new();
+        ppu.render_frame(&memory);
+
+        assert_eq!(pixel(&ppu, 0, 0), 0);
+        assert_eq!(pixel(&ppu, 7, 0), 1, "X flip");
+        assert_eq!(pixel(&ppu, 8, 0), 3, "Bank 1");
+        assert_eq!(pixel(&ppu, 16, 0), 0);
+        assert_eq!(pixel(&ppu, 16, 7), 1, "Y flip");
+        assert_eq!(ppu.rgb_framebuffer()[7], 0x001F);
+        assert_eq!(ppu.rgb_framebuffer()[8], 0x7C00);
+    }
+
+    #[test]
+    fn test_cgb_sprite_priority() {
+        let mut memory = cgb_sprite_memory();
+        // Background tile 0 is color 1 everywhere, the third tile of the map has BG priority
+        for row in 0..8 {
+            memory.vram[0x1000 + row * 2] = 0xFF;
+        }
+        memory.vram[0x3802] = 0x80;
+        // The color 3 sprite is first in OAM, so it wins over the color 1 sprite further left
+        set_sprite(&mut memory, 0, 16, 12, 1, 0x03);
+        set_sprite(&mut memory, 1, 16, 8, 2, 0);
+        set_sprite(&mut memory, 2, 16, 24, 1, 0);
+        set_palette_color(&mut memory, 8 + 3, 3, 0x03E0);
+
+        let mut ppu = Ppu::new();
+        ppu.render_frame(&memory);
+
+        assert_eq!(pixel(&ppu, 5, 0), 3, "OAM order");
+        assert_eq!(ppu.rgb_framebuffer()[5], 0x03E0, "OBJ palette 3");
+        assert_eq!(pixel(&ppu, 16, 0), 1, "BG attribute priority");
+
+        // Clearing LCDC bit 0 puts sprites on top instead of blanking the background
+        memory.io_registers[0x40] &= !0b0000_0001;
+        ppu.render_frame(&memory);
+
+        assert_eq!(pixel(&ppu, 16, 0), 3);
+        assert_eq!(pixel(&ppu, 40, 0), 1);
+    }
+
+    #[test]
+    fn test_background_and_sprite_enable() {
+        let mut memory = sprite_memory();
+        memory.io_registers[0x47] = 0xFF;
+        set_sprite(&mut memory, 0, 16, 8, 2, 0);
+
+        memory.io_registers[0x40] = 0b1000_0001;
+        let mut ppu = Ppu::new();
+        ppu.render_frame(&memory);
+        assert_eq!(pixel(&ppu, 0, 0), 3, "Sprites disabled");
+
+        memory.io_registers[0x40] = 0b1000_0010;
+        ppu.render_frame(&memory);
+        assert_eq!(pixel(&ppu, 0, 0), 1, "Sprite drawn over blank background");
+        assert_eq!(pixel(&ppu, 8, 0), 0, "Background disabled");
+    }
+
+    /// Window map at 0x9C00 with tile 1 (color 3) in its first row and tile 2 (color 1) in its second
+    fn window_memory() -> Memory {
+        let mut memory = sprite_memory();
+        memory.io_registers[0x40] = 0b1111_0001;
+        memory.vram[0x1C00..0x1C20].fill(1);
+        memory.vram[0x1C20..0x1C40].fill(2);
+        memory
+    }
+
+    #[test]
+    fn test_window_starts_at_wx() {
+        let mut memory = window_memory();
+        memory.wx = 15;
+
+        let mut ppu = Ppu::new();
+        ppu.render_frame(&memory);
+        assert_eq!(pixel(&ppu, 7, 0), 0);
+        assert_eq!(pixel(&ppu, 8, 0), 3);
+        assert_eq!(pixel(&ppu, 159, 0), 3);
+
+        // The first pixels of the window are cut off
+        memory.wx = 3;
+        memory.vram[0x10] = 0x0F;
+        ppu.render_frame(&memory);
+        assert_eq!(pixel(&ppu, 0, 0), 3);
+        assert_eq!(pixel(&ppu, 4, 0), 2);
+    }
+
+    #[test]
+    fn test_window_line_counter() {
+        let mut memory = window_memory();
+        memory.wx = 7;
+
+        let mut ppu = Ppu::new();
+        for y in 0..10 {
+            ppu.render_line(&memory, y);
+        }
+
+        memory.io_registers[0x40] &= !0b0010_0000;
+        for y in 10..20 {
+            ppu.render_line(&memory, y);
+        }
+
+        // The window continues from its 11th row rather than jumping to row 20
+        memory.io_registers[0x40] |= 0b0010_0000;
+        ppu.render_line(&memory, 20);
+        assert_eq!(pixel(&ppu, 0, 20), 1);
+    }
+
+    #[test]
+    fn test_window_waits_for_wy() {
+        let mut memory = window_memory();
+        memory.wx = 7;
+        memory.wy = 100;
+
+        let mut ppu = Ppu::new();
+        for y in 0..10 {
+            ppu.render_line(&memory, y);
+        }
+
+        // WY was already passed this frame
+        memory.wy = 5;
+        ppu.render_line(&memory, 10);
+        assert_eq!(pixel(&ppu, 0, 10), 0);
+
+        ppu.render_frame(&memory);
+        assert_eq!(pixel(&ppu, 0, 4), 0);
+        assert_eq!(pixel(&ppu, 0, 5), 3);
     }
 }
@@ -0,0 +1,314 @@
+use crate::{cpu::Cpu, instructions::Instruction, memory::Memory, util::combine_bytes};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+};
+
+/// Deepest call stack tracked before new calls are folded into their caller
+///
+/// Code that drops return addresses off the stack would otherwise grow the stack forever
+const MAX_STACK_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BankAddress {
+    pub bank: u16,
+    pub address: u16,
+}
+
+impl BankAddress {
+    pub fn new(memory: &Memory, address: u16) -> BankAddress {
+        BankAddress {
+            bank: memory.bank_of(address),
+            address,
+        }
+    }
+}
+
+impl fmt::Display for BankAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:0>2X}:{:0>4X}", self.bank, self.address)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ControlFlow {
+    Call,
+    Return,
+    Other,
+}
+
+/// Accumulates executed T-cycles per instruction address and per call stack
+///
+/// Call `begin_instruction` after parsing an instruction and `end_instruction` after executing it
+#[derive(Debug, Default)]
+pub struct Profiler {
+    address_cycles: HashMap<BankAddress, u64>,
+    stack_cycles: HashMap<Vec<BankAddress>, u64>,
+    stack: Vec<BankAddress>,
+    location: Option<BankAddress>,
+    control_flow: Option<ControlFlow>,
+    stack_pointer: u16,
+    last_cycles: Option<u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Forget the call stack and cycle count of the last instruction, for when the system is reset
+    ///
+    /// The cycles collected so far are kept
+    pub fn restart(&mut self) {
+        self.stack.clear();
+        self.location = None;
+        self.control_flow = None;
+        self.last_cycles = None;
+    }
+
+    pub fn begin_instruction(&mut self, instruction: &Instruction, cpu: &Cpu, memory: &Memory) {
+        self.location = Some(BankAddress::new(memory, cpu.program_counter));
+        self.stack_pointer = cpu.stack_pointer;
+        self.control_flow = Some(match instruction {
+            Instruction::Call
+            | Instruction::CallConditional { .. }
+            | Instruction::Reset0 { .. }
+            | Instruction::Reset8 { .. } => ControlFlow::Call,
+            Instruction::Return
+            | Instruction::ReturnConditional { .. }
+            | Instruction::ReturnAndEnableInterrupts => ControlFlow::Return,
+            _ => ControlFlow::Other,
+        });
+
+        if self.last_cycles.is_none() {
+            // The opcode fetch of the very first instruction has already happened
+            self.last_cycles = Some(memory.cycles().saturating_sub(4));
+        }
+    }
+
+    pub fn end_instruction(&mut self, cpu: &Cpu, memory: &Memory) {
+        let (Some(location), Some(control_flow), Some(last_cycles)) = (
+            self.location.take(),
+            self.control_flow.take(),
+            self.last_cycles,
+        ) else {
+            return;
+        };
+
+        // Cycles are counted from the end of the previous instruction so the opcode fetch is included
+        let cycles = memory.cycles() - last_cycles;
+        self.last_cycles = Some(memory.cycles());
+
+        *self.address_cycles.entry(location).or_insert(0) += cycles;
+        *self.stack_cycles.entry(self.stack.clone()).or_insert(0) += cycles;
+
+        // Remove the return address pushed by a serviced interrupt to see what the instruction did to SP
+        let stack_pointer = match cpu.serviced_interrupt() {
+            Some(_) => cpu.stack_pointer.wrapping_add(2),
+            None => cpu.stack_pointer,
+        };
+
+        match control_flow {
+            ControlFlow::Call if stack_pointer == self.stack_pointer.wrapping_sub(2) => {
+                self.push(BankAddress::new(memory, self.call_target(cpu, memory)));
+            }
+            ControlFlow::Return if stack_pointer == self.stack_pointer.wrapping_add(2) => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+
+        if let Some(handler) = cpu.serviced_interrupt() {
+            self.push(BankAddress::new(memory, handler));
+        }
+    }
+
+    /// Returns where a taken call jumped to, looking past the interrupt handler if one was entered
+    fn call_target(&self, cpu: &Cpu, memory: &Memory) -> u16 {
+        match cpu.serviced_interrupt() {
+            Some(_) => {
+                let low = memory.peek(cpu.stack_pointer);
+                let high = memory.peek(cpu.stack_pointer.wrapping_add(1));
+                combine_bytes(high, low)
+            }
+            None => cpu.program_counter,
+        }
+    }
+
+    fn push(&mut self, routine: BankAddress) {
+        if self.stack.len() < MAX_STACK_DEPTH {
+            self.stack.push(routine);
+        }
+    }
+
+    /// Returns the cycles spent at each address, most expensive first
+    pub fn address_report(&self) -> Vec<(BankAddress, u64)> {
+        let mut report: Vec<(BankAddress, u64)> =
+            self.address_cycles.iter().map(|(k, v)| (*k, *v)).collect();
+        report.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        report
+    }
+
+    /// Returns the self and inclusive cycles spent in each routine, most expensive first
+    ///
+    /// Cycles spent outside of any called routine are reported under `None`
+    pub fn routine_report(&self) -> Vec<(Option<BankAddress>, u64, u64)> {
+        let mut routines: HashMap<Option<BankAddress>, (u64, u64)> = HashMap::new();
+
+        for (stack, cycles) in &self.stack_cycles {
+            routines.entry(stack.last().copied()).or_insert((0, 0)).0 += cycles;
+
+            // Recursive routines should only count once towards their inclusive total
+            let mut seen = Vec::new();
+            for routine in stack.iter().map(|r| Some(*r)).chain([None]) {
+                if !seen.contains(&routine) {
+                    seen.push(routine);
+                    routines.entry(routine).or_insert((0, 0)).1 += cycles;
+                }
+            }
+        }
+
+        let mut report: Vec<(Option<BankAddress>, u64, u64)> = routines
+            .into_iter()
+            .map(|(routine, (self_cycles, total_cycles))| (routine, self_cycles, total_cycles))
+            .collect();
+        report.sort_by(|a, b| b.2.cmp(&a.2).then(b.1.cmp(&a.1)).then(a.0.cmp(&b.0)));
+        report
+    }
+
+    /// Write a human readable report of the routines and addresses sorted by cycles spent
+    pub fn write_report(&self, writer: &mut impl Write) -> io::Result<()> {
+        let total: u64 = self.address_cycles.values().sum();
+        let percent = |cycles: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                cycles as f64 * 100.0 / total as f64
+            }
+        };
+
+        writeln!(writer, "Total cycles: {}", total)?;
+        writeln!(writer)?;
+        writeln!(writer, "Routines:")?;
+        writeln!(
+            writer,
+            "{:>14} {:>7} {:>14} {:>7}  routine",
+            "self", "%", "total", "%"
+        )?;
+        for (routine, self_cycles, total_cycles) in self.routine_report() {
+            let name = match routine {
+                Some(routine) => routine.to_string(),
+                None => "root".to_owned(),
+            };
+            writeln!(
+                writer,
+                "{:>14} {:>6.2}% {:>14} {:>6.2}%  {}",
+                self_cycles,
+                percent(self_cycles),
+                total_cycles,
+                percent(total_cycles),
+                name
+            )?;
+        }
+
+        writeln!(writer)?;
+        writeln!(writer, "Addresses:")?;
+        writeln!(writer, "{:>14} {:>7}  address", "cycles", "%")?;
+        for (address, cycles) in self.address_report() {
+            writeln!(
+                writer,
+                "{:>14} {:>6.2}%  {}",
+                cycles,
+                percent(cycles),
+                address
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the call stacks in the folded format used by flamegraph tools
+    ///
+    /// Each line is `root;00:0150;01:4000 <cycles>`
+    pub fn write_folded(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut stacks: Vec<(&Vec<BankAddress>, &u64)> = self.stack_cycles.iter().collect();
+        stacks.sort();
+
+        for (stack, cycles) in stacks {
+            let mut line = "root".to_owned();
+            for routine in stack {
+                line.push_str(&format!(";{}", routine));
+            }
+            writeln!(writer, "{} {}", line, cycles)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    fn run_program(program: &[u8], instructions: usize) -> Profiler {
+        let mut cpu = Cpu::new();
+        let mut memory = Memory::new();
+        let mut profiler = Profiler::new();
+        cpu.set_post_boot_state(Model::Dmg, false, 0x01);
+        memory.set_post_boot_state();
+        cpu.interrupts_enabled = false;
+        memory.rom[0x100..(0x100 + program.len())].copy_from_slice(program);
+
+        for _ in 0..instructions {
+            let instruction = cpu.parse(&mut memory);
+            profiler.begin_instruction(&instruction, &cpu, &memory);
+            cpu.execute(instruction, &mut memory);
+            profiler.end_instruction(&cpu, &memory);
+        }
+
+        profiler
+    }
+
+    #[test]
+    fn test_call_and_return_are_tracked() {
+        // CALL $0105; NOP; NOP; NOP; RET
+        let profiler = run_program(&[0xCD, 0x05, 0x01, 0x00, 0x00, 0x00, 0xC9], 4);
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        let stacks: Vec<&str> = folded
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0)
+            .collect();
+
+        assert_eq!(stacks, vec!["root", "root;00:0105"]);
+    }
+
+    #[test]
+    fn test_conditional_call_not_taken() {
+        // SCF; CALL NC,$0110; NOP
+        let profiler = run_program(&[0x37, 0xD4, 0x10, 0x01, 0x00], 3);
+
+        let routines = profiler.routine_report();
+        assert_eq!(routines.len(), 1);
+        assert_eq!(routines[0].0, None);
+    }
+
+    #[test]
+    fn test_address_cycles_add_up_to_routine_totals() {
+        let profiler = run_program(&[0xCD, 0x05, 0x01, 0x00, 0x00, 0x00, 0xC9], 4);
+
+        let address_total: u64 = profiler.address_report().iter().map(|(_, c)| c).sum();
+        let root = profiler
+            .routine_report()
+            .into_iter()
+            .find(|(routine, _, _)| routine.is_none())
+            .unwrap();
+
+        assert_eq!(root.2, address_total);
+        assert_eq!(profiler.address_report().len(), 4);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::util::get_as_bits;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct SpriteAttribute {
     pub y: u8,
     pub x: u8,
@@ -8,7 +8,12 @@ pub struct SpriteAttribute {
     pub bg_over_obj: bool,
     pub y_flip: bool,
     pub x_flip: bool,
+    /// DMG palette, OBP0 or OBP1
     pub palette: u8,
+    /// VRAM bank the tile is read from in CGB mode
+    pub bank: u8,
+    /// OBJ palette (0-7) in CGB mode
+    pub cgb_palette: u8,
 }
 
 impl SpriteAttribute {
@@ -18,6 +23,8 @@ impl SpriteAttribute {
         let y_flip = flag_bits[1] == 1;
         let x_flip = flag_bits[2] == 1;
         let palette = flag_bits[3];
+        let bank = flag_bits[4];
+        let cgb_palette = flags & 0b0000_0111;
         SpriteAttribute {
             y,
             x,
@@ -26,6 +33,8 @@ impl SpriteAttribute {
             y_flip,
             x_flip,
             palette,
+            bank,
+            cgb_palette,
         }
     }
 }
@@ -0,0 +1,101 @@
+use crate::{cpu::Cpu, memory::Memory};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// Writes one line of CPU state per executed instruction in the Gameboy Doctor log format
+///
+/// `A:00 F:11 B:22 C:33 D:44 E:55 H:66 L:77 SP:8888 PC:9999 PCMEM:AA,BB,CC,DD`
+pub struct TraceWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> fmt::Debug for TraceWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TraceWriter").finish_non_exhaustive()
+    }
+}
+
+impl TraceWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(TraceWriter::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(writer: W) -> TraceWriter<W> {
+        TraceWriter { writer }
+    }
+
+    /// Log the state of `cpu` before it executes the instruction at the program counter
+    pub fn log(&mut self, cpu: &Cpu, memory: &Memory) -> io::Result<()> {
+        writeln!(self.writer, "{}", format_trace_line(cpu, memory))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Returns the Gameboy Doctor log line for the current CPU state
+///
+/// Memory is read with `Memory::peek`, so building the line does not advance time
+pub fn format_trace_line(cpu: &Cpu, memory: &Memory) -> String {
+    let pc = cpu.program_counter;
+
+    format!(
+        "A:{:0>2X} F:{:0>2X} B:{:0>2X} C:{:0>2X} D:{:0>2X} E:{:0>2X} H:{:0>2X} L:{:0>2X} SP:{:0>4X} PC:{:0>4X} PCMEM:{:0>2X},{:0>2X},{:0>2X},{:0>2X}",
+        cpu.a,
+        cpu.flags_to_byte(),
+        cpu.b,
+        cpu.c,
+        cpu.d,
+        cpu.e,
+        cpu.h,
+        cpu.l,
+        cpu.stack_pointer,
+        pc,
+        memory.peek(pc),
+        memory.peek(pc.wrapping_add(1)),
+        memory.peek(pc.wrapping_add(2)),
+        memory.peek(pc.wrapping_add(3)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    #[test]
+    fn test_format_trace_line_post_boot() {
+        let mut cpu = Cpu::new();
+        let mut memory = Memory::new();
+        cpu.set_post_boot_state(Model::Dmg, false, 0x01);
+        memory.set_post_boot_state();
+
+        memory.rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x13, 0x02]);
+
+        assert_eq!(
+            format_trace_line(&cpu, &memory),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02"
+        );
+    }
+
+    #[test]
+    fn test_log_writes_one_line_per_call() {
+        let cpu = Cpu::new();
+        let memory = Memory::new();
+        let mut trace = TraceWriter::new(Vec::new());
+
+        trace.log(&cpu, &memory).unwrap();
+        trace.log(&cpu, &memory).unwrap();
+
+        let output = String::from_utf8(trace.writer).unwrap();
+        assert_eq!(output.lines().count(), 2);
+        assert!(output.ends_with('\n'));
+    }
+}